@@ -2,7 +2,7 @@ use {
     hexerator_plugin_api::{
         HexeratorHandle, MethodParam, MethodResult, Plugin, PluginMethod, Value, ValueTy,
     },
-    mario_story_dialog_decode::{decode_imm_buf, BUFFER_SIZE},
+    mario_story_dialog_decode::{decode_imm_buf, DecodeError, BUFFER_SIZE},
 };
 
 struct MarioStoryPlugin;
@@ -101,7 +101,7 @@ impl Plugin for MarioStoryPlugin {
                 match hexerator.get_data(from as usize, to as usize) {
                     Some(data) => match mario_story_dialog_decode::to_string(data) {
                         Ok(string) => Ok(Some(Value::String(string))),
-                        Err(e) => Err(e.to_string()),
+                        Err(e) => Err(decode_error_msg(&e, from as usize)),
                     },
                     None => Err("Range out of bounds".into()),
                 }
@@ -116,7 +116,7 @@ impl Plugin for MarioStoryPlugin {
                     Some(data) => {
                         match mario_story_dialog_decode::to_string_nth_bubble(data, bubble as u8) {
                             Ok(string) => Ok(Some(Value::String(string))),
-                            Err(e) => Err(e.to_string()),
+                            Err(e) => Err(decode_error_msg(&e, from as usize)),
                         }
                     }
                     None => Err("Range out of bounds".into()),
@@ -129,7 +129,7 @@ impl Plugin for MarioStoryPlugin {
                 match hexerator.get_data(from, to) {
                     Some(data) => match mario_story_dialog_decode::to_string(data) {
                         Ok(string) => Ok(Some(Value::String(string))),
-                        Err(e) => Err(e.to_string()),
+                        Err(e) => Err(decode_error_msg(&e, from)),
                     },
                     None => Err("Range out of bounds".into()),
                 }
@@ -139,6 +139,11 @@ impl Plugin for MarioStoryPlugin {
     }
 }
 
+/// Error message with the offset of the error translated to an absolute offset
+fn decode_error_msg(e: &DecodeError, range_start: usize) -> String {
+    format!("{e} (absolute offset: 0x{:X})", range_start + e.offset())
+}
+
 #[no_mangle]
 pub extern "Rust" fn hexerator_plugin_new() -> Box<dyn Plugin> {
     Box::new(MarioStoryPlugin)
@@ -1,3 +1,8 @@
+use {
+    crate::LookupTable,
+    std::{collections::BTreeMap, sync::OnceLock},
+};
+
 pub fn kana(hex: u8) -> Option<char> {
     let val = match hex {
         0x00 => 'あ',
@@ -393,7 +398,7 @@ pub fn button(code: u8) -> Option<Button> {
     })
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Button {
     A,
     B,
@@ -402,3 +407,793 @@ pub enum Button {
     CDown,
     Z,
 }
+
+impl Button {
+    /// Textual representation used in decoded dialog
+    pub fn label(self) -> &'static str {
+        match self {
+            Button::A => "[A]",
+            Button::B => "[B]",
+            Button::Start => "[START]",
+            Button::CDown => "[C⬇]",
+            Button::CLeft => "[C◀]",
+            Button::Z => "[Z]",
+        }
+    }
+    /// Inverse of [`Button::label`]
+    pub fn from_label(label: &str) -> Option<Self> {
+        [
+            Button::A,
+            Button::B,
+            Button::Start,
+            Button::CDown,
+            Button::CLeft,
+            Button::Z,
+        ]
+        .into_iter()
+        .find(|b| b.label() == label)
+    }
+    /// The code of this button in the button lookup table
+    pub fn code(self) -> u8 {
+        match self {
+            Button::A => 0,
+            Button::B => 1,
+            Button::Start => 2,
+            Button::CDown => 4,
+            Button::CLeft => 5,
+            Button::Z => 7,
+        }
+    }
+}
+
+/// Find the lookup table and code to encode `ch` with, in the built-in tables.
+///
+/// Codes `0xF0` and above are never returned, because they are control codes in the dialog
+/// stream. When several codes decode to `ch`, the one [`MARIO_STORY_PREFERRED`] names is
+/// used. Characters without such a rule are [`ReverseError::Ambiguous`].
+pub fn reverse(ch: char) -> Result<CharCode, ReverseError> {
+    Charset::builtin().reverse(ch)
+}
+
+/// Which code the built-in tables encode a character with, when several decode to it
+pub const MARIO_STORY_PREFERRED: &[(char, CharCode)] = &[
+    // 0xC4 stands in for a small ん
+    ('ん', CharCode::Single(LookupTable::Kana, 0x2D)),
+    // 0x89 is ン with a dakuten, 0xC5 stands in for a small ン
+    ('ン', CharCode::Single(LookupTable::Kana, 0x7E)),
+];
+
+/// What kind of character a glyph is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GlyphCategory {
+    Hiragana,
+    Katakana,
+    Kanji,
+    /// Latin letters and digits
+    Alphanumeric,
+    Punctuation,
+    Symbol,
+}
+
+impl GlyphCategory {
+    /// The category of a character, going by its Unicode block
+    pub fn of(ch: char) -> Self {
+        match ch {
+            '\u{30FB}' | '\u{30FC}' => Self::Punctuation,
+            '\u{3041}'..='\u{309F}' => Self::Hiragana,
+            '\u{30A0}'..='\u{30FF}' => Self::Katakana,
+            '\u{3005}' | '\u{4E00}'..='\u{9FFF}' => Self::Kanji,
+            _ if ch.is_alphanumeric() => Self::Alphanumeric,
+            '\u{3000}'..='\u{303F}' | '\u{2010}'..='\u{205E}' | '\u{FF01}'..='\u{FF65}' => {
+                Self::Punctuation
+            }
+            _ if ch.is_ascii_punctuation() || ch.is_whitespace() => Self::Punctuation,
+            '¡' | '¿' | '«' | '»' => Self::Punctuation,
+            _ => Self::Symbol,
+        }
+    }
+}
+
+/// How sure we are that a code decodes to the right character
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Confidence {
+    /// Checked against the font. None of the built-in glyphs are yet.
+    Verified,
+    /// Not checked against the font
+    Guessed,
+    /// Known to be a different glyph, which Unicode has no exact character for
+    Placeholder,
+}
+
+/// What is known about the glyph of a character code
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GlyphInfo {
+    pub category: GlyphCategory,
+    pub confidence: Confidence,
+    pub note: Option<String>,
+}
+
+/// Glyphs of the built-in tables with something to say about them. Every other glyph is
+/// guessed.
+pub const MARIO_STORY_GLYPH_NOTES: &[(LookupTable, u8, GlyphCategory, Confidence, &str)] = &[
+    (
+        LookupTable::Kana,
+        0x89,
+        GlyphCategory::Katakana,
+        Confidence::Placeholder,
+        "ン with a dakuten",
+    ),
+    (
+        LookupTable::Kana,
+        0xBD,
+        GlyphCategory::Symbol,
+        Confidence::Placeholder,
+        "a heart glyph, not the emoji",
+    ),
+    (
+        LookupTable::Kana,
+        0xC4,
+        GlyphCategory::Hiragana,
+        Confidence::Placeholder,
+        "small ん",
+    ),
+    (
+        LookupTable::Kana,
+        0xC5,
+        GlyphCategory::Katakana,
+        Confidence::Placeholder,
+        "small ン",
+    ),
+    (
+        LookupTable::Kana,
+        0xF8,
+        GlyphCategory::Punctuation,
+        Confidence::Guessed,
+        "a space, possibly only used for leading space",
+    ),
+];
+
+/// Codes the built-in tables decode to a similar looking character on purpose, because
+/// Unicode has no exact match. They are the [`Confidence::Placeholder`] glyphs of
+/// [`MARIO_STORY_GLYPH_NOTES`]. Codes that share their character with other codes are
+/// approximations as well, unless the encoder prefers them, see
+/// [`crate::Dialect::is_approximate`].
+pub const MARIO_STORY_APPROXIMATED: &[(LookupTable, u8)] = &[
+    // ン with a dakuten
+    (LookupTable::Kana, 0x89),
+    // A heart glyph, not the emoji
+    (LookupTable::Kana, 0xBD),
+    // Small ん and small ン
+    (LookupTable::Kana, 0xC4),
+    (LookupTable::Kana, 0xC5),
+];
+
+/// The Private Use Area code point that stands for `code` in `table`.
+///
+/// Each character table has a block of 256 code points: kana at U+E000, latin at U+E100
+/// and kanji at U+E200. The button table has none.
+pub fn private_use(table: LookupTable, code: u8) -> Option<char> {
+    let base = match table {
+        LookupTable::Kana => 0xE000,
+        LookupTable::Latin => 0xE100,
+        LookupTable::Kanji => 0xE200,
+        LookupTable::Button => return None,
+    };
+    char::from_u32(base + u32::from(code))
+}
+
+/// Inverse of [`private_use`]
+pub fn from_private_use(ch: char) -> Option<(LookupTable, u8)> {
+    let table = match u32::from(ch) & !0xFF {
+        0xE000 => LookupTable::Kana,
+        0xE100 => LookupTable::Latin,
+        0xE200 => LookupTable::Kanji,
+        _ => return None,
+    };
+    Some((table, ch as u8))
+}
+
+/// A character code
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CharCode {
+    /// A code in a lookup table
+    Single(LookupTable, u8),
+    /// A two byte code, with the lead byte as the high byte
+    Wide(u16),
+}
+
+/// Why a character can't be encoded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReverseError {
+    /// No code decodes to the character
+    Missing,
+    /// Several codes decode to the character, and no rule says which one to use
+    Ambiguous { codes: Vec<CharCode> },
+}
+
+/// A character that more than one code decodes to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ambiguity {
+    pub ch: char,
+    /// Every code that decodes to `ch`, control codes included
+    pub codes: Vec<CharCode>,
+    /// The code `ch` is encoded with, if any
+    pub chosen: Option<CharCode>,
+}
+
+/// Pick the code for a character among the `candidates` that decode to it
+pub(crate) fn resolve(
+    candidates: Vec<CharCode>,
+    preferred: Option<CharCode>,
+) -> Result<CharCode, ReverseError> {
+    match candidates[..] {
+        [] => Err(ReverseError::Missing),
+        [only] => Ok(only),
+        _ => match preferred.filter(|code| candidates.contains(code)) {
+            Some(code) => Ok(code),
+            None => Err(ReverseError::Ambiguous { codes: candidates }),
+        },
+    }
+}
+
+/// Every character more than one of the `entries` decodes to, with the code `reverse`
+/// picks for it
+pub(crate) fn ambiguities(
+    entries: impl Iterator<Item = (CharCode, char)>,
+    reverse: impl Fn(char) -> Result<CharCode, ReverseError>,
+) -> Vec<Ambiguity> {
+    let mut codes: BTreeMap<char, Vec<CharCode>> = BTreeMap::new();
+    for (code, ch) in entries {
+        codes.entry(ch).or_default().push(code);
+    }
+    codes
+        .into_iter()
+        .filter(|(_, codes)| codes.len() > 1)
+        .map(|(ch, codes)| Ambiguity {
+            ch,
+            codes,
+            chosen: reverse(ch).ok(),
+        })
+        .collect()
+}
+
+#[test]
+fn test_reverse() {
+    assert_eq!(reverse('あ'), Ok(CharCode::Single(LookupTable::Kana, 0x00)));
+    assert_eq!(reverse('ん'), Ok(CharCode::Single(LookupTable::Kana, 0x2D)));
+    assert_eq!(
+        reverse('Ｚ'),
+        Ok(CharCode::Single(LookupTable::Latin, 0x19))
+    );
+    assert_eq!(
+        reverse('虫'),
+        Ok(CharCode::Single(LookupTable::Kanji, 0x77))
+    );
+    assert_eq!(reverse('\u{3000}'), Err(ReverseError::Missing));
+    let mut charset = Charset::default();
+    charset.preferred.clear();
+    assert_eq!(
+        charset.reverse('ン'),
+        Err(ReverseError::Ambiguous {
+            codes: vec![
+                CharCode::Single(LookupTable::Kana, 0x7E),
+                CharCode::Single(LookupTable::Kana, 0x89),
+                CharCode::Single(LookupTable::Kana, 0xC5)
+            ]
+        })
+    );
+    // A rule for a code that doesn't decode to the character is ignored
+    charset
+        .preferred
+        .insert('ン', CharCode::Single(LookupTable::Kana, 0x7F));
+    assert!(charset.reverse('ン').is_err());
+}
+
+#[test]
+fn test_ambiguities() {
+    let report = Charset::builtin().ambiguities();
+    let chars: String = report.iter().map(|amb| amb.ch).collect();
+    assert_eq!(chars, "\n\u{3000}べょんンＸ");
+    // Only the characters of placeholder glyphs are resolved, nothing decides the others
+    let chosen: String = report
+        .iter()
+        .filter(|amb| amb.chosen.is_some())
+        .map(|amb| amb.ch)
+        .collect();
+    assert_eq!(chosen, "んン");
+    assert_eq!(
+        reverse('べ'),
+        Err(ReverseError::Ambiguous {
+            codes: vec![
+                CharCode::Single(LookupTable::Kana, 0x41),
+                CharCode::Single(LookupTable::Kana, 0x92)
+            ]
+        })
+    );
+    let space = &report[1];
+    assert_eq!(space.chosen, None);
+    assert_eq!(space.codes.len(), 3);
+}
+
+/// A value a `.tbl` file can map a code to
+pub trait TblValue: Sized + Copy + PartialEq {
+    fn from_tbl(s: &str) -> Option<Self>;
+    fn to_tbl(self) -> String;
+    /// The error for a value on `line` that [`from_tbl`](Self::from_tbl) rejected
+    fn invalid(line: usize, _s: &str) -> TblError {
+        TblError::InvalidValue { line }
+    }
+}
+
+/// A single character. Values of several characters, like the DTE entries of some
+/// tables, aren't supported, because a code always decodes to one character.
+impl TblValue for char {
+    fn from_tbl(s: &str) -> Option<Self> {
+        if s == "\\n" {
+            return Some('\n');
+        }
+        let mut chars = s.chars();
+        let ch = chars.next()?;
+        chars.next().is_none().then_some(ch)
+    }
+    fn to_tbl(self) -> String {
+        match self {
+            '\n' => "\\n".into(),
+            ch => ch.into(),
+        }
+    }
+    fn invalid(line: usize, s: &str) -> TblError {
+        match s.chars().count() {
+            0 | 1 => TblError::InvalidValue { line },
+            _ => TblError::MultiChar { line },
+        }
+    }
+}
+
+impl TblValue for Button {
+    fn from_tbl(s: &str) -> Option<Self> {
+        Self::from_label(s)
+    }
+    fn to_tbl(self) -> String {
+        self.label().into()
+    }
+}
+
+/// Numbers, such as glyph widths, are written in decimal
+impl TblValue for u8 {
+    fn from_tbl(s: &str) -> Option<Self> {
+        s.trim().parse().ok()
+    }
+    fn to_tbl(self) -> String {
+        self.to_string()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TblError {
+    /// The line is not `XX=value`
+    Syntax { line: usize },
+    /// The code is not a hexadecimal byte
+    InvalidCode { line: usize },
+    /// The value can't be used in this table
+    InvalidValue { line: usize },
+    /// The value has several characters, which character tables don't support
+    MultiChar { line: usize },
+}
+
+impl std::fmt::Display for TblError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Syntax { line } => write!(f, "line {line}: expected `XX=value`"),
+            Self::InvalidCode { line } => write!(f, "line {line}: invalid code"),
+            Self::InvalidValue { line } => write!(f, "line {line}: invalid value"),
+            Self::MultiChar { line } => write!(
+                f,
+                "line {line}: values of several characters (DTE) are not supported"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for TblError {}
+
+/// Maps every byte code of one lookup table to a value
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CodeTable<T> {
+    entries: Vec<Option<T>>,
+}
+
+impl<T: TblValue> CodeTable<T> {
+    /// An empty table
+    pub fn new() -> Self {
+        Self::from_fn(|_| None)
+    }
+    fn from_fn(f: impl Fn(u8) -> Option<T>) -> Self {
+        Self {
+            entries: (0x00..=0xFF).map(f).collect(),
+        }
+    }
+    pub fn get(&self, code: u8) -> Option<T> {
+        self.entries[usize::from(code)]
+    }
+    pub fn set(&mut self, code: u8, value: Option<T>) {
+        self.entries[usize::from(code)] = value;
+    }
+    /// All codes that map to `value`, in ascending order
+    pub fn codes_of(&self, value: T) -> impl Iterator<Item = u8> + '_ {
+        (0x00..=0xFF).filter(move |&code| self.get(code) == Some(value))
+    }
+    /// Parse a `.tbl` file.
+    ///
+    /// Every line maps a hexadecimal code to a value, like `2D=ん`. Blank lines, and the
+    /// `/` and `*` lines some tools use for control codes, are skipped. Character tables
+    /// take one character per code, DTE values like `80=th` are rejected with
+    /// [`TblError::MultiChar`].
+    pub fn parse_tbl(text: &str) -> Result<Self, TblError> {
+        let mut table = Self::new();
+        for entry in tbl_entries(text) {
+            let (line, code, value) = entry?;
+            let code = u8::from_str_radix(code, 16).map_err(|_| TblError::InvalidCode { line })?;
+            let value = T::from_tbl(value).ok_or_else(|| T::invalid(line, value))?;
+            table.set(code, Some(value));
+        }
+        Ok(table)
+    }
+    /// Write the table in `.tbl` format
+    pub fn to_tbl(&self) -> String {
+        let mut out = String::new();
+        for code in 0x00..=0xFF {
+            if let Some(value) = self.get(code) {
+                out.push_str(&format!("{code:02X}={}\n", value.to_tbl()));
+            }
+        }
+        out
+    }
+}
+
+impl<T: TblValue> Default for CodeTable<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The (1 based) line number, code and value of every entry of a `.tbl` file
+fn tbl_entries(text: &str) -> impl Iterator<Item = Result<(usize, &str, &str), TblError>> {
+    text.lines().enumerate().filter_map(|(i, line)| {
+        let line_no = i + 1;
+        let line = line.trim_end_matches('\r');
+        if line.trim().is_empty() || line.starts_with(['/', '*']) {
+            return None;
+        }
+        Some(match line.split_once('=') {
+            Some((code, value)) => Ok((line_no, code.trim(), value)),
+            None => Err(TblError::Syntax { line: line_no }),
+        })
+    })
+}
+
+/// Character codes of a multi-byte encoding: single byte codes, and two byte codes
+/// stored lead byte first
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GlyphTable {
+    pub single: CodeTable<char>,
+    wide: BTreeMap<u16, char>,
+}
+
+impl GlyphTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn get_wide(&self, code: u16) -> Option<char> {
+        self.wide.get(&code).copied()
+    }
+    pub fn set_wide(&mut self, code: u16, value: Option<char>) {
+        match value {
+            Some(ch) => self.wide.insert(code, ch),
+            None => self.wide.remove(&code),
+        };
+    }
+    /// Whether `byte` starts any two byte code of the table
+    pub fn is_lead_byte(&self, byte: u8) -> bool {
+        let first = u16::from(byte) << 8;
+        self.wide.range(first..=first | 0xFF).next().is_some()
+    }
+    /// All two byte codes of the table, in ascending order
+    pub fn wide_codes(&self) -> impl Iterator<Item = u16> + '_ {
+        self.wide.keys().copied()
+    }
+    /// All two byte codes that map to `ch`, in ascending order
+    pub fn wide_codes_of(&self, ch: char) -> impl Iterator<Item = u16> + '_ {
+        self.wide
+            .iter()
+            .filter(move |&(_, &value)| value == ch)
+            .map(|(&code, _)| code)
+    }
+    /// Every code of the table, single byte codes first, as [`LookupTable::Latin`] codes
+    pub(crate) fn entries(&self) -> impl Iterator<Item = (CharCode, char)> + '_ {
+        let single = (0x00..=0xFF).filter_map(|code| {
+            Some((
+                CharCode::Single(LookupTable::Latin, code),
+                self.single.get(code)?,
+            ))
+        });
+        let wide = self
+            .wide
+            .iter()
+            .map(|(&code, &ch)| (CharCode::Wide(code), ch));
+        single.chain(wide)
+    }
+    /// Parse a `.tbl` file. Codes with two hex digits are single byte codes, codes with
+    /// four digits are two byte codes, like `8140=的`.
+    pub fn parse_tbl(text: &str) -> Result<Self, TblError> {
+        let mut table = Self::new();
+        for entry in tbl_entries(text) {
+            let (line, code, value) = entry?;
+            let value = char::from_tbl(value).ok_or_else(|| char::invalid(line, value))?;
+            let invalid = |_| TblError::InvalidCode { line };
+            match code.len() {
+                2 => table
+                    .single
+                    .set(u8::from_str_radix(code, 16).map_err(invalid)?, Some(value)),
+                4 => table.set_wide(u16::from_str_radix(code, 16).map_err(invalid)?, Some(value)),
+                _ => return Err(TblError::InvalidCode { line }),
+            }
+        }
+        Ok(table)
+    }
+    /// Write the table in `.tbl` format, single byte codes first
+    pub fn to_tbl(&self) -> String {
+        let mut out = self.single.to_tbl();
+        for (code, ch) in &self.wide {
+            out.push_str(&format!("{code:04X}={}\n", ch.to_tbl()));
+        }
+        out
+    }
+}
+
+/// The lookup tables the decoders map character codes with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Charset {
+    pub kana: CodeTable<char>,
+    pub kanji: CodeTable<char>,
+    pub latin: CodeTable<char>,
+    pub button: CodeTable<Button>,
+    /// Which code to encode a character with, when several decode to it
+    pub preferred: BTreeMap<char, CharCode>,
+    /// Metadata of the glyphs that are verified or have a note. Codes without any are
+    /// guessed.
+    pub notes: BTreeMap<(LookupTable, u8), GlyphInfo>,
+}
+
+impl Charset {
+    /// The built-in Mario Story tables
+    pub fn builtin() -> &'static Self {
+        static BUILTIN: OnceLock<Charset> = OnceLock::new();
+        BUILTIN.get_or_init(|| {
+            let mut charset = Self {
+                kana: CodeTable::from_fn(kana),
+                kanji: CodeTable::from_fn(kanji),
+                latin: CodeTable::from_fn(latin),
+                button: CodeTable::from_fn(button),
+                preferred: MARIO_STORY_PREFERRED.iter().copied().collect(),
+                notes: BTreeMap::new(),
+            };
+            for &(table, code, category, confidence, note) in MARIO_STORY_GLYPH_NOTES {
+                let info = GlyphInfo {
+                    category,
+                    confidence,
+                    note: Some(note.into()),
+                };
+                charset.notes.insert((table, code), info);
+            }
+            charset
+        })
+    }
+    fn char_table(&self, table: LookupTable) -> Option<&CodeTable<char>> {
+        match table {
+            LookupTable::Kana => Some(&self.kana),
+            LookupTable::Kanji => Some(&self.kanji),
+            LookupTable::Latin => Some(&self.latin),
+            LookupTable::Button => None,
+        }
+    }
+    /// Look up a character. The button table has no characters.
+    pub fn char(&self, table: LookupTable, code: u8) -> Option<char> {
+        self.char_table(table)?.get(code)
+    }
+    pub fn button(&self, code: u8) -> Option<Button> {
+        self.button.get(code)
+    }
+    /// Like [`reverse`], but with these tables and [`Charset::preferred`]
+    pub fn reverse(&self, ch: char) -> Result<CharCode, ReverseError> {
+        let candidates = self
+            .entries()
+            .filter(|&(code, value)| {
+                value == ch && matches!(code, CharCode::Single(_, 0x00..=0xEF))
+            })
+            .map(|(code, _)| code)
+            .collect();
+        resolve(candidates, self.preferred.get(&ch).copied())
+    }
+    /// Metadata of the glyph of `code` in `table`, if it decodes to a character.
+    ///
+    /// Codes without [`Charset::notes`] are guessed, with the category of their character.
+    pub fn glyph_info(&self, table: LookupTable, code: u8) -> Option<GlyphInfo> {
+        let ch = self.char(table, code)?;
+        Some(match self.notes.get(&(table, code)) {
+            Some(info) => info.clone(),
+            None => GlyphInfo {
+                category: GlyphCategory::of(ch),
+                confidence: Confidence::Guessed,
+                note: None,
+            },
+        })
+    }
+    /// Every character more than one code decodes to
+    pub fn ambiguities(&self) -> Vec<Ambiguity> {
+        ambiguities(self.entries(), |ch| self.reverse(ch))
+    }
+    /// Every code of the character tables, in kana, latin, kanji order
+    fn entries(&self) -> impl Iterator<Item = (CharCode, char)> + '_ {
+        [
+            (LookupTable::Kana, &self.kana),
+            (LookupTable::Latin, &self.latin),
+            (LookupTable::Kanji, &self.kanji),
+        ]
+        .into_iter()
+        .flat_map(|(table, chars)| {
+            (0x00..=0xFF)
+                .filter_map(move |code| Some((CharCode::Single(table, code), chars.get(code)?)))
+        })
+    }
+    /// Replace one of the tables with the contents of a `.tbl` file
+    pub fn load_tbl(&mut self, table: LookupTable, text: &str) -> Result<(), TblError> {
+        match table {
+            LookupTable::Kana => self.kana = CodeTable::parse_tbl(text)?,
+            LookupTable::Kanji => self.kanji = CodeTable::parse_tbl(text)?,
+            LookupTable::Latin => self.latin = CodeTable::parse_tbl(text)?,
+            LookupTable::Button => self.button = CodeTable::parse_tbl(text)?,
+        }
+        Ok(())
+    }
+    /// Write one of the tables in `.tbl` format
+    pub fn to_tbl(&self, table: LookupTable) -> String {
+        match self.char_table(table) {
+            Some(chars) => chars.to_tbl(),
+            None => self.button.to_tbl(),
+        }
+    }
+}
+
+impl Default for Charset {
+    fn default() -> Self {
+        Self::builtin().clone()
+    }
+}
+
+#[test]
+fn test_builtin_tbl_files() {
+    let charset = Charset::builtin();
+    let files = [
+        (LookupTable::Kana, include_str!("../tables/kana.tbl")),
+        (LookupTable::Kanji, include_str!("../tables/kanji.tbl")),
+        (LookupTable::Latin, include_str!("../tables/latin.tbl")),
+        (LookupTable::Button, include_str!("../tables/button.tbl")),
+    ];
+    let mut loaded = Charset {
+        kana: CodeTable::new(),
+        kanji: CodeTable::new(),
+        latin: CodeTable::new(),
+        button: CodeTable::new(),
+        preferred: charset.preferred.clone(),
+        notes: charset.notes.clone(),
+    };
+    for (table, file) in files {
+        assert_eq!(charset.to_tbl(table), file);
+        loaded.load_tbl(table, file).unwrap();
+    }
+    assert_eq!(&loaded, charset);
+    assert_eq!(
+        CodeTable::<char>::parse_tbl("/FD\n00=あ\n01=いい"),
+        Err(TblError::MultiChar { line: 3 })
+    );
+    assert_eq!(
+        CodeTable::<char>::parse_tbl("00="),
+        Err(TblError::InvalidValue { line: 1 })
+    );
+}
+
+#[test]
+fn test_glyph_table() {
+    let table = GlyphTable::parse_tbl("01=!\n8140=的\n8141=一\n9F00=的\n").unwrap();
+    assert_eq!(table.single.get(0x01), Some('!'));
+    assert_eq!(table.get_wide(0x8141), Some('一'));
+    assert!(table.is_lead_byte(0x81) && table.is_lead_byte(0x9F));
+    assert!(!table.is_lead_byte(0x01) && !table.is_lead_byte(0x82));
+    assert_eq!(
+        table.wide_codes_of('的').collect::<Vec<_>>(),
+        [0x8140, 0x9F00]
+    );
+    assert_eq!(GlyphTable::parse_tbl(&table.to_tbl()), Ok(table));
+    assert_eq!(
+        GlyphTable::parse_tbl("814=的"),
+        Err(TblError::InvalidCode { line: 1 })
+    );
+}
+
+#[test]
+fn test_private_use() {
+    assert_eq!(private_use(LookupTable::Kana, 0xC4), Some('\u{E0C4}'));
+    assert_eq!(private_use(LookupTable::Button, 0x00), None);
+    for table in [LookupTable::Kana, LookupTable::Latin, LookupTable::Kanji] {
+        for code in [0x00, 0x7F, 0xFF] {
+            assert_eq!(
+                from_private_use(private_use(table, code).unwrap()),
+                Some((table, code))
+            );
+        }
+    }
+    assert_eq!(from_private_use('\u{E300}'), None);
+    assert_eq!(from_private_use('ん'), None);
+}
+
+#[test]
+fn test_glyph_info() {
+    let charset = Charset::builtin();
+    let info = |table, code| charset.glyph_info(table, code).unwrap();
+    assert_eq!(
+        info(LookupTable::Kana, 0x00),
+        GlyphInfo {
+            category: GlyphCategory::Hiragana,
+            confidence: Confidence::Guessed,
+            note: None
+        }
+    );
+    assert_eq!(
+        info(LookupTable::Kanji, 0x77),
+        GlyphInfo {
+            category: GlyphCategory::Kanji,
+            confidence: Confidence::Guessed,
+            note: None
+        }
+    );
+    assert_eq!(
+        info(LookupTable::Kanji, 0x78).confidence,
+        Confidence::Guessed
+    );
+    assert_eq!(
+        info(LookupTable::Kana, 0x4C).confidence,
+        Confidence::Guessed
+    );
+    assert_eq!(
+        info(LookupTable::Latin, 0x19).confidence,
+        Confidence::Guessed
+    );
+    assert_eq!(
+        info(LookupTable::Latin, 0x00).category,
+        GlyphCategory::Alphanumeric
+    );
+    assert_eq!(
+        info(LookupTable::Kana, 0x92).category,
+        GlyphCategory::Hiragana
+    );
+    assert_eq!(
+        info(LookupTable::Kana, 0xC4).confidence,
+        Confidence::Placeholder
+    );
+    assert_eq!(charset.glyph_info(LookupTable::Latin, 0xFF), None);
+    let placeholders: Vec<_> = MARIO_STORY_GLYPH_NOTES
+        .iter()
+        .filter(|&&(.., confidence, _)| confidence == Confidence::Placeholder)
+        .map(|&(table, code, ..)| (table, code))
+        .collect();
+    assert_eq!(placeholders, MARIO_STORY_APPROXIMATED);
+    for &(table, code, ..) in MARIO_STORY_GLYPH_NOTES {
+        assert!(charset.char(table, code).is_some(), "{table:?} {code:02X}");
+    }
+    assert_eq!(GlyphCategory::of('。'), GlyphCategory::Punctuation);
+    assert_eq!(GlyphCategory::of('ー'), GlyphCategory::Punctuation);
+    assert_eq!(GlyphCategory::of('ア'), GlyphCategory::Katakana);
+    assert_eq!(GlyphCategory::of('é'), GlyphCategory::Alphanumeric);
+    assert_eq!(GlyphCategory::of('♪'), GlyphCategory::Symbol);
+}
@@ -0,0 +1,497 @@
+//! Conversion to and from the message syntax of the Paper Mario decompilation.
+//!
+//! The decomp keeps its messages in `.msg` files like this:
+//!
+//! ```text
+//! #message:00:01 {
+//!     [Style right]
+//!     Hello[BR]
+//!     [Wait][Next]
+//!     Bye[End]
+//! }
+//! ```
+//!
+//! Newlines and indentation are only for readability, a linebreak is `[BR]`. Any other
+//! plain space is [`Event::Space`], and a literal `[`, `{`, `}` or `\` is escaped with `\`,
+//! so it can't end a tag or the message. A space at the start of a line is written as
+//! `[Space]`, so it isn't taken for indentation.
+//!
+//! Controls get their decomp names where the Mario Story command has the same byte and
+//! arguments, which hasn't been checked in the game for all of them. Everything else is
+//! written as `[Raw 0xFF 0x08]` with the stream order bytes, which the importer decodes
+//! again. Styles get the decomp name of their byte, whatever the [`Style`] is called. The
+//! decomp's choice, lamppost, postcard and upgrade styles take arguments that Mario Story
+//! doesn't have, so their bytes are written as `Raw` too.
+//!
+//! Events that don't stand for any bytes, like [`Event::ExtCmdError`], can't be written.
+
+use {
+    crate::{
+        encode::table_switch_code, markup::ParseError, msg::MessageId, tokenize_stream, Button,
+        DecodeError, DecodeOptions, Event, ExtCmd, Span, Strictness, Style, Token,
+    },
+    std::ops::Range,
+};
+
+#[cfg(test)]
+use std::assert_matches::assert_matches;
+
+/// Decomp names of the styles without arguments, by byte
+const STYLES: &[(Style, &str)] = &[
+    (Style::BubbleRight, "right"),
+    (Style::BubbleLeft, "left"),
+    (Style::BubbleA, "center"),
+    (Style::BubbleB, "tattle"),
+    (Style::NarrationA, "inspect"),
+    (Style::SignPost, "sign"),
+    (Style::WhiteBubbleA, "popup"),
+    (Style::NarrationSilent, "narrate"),
+    (Style::NoDisplayVCenter, "epilogue"),
+];
+
+/// Decomp names of ext commands
+const EXT_CMDS: &[(u8, &str)] = &[
+    (0x05, "Color"),
+    (0x0C, "Scroll"),
+    (0x0D, "Size"),
+    (0x0E, "SizeReset"),
+    (0x13, "Down"),
+    (0x14, "Up"),
+    (0x18, "Image"),
+    (0x24, "SaveColor"),
+    (0x25, "RestoreColor"),
+    (0x26, "StartFX"),
+    (0x27, "EndFX"),
+    (0x29, "CenterX"),
+    (0x2F, "Voice"),
+];
+
+/// Decomp names of buttons
+const BUTTONS: &[(Button, &str)] = &[
+    (Button::A, "A"),
+    (Button::B, "B"),
+    (Button::Start, "Start"),
+    (Button::CDown, "C-down"),
+    (Button::CLeft, "C-left"),
+    (Button::Z, "Z"),
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WriteError {
+    /// The arguments of this ext command were lost
+    ExtCmdError { id: u8 },
+    /// A decoding error that skipped no bytes, like a missing terminator
+    Decode(DecodeError),
+}
+
+impl std::fmt::Display for WriteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ExtCmdError { id } => write!(f, "erroneous ext command 0x{id:02X}"),
+            Self::Decode(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for WriteError {}
+
+/// Write events in decomp syntax
+pub fn to_msg(events: &[Event]) -> Result<String, WriteError> {
+    let mut s = String::new();
+    for event in events {
+        match event {
+            Event::StyleChange(style) => match STYLES.iter().find(|(st, _)| st == style) {
+                Some((_, name)) => s.push_str(&format!("[Style {name}]\n")),
+                None => write_raw(&[0xFC, *style as u8], &mut s),
+            },
+            Event::Space if s.is_empty() || s.ends_with('\n') => s.push_str("[Space]"),
+            Event::Space => s.push(' '),
+            Event::Dialog(text) => {
+                for ch in text.chars() {
+                    if matches!(ch, '[' | '{' | '}' | '\\') {
+                        s.push('\\');
+                    }
+                    s.push(ch);
+                }
+            }
+            Event::End => s.push_str("[End]\n"),
+            Event::Linebreak => s.push_str("[BR]\n"),
+            Event::Delay(amount) => s.push_str(&format!("[Pause {amount}]")),
+            Event::Bell => s.push_str("[Wait]"),
+            Event::NextBubble => s.push_str("[Next]\n"),
+            Event::ButtonRef { button, rawcode } => {
+                match BUTTONS
+                    .iter()
+                    .find(|(b, _)| Some(*b) == *button && b.code() == *rawcode)
+                {
+                    Some((_, name)) => s.push_str(&format!("[~{name}]")),
+                    None => write_raw(&[0xF6, *rawcode], &mut s),
+                }
+            }
+            Event::ExtCmd(cmd) => match EXT_CMDS
+                .iter()
+                .find(|(id, _)| *id == cmd.id())
+                .filter(|_| !matches!(cmd, ExtCmd::Other { .. }))
+            {
+                Some((_, name)) => {
+                    s.push('[');
+                    s.push_str(name);
+                    for arg in cmd.args() {
+                        s.push_str(&format!(" 0x{arg:02X}"));
+                    }
+                    s.push(']');
+                }
+                None => {
+                    let mut raw = vec![0xFF, cmd.id()];
+                    raw.extend(cmd.args());
+                    write_raw(&raw, &mut s);
+                }
+            },
+            // The table switch goes along, because it's part of what the code means
+            Event::RawChar { table, code } | Event::Unverified { table, code, .. } => {
+                write_raw(&[table_switch_code(*table), *code], &mut s)
+            }
+            Event::RawWideChar { code } => write_raw(&code.to_be_bytes(), &mut s),
+            Event::Sparkly => write_raw(&[0xD9], &mut s),
+            // Paper Mario's bytes, which the importer can't tell from Mario Story characters
+            Event::StyleWithParams { style, params } => {
+                let mut raw = vec![0xFC, *style as u8];
+                raw.extend(params);
+                write_raw(&raw, &mut s);
+            }
+            Event::FullSpace => write_raw(&[0xF8], &mut s),
+            Event::HalfSpace => write_raw(&[0xF9], &mut s),
+            Event::SaveColor => write_raw(&[0xFA], &mut s),
+            Event::Error { raw, .. } if !raw.is_empty() => write_raw(raw, &mut s),
+            Event::Error { error, .. } => return Err(WriteError::Decode(error.clone())),
+            Event::ExtCmdError { id, .. } => return Err(WriteError::ExtCmdError { id: *id }),
+        }
+    }
+    Ok(s)
+}
+
+/// Write a whole message, with its `#message` header
+pub fn write_message(id: MessageId, events: &[Event]) -> Result<String, WriteError> {
+    let body = to_msg(events)?;
+    let mut s = format!("#message:{:02X}:{:03X} {{\n", id.section(), id.index());
+    for line in body.lines() {
+        s.push_str("    ");
+        s.push_str(line);
+        s.push('\n');
+    }
+    s.push_str("}\n");
+    Ok(s)
+}
+
+fn write_raw(raw: &[u8], s: &mut String) {
+    s.push_str("[Raw");
+    for b in raw {
+        s.push_str(&format!(" 0x{b:02X}"));
+    }
+    s.push(']');
+}
+
+/// Read events from decomp syntax. This is the inverse of [`to_msg`].
+pub fn from_msg(text: &str) -> Result<Vec<Event>, ParseError> {
+    let mut events = Vec::new();
+    let mut buf = String::new();
+    let mut chars = text.char_indices();
+    let mut line_start = true;
+    macro_rules! flushbuf {
+        () => {
+            if !buf.is_empty() {
+                events.push(Event::Dialog(std::mem::take(&mut buf)));
+            }
+        };
+    }
+    while let Some((pos, ch)) = chars.next() {
+        let indent = line_start;
+        line_start = ch == '\n' || (line_start && ch == ' ');
+        match ch {
+            '\n' | '\r' | '\t' => {}
+            ' ' if indent => {}
+            ' ' => {
+                flushbuf!();
+                events.push(Event::Space);
+            }
+            '\\' => match chars.next() {
+                Some((_, ch)) => buf.push(ch),
+                None => return Err(ParseError::UnterminatedTag { pos }),
+            },
+            '[' => {
+                let Some(len) = text[pos..].find(']') else {
+                    return Err(ParseError::UnterminatedTag { pos });
+                };
+                let tag = &text[pos + 1..pos + len];
+                while chars.offset() <= pos + len {
+                    chars.next();
+                }
+                flushbuf!();
+                parse_tag(tag, pos, &mut events)?;
+            }
+            _ => buf.push(ch),
+        }
+    }
+    flushbuf!();
+    // Characters from `Raw` tags are separate from the text around them
+    let mut merged: Vec<Event> = Vec::with_capacity(events.len());
+    for event in events {
+        match (merged.last_mut(), event) {
+            (Some(Event::Dialog(text)), Event::Dialog(more)) => text.push_str(&more),
+            (_, event) => merged.push(event),
+        }
+    }
+    Ok(merged)
+}
+
+/// Read all messages of a `.msg` file
+pub fn parse_file(text: &str) -> Result<Vec<(MessageId, Vec<Event>)>, ParseError> {
+    let mut messages = Vec::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("#message:") {
+        let pos = text.len() - rest.len() + start;
+        let unterminated = || ParseError::UnterminatedTag { pos };
+        let header_end = rest[start..].find('{').ok_or_else(unterminated)? + start;
+        let header = rest[start + "#message:".len()..header_end].trim();
+        let id = header
+            .split_once(':')
+            .and_then(|(sec, idx)| {
+                Some(MessageId::new(
+                    u16::from_str_radix(sec, 16).ok()?,
+                    u16::from_str_radix(idx, 16).ok()?,
+                ))
+            })
+            .ok_or_else(|| ParseError::InvalidArgument {
+                pos,
+                tag: header.to_owned(),
+            })?;
+        let body_end = closing_brace(&rest[header_end + 1..]).ok_or_else(unterminated)?;
+        let body = &rest[header_end + 1..header_end + 1 + body_end];
+        let body_pos = text.len() - rest.len() + header_end + 1;
+        let events = from_msg(body).map_err(|e| offset_error(e, body_pos))?;
+        messages.push((id, events));
+        rest = &rest[header_end + 1 + body_end + 1..];
+    }
+    Ok(messages)
+}
+
+/// Position of the `}` closing a message body, skipping escaped characters and tags
+fn closing_brace(body: &str) -> Option<usize> {
+    let mut chars = body.char_indices();
+    let mut in_tag = false;
+    while let Some((pos, ch)) = chars.next() {
+        match ch {
+            '\\' => {
+                chars.next();
+            }
+            '[' => in_tag = true,
+            ']' => in_tag = false,
+            '}' if !in_tag => return Some(pos),
+            _ => {}
+        }
+    }
+    None
+}
+
+fn offset_error(e: ParseError, by: usize) -> ParseError {
+    match e {
+        ParseError::UnterminatedTag { pos } => ParseError::UnterminatedTag { pos: pos + by },
+        ParseError::UnknownTag { pos, tag } => ParseError::UnknownTag { pos: pos + by, tag },
+        ParseError::InvalidArgument { pos, tag } => {
+            ParseError::InvalidArgument { pos: pos + by, tag }
+        }
+    }
+}
+
+fn parse_tag(tag: &str, pos: usize, events: &mut Vec<Event>) -> Result<(), ParseError> {
+    let invalid = || ParseError::InvalidArgument {
+        pos,
+        tag: tag.to_owned(),
+    };
+    let mut words = tag.split_whitespace();
+    let name = words.next().unwrap_or_default();
+    let args: Vec<&str> = words.collect();
+    let nums = || -> Result<Vec<u8>, ParseError> {
+        args.iter()
+            .map(|arg| parse_num(arg).ok_or_else(invalid))
+            .collect()
+    };
+    let event = match name {
+        "BR" => Event::Linebreak,
+        "Space" => Event::Space,
+        "Wait" => Event::Bell,
+        "Next" => Event::NextBubble,
+        "End" => Event::End,
+        "Pause" => match nums()?[..] {
+            [amount] => Event::Delay(amount),
+            _ => return Err(invalid()),
+        },
+        "Style" => match args[..] {
+            [name] => STYLES
+                .iter()
+                .find(|(_, style)| *style == name)
+                .map(|(style, _)| Event::StyleChange(*style))
+                .ok_or_else(invalid)?,
+            _ => return Err(invalid()),
+        },
+        "Raw" => {
+            let raw = nums()?;
+            events.extend(decode_raw(&raw).ok_or_else(invalid)?);
+            return Ok(());
+        }
+        _ => {
+            if let Some(name) = name.strip_prefix('~') {
+                let (button, _) = BUTTONS
+                    .iter()
+                    .find(|(_, b)| *b == name)
+                    .ok_or_else(invalid)?;
+                Event::ButtonRef {
+                    button: Some(*button),
+                    rawcode: button.code(),
+                }
+            } else if let Some((id, _)) = EXT_CMDS.iter().find(|(_, cmd)| *cmd == name) {
+                match ExtCmd::new(*id, &nums()?) {
+                    ExtCmd::Other { .. } => return Err(invalid()),
+                    cmd => Event::ExtCmd(cmd),
+                }
+            } else {
+                return Err(ParseError::UnknownTag {
+                    pos,
+                    tag: tag.to_owned(),
+                });
+            }
+        }
+    };
+    events.push(event);
+    Ok(())
+}
+
+/// Decode the bytes of a `Raw` tag back into events
+fn decode_raw(raw: &[u8]) -> Option<Vec<Event>> {
+    let opts = DecodeOptions {
+        strictness: Strictness::Lenient,
+        stop_at_end: false,
+        ..Default::default()
+    };
+    let span_of = |r: Range<usize>| Span {
+        offset: r.start,
+        len: r.len(),
+    };
+    let mut events = Vec::new();
+    for tok in tokenize_stream(raw, span_of, &opts).ok()?.items {
+        match tok.inner {
+            Token::TableSwitch(_) => {}
+            Token::Char { ch: Some(ch), .. } | Token::WideChar { ch: Some(ch), .. } => {
+                events.push(Event::Dialog(ch.to_string()))
+            }
+            Token::Char { table, code, .. } => events.push(Event::RawChar { table, code }),
+            Token::WideChar { code, .. } => events.push(Event::RawWideChar { code }),
+            // Raw bytes never have a terminator
+            Token::Event(Event::Error { raw, .. }) if raw.is_empty() => {}
+            Token::Event(event) => events.push(event),
+        }
+    }
+    Some(events)
+}
+
+fn parse_num(s: &str) -> Option<u8> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u8::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+#[test]
+fn test_msg_roundtrip() {
+    let events = vec![
+        Event::StyleChange(Style::BubbleRight),
+        // Paper Mario has braces, which would end the message
+        Event::Dialog("あ[い{う}".into()),
+        Event::Space,
+        Event::ButtonRef {
+            button: Some(Button::CDown),
+            rawcode: 4,
+        },
+        Event::Linebreak,
+        Event::Space,
+        Event::ExtCmd(ExtCmd::TextColor { c: 0x0A }),
+        Event::ExtCmd(ExtCmd::Unk8 {}),
+        Event::RawChar {
+            table: crate::LookupTable::Kana,
+            code: 0xEE,
+        },
+        Event::Sparkly,
+        Event::Delay(10),
+        Event::Bell,
+        Event::NextBubble,
+        Event::StyleChange(Style::NarrationB),
+        Event::StyleChange(Style::NoDisplayVCenter),
+        Event::StyleChange(Style::WhiteBorder),
+        Event::End,
+    ];
+    let text = to_msg(&events).unwrap();
+    assert_eq!(
+        text,
+        "[Style right]\nあ\\[い\\{う\\} [~C-down][BR]\n[Space][Color 0x0A][Raw 0xFF 0x08]\
+         [Raw 0xF3 0xEE][Raw 0xD9][Pause 10][Wait][Next]\n[Raw 0xFC 0x0F][Style epilogue]\n\
+         [Raw 0xFC 0x05][End]\n"
+    );
+    assert_eq!(from_msg(&text).unwrap(), events);
+    let file = format!(
+        "{}\n{}",
+        write_message(MessageId::new(1, 0x2A), &events).unwrap(),
+        write_message(MessageId::new(1, 0x2B), &[Event::End]).unwrap()
+    );
+    assert!(file.starts_with("#message:01:02A {\n    [Style right]\n"));
+    assert_eq!(
+        parse_file(&file).unwrap(),
+        vec![
+            (MessageId::new(1, 0x2A), events),
+            (MessageId::new(1, 0x2B), vec![Event::End])
+        ]
+    );
+    assert_eq!(
+        from_msg("[Style sideways]"),
+        Err(ParseError::InvalidArgument {
+            pos: 0,
+            tag: "Style sideways".into()
+        })
+    );
+    // Paper Mario's choice box takes a position and size
+    assert_matches!(
+        from_msg("[Style choice]"),
+        Err(ParseError::InvalidArgument { .. })
+    );
+    // Parameters the named command doesn't take are kept as bytes, not dropped
+    let other = Event::ExtCmd(ExtCmd::Other {
+        id: 0x05,
+        args: vec![1, 2],
+    });
+    assert_eq!(to_msg(&[other]).unwrap(), "[Raw 0xFF 0x05 0x01 0x02]");
+    assert_matches!(
+        from_msg("[Color 0x01 0x02]"),
+        Err(ParseError::InvalidArgument { .. })
+    );
+    assert_eq!(
+        to_msg(&[Event::ExtCmdError {
+            id: 0x05,
+            argc: 1,
+            args_got: 0
+        }]),
+        Err(WriteError::ExtCmdError { id: 0x05 })
+    );
+    let error = DecodeError::MissingTerminator {
+        offset: 2,
+        state: crate::Status::Init,
+    };
+    assert_eq!(
+        to_msg(&[
+            Event::Dialog("あ".into()),
+            Event::Error {
+                error: error.clone(),
+                raw: vec![]
+            }
+        ]),
+        Err(WriteError::Decode(error))
+    );
+}
@@ -0,0 +1,897 @@
+//! Which game's text format the decoders and encoders speak.
+//!
+//! A [`Dialect`] decides what every byte of a dialog stream means: which bytes are control
+//! codes, how many parameters each ext command takes, and which character each code stands
+//! for. The event model ([`Event`](crate::Event), [`Token`](crate::Token)) is the same for
+//! every dialect. [`MarioStory`] is the default, [`PaperMario`] reads the western releases
+//! and [`Ique`] the Chinese one.
+
+use {
+    crate::{
+        charsets::{ambiguities, resolve},
+        encode::table_switch_code,
+        extcmd, private_use, Ambiguity, Button, CharCode, Charset, CodeTable, Confidence,
+        GlyphCategory, GlyphInfo, GlyphTable, LookupTable, ReverseError, Style,
+    },
+    std::{ops::RangeInclusive, sync::OnceLock},
+};
+
+#[cfg(test)]
+use crate::{encode_events_with, swap_words, translate_with, DecodeOptions, EncodeError, Event};
+#[cfg(test)]
+use std::assert_matches::assert_matches;
+
+/// Meaning of a byte at the top level of the dialog stream, outside of any command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Control {
+    Linebreak,
+    Bell,
+    /// Followed by the delay amount
+    Delay,
+    TableSwitch(LookupTable),
+    Space,
+    FullSpace,
+    HalfSpace,
+    SaveColor,
+    NextBubble,
+    /// Followed by a [`Style`](crate::Style)
+    Style,
+    End,
+    /// Followed by the command id and its parameters
+    ExtCmd,
+    Sparkly,
+}
+
+/// Character mapping and control code grammar of a game's dialog text
+pub trait Dialect: std::fmt::Debug + Send + Sync {
+    /// What `byte` means when it's not part of a command. `None` for character codes.
+    fn control(&self, byte: u8) -> Option<Control>;
+    /// The byte for `control`, or `None` if this dialect doesn't have it
+    fn control_code(&self, control: Control) -> Option<u8>;
+    /// Number of parameters of the ext command `id`, or `None` if it's unknown
+    fn ext_params(&self, id: u8) -> Option<u8> {
+        extcmd::n_params(id)
+    }
+    /// Number of parameters of the ext command `id` that follow `args`, for commands that
+    /// read more depending on the parameters before. Called every time the parameters run
+    /// out, first with the [`Dialect::ext_params`] ones.
+    fn ext_extra_params(&self, _id: u8, _args: &[u8]) -> u8 {
+        0
+    }
+    /// Number of parameters that follow `style`
+    fn style_params(&self, _style: Style) -> u8 {
+        0
+    }
+    /// The lookup table a stream starts out in
+    fn initial_table(&self) -> LookupTable {
+        LookupTable::Kana
+    }
+    /// Character for `code` in `table`
+    fn char(&self, table: LookupTable, code: u8) -> Option<char>;
+    /// Button for `code` in the button table
+    fn button(&self, code: u8) -> Option<Button>;
+    /// The code to encode `ch` with. Never returns a control code or a lead byte.
+    ///
+    /// When several codes decode to `ch`, this only picks one if the dialect has a rule
+    /// for it, and is [`ReverseError::Ambiguous`] otherwise.
+    fn reverse(&self, ch: char) -> Result<CharCode, ReverseError>;
+    /// Every character more than one code decodes to, and which code [`Dialect::reverse`]
+    /// picks for it
+    fn ambiguities(&self) -> Vec<Ambiguity>;
+    /// What is known about the glyph of `code` in `table`, if it decodes to a character.
+    ///
+    /// Without metadata, every glyph counts as [`Confidence::Guessed`].
+    fn glyph_info(&self, table: LookupTable, code: u8) -> Option<GlyphInfo> {
+        let ch = self.char(table, code)?;
+        Some(GlyphInfo {
+            category: GlyphCategory::of(ch),
+            confidence: Confidence::Guessed,
+            note: None,
+        })
+    }
+    /// Whether `code` in `table` decodes to a character that only looks like its glyph, or
+    /// that other codes decode to as well. See [`GlyphMode`](crate::GlyphMode).
+    fn is_approximate(&self, _table: LookupTable, _code: u8) -> bool {
+        false
+    }
+    /// Whether `byte` is the first byte of a two byte character code. Checked before
+    /// [`Dialect::control`], so a lead byte is never a control code.
+    fn is_lead_byte(&self, _byte: u8) -> bool {
+        false
+    }
+    /// Character for a two byte code, with the lead byte as the high byte
+    fn wide_char(&self, _code: u16) -> Option<char> {
+        None
+    }
+    /// How [`Control::Space`] is written in plain text
+    fn space(&self) -> char {
+        '\u{3000}'
+    }
+}
+
+/// How many parameters the ext command `id` takes when it starts with `args`, or `None` if
+/// `dialect` doesn't know it
+pub(crate) fn ext_param_count(dialect: &dyn Dialect, id: u8, args: &[u8]) -> Option<usize> {
+    let mut count = usize::from(dialect.ext_params(id)?);
+    while count <= args.len() {
+        match dialect.ext_extra_params(id, &args[..count]) {
+            0 => break,
+            more => count += usize::from(more),
+        }
+    }
+    Some(count)
+}
+
+/// Mario Story (Japan), with the built-in or custom lookup tables
+#[derive(Debug, Clone, Default)]
+pub struct MarioStory {
+    pub charset: Charset,
+}
+
+impl MarioStory {
+    /// Mario Story with the built-in tables
+    pub fn builtin() -> &'static Self {
+        static BUILTIN: OnceLock<MarioStory> = OnceLock::new();
+        BUILTIN.get_or_init(Self::default)
+    }
+    pub fn with_charset(charset: Charset) -> Self {
+        Self { charset }
+    }
+}
+
+impl Dialect for MarioStory {
+    fn control(&self, byte: u8) -> Option<Control> {
+        Some(match byte {
+            0xD9 => Control::Sparkly,
+            0xF0 => Control::Linebreak,
+            0xF1 => Control::Bell,
+            0xF2 => Control::Delay,
+            0xF3 => Control::TableSwitch(LookupTable::Kana),
+            0xF4 => Control::TableSwitch(LookupTable::Latin),
+            0xF5 => Control::TableSwitch(LookupTable::Kanji),
+            0xF6 => Control::TableSwitch(LookupTable::Button),
+            0xF7 => Control::Space,
+            0xFB => Control::NextBubble,
+            0xFC => Control::Style,
+            0xFD => Control::End,
+            0xFF => Control::ExtCmd,
+            _ => return None,
+        })
+    }
+    fn control_code(&self, control: Control) -> Option<u8> {
+        Some(match control {
+            Control::Sparkly => 0xD9,
+            Control::Linebreak => 0xF0,
+            Control::Bell => 0xF1,
+            Control::Delay => 0xF2,
+            Control::TableSwitch(table) => table_switch_code(table),
+            Control::Space => 0xF7,
+            Control::NextBubble => 0xFB,
+            Control::Style => 0xFC,
+            Control::End => 0xFD,
+            Control::ExtCmd => 0xFF,
+            Control::FullSpace | Control::HalfSpace | Control::SaveColor => return None,
+        })
+    }
+    fn char(&self, table: LookupTable, code: u8) -> Option<char> {
+        self.charset.char(table, code)
+    }
+    fn glyph_info(&self, table: LookupTable, code: u8) -> Option<GlyphInfo> {
+        self.charset.glyph_info(table, code)
+    }
+    fn is_approximate(&self, table: LookupTable, code: u8) -> bool {
+        let Some(ch) = self.char(table, code) else {
+            return false;
+        };
+        let placeholder = self
+            .glyph_info(table, code)
+            .is_some_and(|info| info.confidence == Confidence::Placeholder);
+        placeholder || self.reverse(ch) != Ok(CharCode::Single(table, code))
+    }
+    fn button(&self, code: u8) -> Option<Button> {
+        self.charset.button(code)
+    }
+    fn reverse(&self, ch: char) -> Result<CharCode, ReverseError> {
+        self.charset.reverse(ch)
+    }
+    fn ambiguities(&self) -> Vec<Ambiguity> {
+        self.charset.ambiguities()
+    }
+}
+
+/// Describe the [`private_use`] code points a dialect's approximated codes decode to, for
+/// building a font that shows them as the real glyphs.
+///
+/// Every line has the code point, the lookup table and code, and the approximating
+/// character, separated by tabs, like `U+E0C4\tkana\tC4\tん`.
+pub fn font_mapping(dialect: &dyn Dialect) -> String {
+    let mut out = String::new();
+    for (table, name) in [
+        (LookupTable::Kana, "kana"),
+        (LookupTable::Latin, "latin"),
+        (LookupTable::Kanji, "kanji"),
+    ] {
+        for code in 0x00..=0xFF {
+            if dialect.control(code).is_some() || !dialect.is_approximate(table, code) {
+                continue;
+            }
+            let (Some(pua), Some(ch)) = (private_use(table, code), dialect.char(table, code))
+            else {
+                continue;
+            };
+            out.push_str(&format!(
+                "U+{:04X}\t{name}\t{code:02X}\t{ch}\n",
+                u32::from(pua)
+            ));
+        }
+    }
+    out
+}
+
+/// Paper Mario (US and PAL).
+///
+/// There is a single lookup table, [`LookupTable::Latin`], with ASCII and the accented
+/// letters the European translations need. The control codes follow the decompilation.
+/// Most are the same as Mario Story's, but `0xF3..=0xF6` select font variants instead of
+/// lookup tables, `0xF8..=0xFA` are the full space, half space and color save, and there
+/// is no sparkly text. The font variant bytes decode as
+/// [`Event::RawChar`](crate::Event::RawChar)s, so they survive a round trip.
+///
+/// The choice, lamppost, postcard and upgrade styles are followed by parameters, and decode
+/// as [`Event::StyleWithParams`](crate::Event::StyleWithParams). The ext commands are
+/// [`PAPER_MARIO_EXT_PARAMS`]. Those without a Mario Story name decode as
+/// [`ExtCmd::Other`](crate::ExtCmd::Other).
+#[derive(Debug, Clone)]
+pub struct PaperMario {
+    pub table: CodeTable<char>,
+}
+
+impl PaperMario {
+    /// Paper Mario with the built-in table, `tables/paper_mario.tbl`
+    pub fn builtin() -> &'static Self {
+        static BUILTIN: OnceLock<PaperMario> = OnceLock::new();
+        BUILTIN.get_or_init(|| Self {
+            table: CodeTable::parse_tbl(include_str!("../tables/paper_mario.tbl"))
+                .expect("built-in table is valid"),
+        })
+    }
+    pub fn with_table(table: CodeTable<char>) -> Self {
+        Self { table }
+    }
+}
+
+impl Default for PaperMario {
+    fn default() -> Self {
+        Self::builtin().clone()
+    }
+}
+
+/// Parameter counts of the Paper Mario ext commands, after the read functions of the
+/// decompilation's `msg.c`. [`ExtCmd`](crate::ExtCmd) names the ones Mario Story has too.
+///
+/// The start effect command (`0x26`) reads one more byte for the static, blur and dither
+/// fade effects (`3`, `4` and `6`), see [`Dialect::ext_extra_params`].
+pub const PAPER_MARIO_EXT_PARAMS: &[(u8, u8)] = &[
+    (0x00, 1), // font
+    (0x01, 0), // variants 1 to 3
+    (0x02, 0),
+    (0x03, 0),
+    (0x04, 0), // yield
+    (0x05, 1), // color
+    (0x06, 0), // no skip
+    (0x07, 0), // input off
+    (0x08, 0), // input on
+    (0x09, 1), // spacing
+    (0x0A, 0), // delay off
+    (0x0B, 0), // delay on
+    (0x0C, 1), // scroll
+    (0x0D, 2), // size
+    (0x0E, 0), // size reset
+    (0x0F, 2), // speed: delay, characters per step
+    (0x10, 2), // x position, 16 bits
+    (0x11, 1), // y position
+    (0x12, 1), // right
+    (0x13, 1), // down
+    (0x14, 1), // up
+    (0x15, 1), // inline image
+    (0x16, 3), // animated sprite: sprite, 16 bits, and raster
+    (0x17, 2), // item icon, 16 bits
+    (0x18, 7), // image
+    (0x19, 1), // hide image
+    (0x1A, 3), // animation delay
+    (0x1B, 2), // animation loop
+    (0x1C, 1), // animation done
+    (0x1D, 3), // set cursor: index, x, y
+    (0x1E, 1), // cursor
+    (0x1F, 1), // end choice
+    (0x20, 1), // set cancel
+    (0x21, 1), // option
+    (0x22, 0), // save position
+    (0x23, 0), // restore position
+    (0x24, 0), // save color
+    (0x25, 0), // restore color
+    (0x26, 1), // start effect
+    (0x27, 1), // end effect
+    (0x28, 1), // variable
+    (0x29, 1), // center x
+    (0x2A, 1), // set rewind
+    (0x2B, 0), // enable C down next
+    (0x2C, 8), // custom voice: two 32 bit values
+    (0x2E, 1), // volume
+    (0x2F, 1), // voice
+];
+
+impl Dialect for PaperMario {
+    fn control(&self, byte: u8) -> Option<Control> {
+        Some(match byte {
+            0xF0 => Control::Linebreak,
+            0xF1 => Control::Bell,
+            0xF2 => Control::Delay,
+            0xF7 => Control::Space,
+            0xF8 => Control::FullSpace,
+            0xF9 => Control::HalfSpace,
+            0xFA => Control::SaveColor,
+            0xFB => Control::NextBubble,
+            0xFC => Control::Style,
+            0xFD => Control::End,
+            0xFF => Control::ExtCmd,
+            _ => return None,
+        })
+    }
+    fn control_code(&self, control: Control) -> Option<u8> {
+        Some(match control {
+            Control::Linebreak => 0xF0,
+            Control::Bell => 0xF1,
+            Control::Delay => 0xF2,
+            Control::Space => 0xF7,
+            Control::FullSpace => 0xF8,
+            Control::HalfSpace => 0xF9,
+            Control::SaveColor => 0xFA,
+            Control::NextBubble => 0xFB,
+            Control::Style => 0xFC,
+            Control::End => 0xFD,
+            Control::ExtCmd => 0xFF,
+            Control::TableSwitch(_) | Control::Sparkly => return None,
+        })
+    }
+    fn ext_params(&self, id: u8) -> Option<u8> {
+        PAPER_MARIO_EXT_PARAMS
+            .iter()
+            .find(|&&(cmd, _)| cmd == id)
+            .map(|&(_, argc)| argc)
+    }
+    fn ext_extra_params(&self, id: u8, args: &[u8]) -> u8 {
+        match (id, args) {
+            (0x26, [3 | 4 | 6]) => 1,
+            _ => 0,
+        }
+    }
+    fn style_params(&self, style: Style) -> u8 {
+        match style {
+            // Choice and upgrade boxes: position and size
+            Style::WhiteBorder | Style::NoDisplay => 4,
+            // Lamppost: height. Postcard: image index.
+            Style::BlueMessage | Style::Invalid2 => 1,
+            _ => 0,
+        }
+    }
+    fn initial_table(&self) -> LookupTable {
+        LookupTable::Latin
+    }
+    fn char(&self, table: LookupTable, code: u8) -> Option<char> {
+        match table {
+            LookupTable::Latin => self.table.get(code),
+            _ => None,
+        }
+    }
+    fn glyph_info(&self, table: LookupTable, code: u8) -> Option<GlyphInfo> {
+        let ch = self.char(table, code)?;
+        Some(GlyphInfo {
+            category: GlyphCategory::of(ch),
+            confidence: Confidence::Guessed,
+            note: Some("from the decompilation's charset, not checked against the font".into()),
+        })
+    }
+    fn button(&self, _code: u8) -> Option<Button> {
+        None
+    }
+    fn reverse(&self, ch: char) -> Result<CharCode, ReverseError> {
+        let candidates = self
+            .table
+            .codes_of(ch)
+            .filter(|&code| self.control(code).is_none())
+            .map(|code| CharCode::Single(LookupTable::Latin, code))
+            .collect();
+        resolve(candidates, None)
+    }
+    fn ambiguities(&self) -> Vec<Ambiguity> {
+        let entries = (0x00..=0xFF).filter_map(|code| {
+            Some((
+                CharCode::Single(LookupTable::Latin, code),
+                self.table.get(code)?,
+            ))
+        });
+        ambiguities(entries, |ch| self.reverse(ch))
+    }
+    fn space(&self) -> char {
+        ' '
+    }
+}
+
+/// Paper Mario (iQue, Chinese).
+///
+/// Besides the single byte codes, characters are two byte codes, lead byte first. The
+/// lead bytes are [`Ique::DEFAULT_LEAD_BYTES`] unless given with [`Ique::with_lead_bytes`],
+/// whatever the glyph table contains. The control
+/// codes are assumed to be the same as [`PaperMario`]'s, which hasn't been checked
+/// against the game.
+///
+/// There is no built-in table of the Chinese glyphs. [`Ique::builtin`] knows the single
+/// byte codes of [`PaperMario`] outside the lead bytes, and decodes every two byte code
+/// as [`Event::RawWideChar`](crate::Event::RawWideChar). Load a table with
+/// [`GlyphTable::parse_tbl`] and [`Ique::with_glyphs`] for the characters.
+#[derive(Debug, Clone)]
+pub struct Ique {
+    glyphs: GlyphTable,
+    lead_bytes: RangeInclusive<u8>,
+}
+
+impl Ique {
+    /// First bytes of the two byte codes, if not given. A guess that hasn't been checked
+    /// against the game: the accented letters of [`PaperMario`], which the Chinese release
+    /// has no use for.
+    pub const DEFAULT_LEAD_BYTES: RangeInclusive<u8> = 0x5F..=0x8F;
+    /// iQue Paper Mario without any two byte codes
+    pub fn builtin() -> &'static Self {
+        static BUILTIN: OnceLock<Ique> = OnceLock::new();
+        BUILTIN.get_or_init(|| {
+            let mut glyphs = GlyphTable::new();
+            glyphs.single = PaperMario::builtin().table.clone();
+            for code in Self::DEFAULT_LEAD_BYTES {
+                glyphs.single.set(code, None);
+            }
+            Self {
+                glyphs,
+                lead_bytes: Self::DEFAULT_LEAD_BYTES,
+            }
+        })
+    }
+    /// iQue Paper Mario with `glyphs`, and the [`Ique::DEFAULT_LEAD_BYTES`]
+    pub fn with_glyphs(glyphs: GlyphTable) -> Result<Self, LeadByteError> {
+        Self::with_lead_bytes(glyphs, Self::DEFAULT_LEAD_BYTES)
+    }
+    /// iQue Paper Mario with `glyphs`, and two byte codes starting with `lead_bytes`.
+    ///
+    /// Fails if a two byte code doesn't start with a lead byte, like `FD40`, which would
+    /// decode as a terminator and a character. Single byte codes of lead bytes are never
+    /// decoded.
+    pub fn with_lead_bytes(
+        glyphs: GlyphTable,
+        lead_bytes: RangeInclusive<u8>,
+    ) -> Result<Self, LeadByteError> {
+        let invalid = glyphs
+            .wide_codes()
+            .find(|code| !lead_bytes.contains(&code.to_be_bytes()[0]));
+        match invalid {
+            Some(code) => Err(LeadByteError { code }),
+            None => Ok(Self { glyphs, lead_bytes }),
+        }
+    }
+    pub fn glyphs(&self) -> &GlyphTable {
+        &self.glyphs
+    }
+    pub fn lead_bytes(&self) -> RangeInclusive<u8> {
+        self.lead_bytes.clone()
+    }
+}
+
+/// A two byte code that doesn't start with a lead byte, see [`Ique::with_glyphs`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LeadByteError {
+    pub code: u16,
+}
+
+impl std::fmt::Display for LeadByteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "code {:04X} doesn't start with a lead byte", self.code)
+    }
+}
+
+impl std::error::Error for LeadByteError {}
+
+impl Default for Ique {
+    fn default() -> Self {
+        Self::builtin().clone()
+    }
+}
+
+impl Dialect for Ique {
+    fn control(&self, byte: u8) -> Option<Control> {
+        PaperMario::builtin().control(byte)
+    }
+    fn control_code(&self, control: Control) -> Option<u8> {
+        PaperMario::builtin().control_code(control)
+    }
+    fn ext_params(&self, id: u8) -> Option<u8> {
+        PaperMario::builtin().ext_params(id)
+    }
+    fn ext_extra_params(&self, id: u8, args: &[u8]) -> u8 {
+        PaperMario::builtin().ext_extra_params(id, args)
+    }
+    fn style_params(&self, style: Style) -> u8 {
+        PaperMario::builtin().style_params(style)
+    }
+    fn initial_table(&self) -> LookupTable {
+        LookupTable::Latin
+    }
+    fn char(&self, table: LookupTable, code: u8) -> Option<char> {
+        match table {
+            LookupTable::Latin => self.glyphs.single.get(code),
+            _ => None,
+        }
+    }
+    fn button(&self, _code: u8) -> Option<Button> {
+        None
+    }
+    fn reverse(&self, ch: char) -> Result<CharCode, ReverseError> {
+        let candidates = self
+            .glyphs
+            .entries()
+            .filter(|&(code, value)| {
+                value == ch
+                    && match code {
+                        CharCode::Single(_, code) => {
+                            !self.is_lead_byte(code) && self.control(code).is_none()
+                        }
+                        CharCode::Wide(_) => true,
+                    }
+            })
+            .map(|(code, _)| code)
+            .collect();
+        resolve(candidates, None)
+    }
+    fn ambiguities(&self) -> Vec<Ambiguity> {
+        ambiguities(self.glyphs.entries(), |ch| self.reverse(ch))
+    }
+    fn is_lead_byte(&self, byte: u8) -> bool {
+        self.lead_bytes.contains(&byte)
+    }
+    fn wide_char(&self, code: u16) -> Option<char> {
+        self.glyphs.get_wide(code)
+    }
+    fn space(&self) -> char {
+        ' '
+    }
+}
+
+/// Mario Story with the end and linebreak codes swapped
+#[cfg(test)]
+#[derive(Debug)]
+struct Swapped;
+
+#[cfg(test)]
+impl Dialect for Swapped {
+    fn control(&self, byte: u8) -> Option<Control> {
+        match byte {
+            0xF0 => Some(Control::End),
+            0xFD => Some(Control::Linebreak),
+            _ => MarioStory::builtin().control(byte),
+        }
+    }
+    fn control_code(&self, control: Control) -> Option<u8> {
+        match control {
+            Control::End => Some(0xF0),
+            Control::Linebreak => Some(0xFD),
+            Control::Sparkly => None,
+            _ => MarioStory::builtin().control_code(control),
+        }
+    }
+    fn char(&self, table: LookupTable, code: u8) -> Option<char> {
+        MarioStory::builtin().char(table, code)
+    }
+    fn button(&self, code: u8) -> Option<Button> {
+        MarioStory::builtin().button(code)
+    }
+    fn reverse(&self, ch: char) -> Result<CharCode, ReverseError> {
+        MarioStory::builtin().reverse(ch)
+    }
+    fn ambiguities(&self) -> Vec<Ambiguity> {
+        MarioStory::builtin().ambiguities()
+    }
+}
+
+#[test]
+fn test_dialect() {
+    let opts = DecodeOptions {
+        dialect: Some(std::sync::Arc::new(Swapped)),
+        ..Default::default()
+    };
+    let raw = swap_words(&[0x00, 0xFD, 0x01, 0xF0]);
+    let events: Vec<Event> = translate_with(&raw, &opts)
+        .unwrap()
+        .items
+        .into_iter()
+        .map(|ev| ev.inner)
+        .collect();
+    assert_eq!(
+        events,
+        [
+            Event::Dialog("あ".into()),
+            Event::Linebreak,
+            Event::Dialog("い".into()),
+            Event::End
+        ]
+    );
+    assert_eq!(encode_events_with(&events, &Swapped).unwrap(), raw);
+    assert_eq!(
+        encode_events_with(&[Event::Sparkly], &Swapped),
+        Err(EncodeError::Unsupported {
+            control: Control::Sparkly
+        })
+    );
+}
+
+#[test]
+fn test_paper_mario() {
+    let opts = DecodeOptions {
+        dialect: Some(std::sync::Arc::new(PaperMario::default())),
+        ..Default::default()
+    };
+    // "Hi, Mario!" with a font variant switch and an accented letter
+    let stream = [
+        0xFC, 0x01, 0x28, 0x49, 0x0C, 0xF7, 0x2D, 0x41, 0x52, 0x49, 0x4F, 0x01, 0xF0, 0xF4, 0x7D,
+        0xFD,
+    ];
+    let raw = swap_words(&stream);
+    assert_eq!(
+        crate::to_string_with(&raw, &opts).unwrap(),
+        "Hi, Mario!\n[raw:latin:F4]é"
+    );
+    let events: Vec<Event> = translate_with(&raw, &opts)
+        .unwrap()
+        .items
+        .into_iter()
+        .map(|ev| ev.inner)
+        .collect();
+    assert_eq!(
+        encode_events_with(&events, PaperMario::builtin()).unwrap(),
+        raw
+    );
+    let text = [Event::Dialog("Ça va? ".into()), Event::End];
+    assert_eq!(
+        swap_words(&encode_events_with(&text, PaperMario::builtin()).unwrap()),
+        [0x64, 0x41, 0xF7, 0x56, 0x41, 0x1F, 0xF7, 0xFD]
+    );
+    // A lamppost with its height, the spaces and the color save, then a choice box
+    let stream = [
+        0xFC, 0x08, 0x28, 0x28, 0xF8, 0xF9, 0xFA, 0xFC, 0x05, 0x10, 0x20, 0x30, 0x40, 0xFD,
+    ];
+    let raw = swap_words(&stream);
+    let events: Vec<Event> = translate_with(&raw, &opts)
+        .unwrap()
+        .items
+        .into_iter()
+        .map(|ev| ev.inner)
+        .collect();
+    assert_eq!(
+        events,
+        [
+            Event::StyleWithParams {
+                style: Style::BlueMessage,
+                params: vec![0x28]
+            },
+            Event::Dialog("H".into()),
+            Event::FullSpace,
+            Event::HalfSpace,
+            Event::SaveColor,
+            Event::StyleWithParams {
+                style: Style::WhiteBorder,
+                params: vec![0x10, 0x20, 0x30, 0x40]
+            },
+            Event::End
+        ]
+    );
+    let text = crate::markup::print(&events);
+    assert_eq!(
+        text,
+        "[style:BlueMessage:40]H[full_space][half_space][push_color]\
+         [style:WhiteBorder:16,32,48,64][end]"
+    );
+    assert_eq!(
+        crate::encode_with(&text, PaperMario::builtin()).unwrap(),
+        raw
+    );
+    assert_eq!(
+        encode_events_with(
+            &[Event::StyleChange(Style::WhiteBorder)],
+            PaperMario::builtin()
+        ),
+        Err(EncodeError::StyleParams {
+            style: Style::WhiteBorder,
+            expected: 4
+        })
+    );
+    assert_matches!(
+        translate_with(&swap_words(&[0xFC, 0x05, 0x10]), &opts),
+        Err(crate::DecodeError::TruncatedStyle {
+            state: crate::Status::StyleParams { argidx: 1, .. },
+            ..
+        })
+    );
+    // Nothing of the table is checked against the font
+    let info = PaperMario::builtin().glyph_info(LookupTable::Latin, 0x21);
+    assert_eq!(info.map(|info| info.confidence), Some(Confidence::Guessed));
+}
+
+#[test]
+fn test_paper_mario_ext() {
+    use crate::{ExtCmd, Strictness};
+    let opts = DecodeOptions {
+        dialect: Some(std::sync::Arc::new(PaperMario::default())),
+        strictness: Strictness::Strict,
+        ..Default::default()
+    };
+    // Speed, position, yield and font, a dither fade with its alpha and a wave, then the
+    // cursor and option commands of a choice
+    let stream = [
+        0xFF, 0x0F, 0x00, 0x03, 0x28, 0xFF, 0x10, 0x00, 0x20, 0xFF, 0x11, 0x08, 0xFF, 0x04, 0xFF,
+        0x00, 0x03, 0xFF, 0x26, 0x06, 0x80, 0xFF, 0x26, 0x01, 0xFF, 0x1E, 0x00, 0xFF, 0x21, 0x00,
+        0xFF, 0x1F, 0x02, 0xFF, 0x20, 0x01, 0xFD,
+    ];
+    let raw = swap_words(&stream);
+    let events: Vec<Event> = translate_with(&raw, &opts)
+        .unwrap()
+        .items
+        .into_iter()
+        .map(|ev| ev.inner)
+        .collect();
+    let other = |id, args: &[u8]| {
+        Event::ExtCmd(ExtCmd::Other {
+            id,
+            args: args.to_vec(),
+        })
+    };
+    assert_eq!(
+        events,
+        [
+            other(0x0F, &[0x00, 0x03]),
+            Event::Dialog("H".into()),
+            other(0x10, &[0x00, 0x20]),
+            other(0x11, &[0x08]),
+            other(0x04, &[]),
+            other(0x00, &[0x03]),
+            other(0x26, &[0x06, 0x80]),
+            Event::ExtCmd(ExtCmd::StartEffect { id: 0x01 }),
+            other(0x1E, &[0x00]),
+            other(0x21, &[0x00]),
+            other(0x1F, &[0x02]),
+            other(0x20, &[0x01]),
+            Event::End,
+        ]
+    );
+    let text = crate::markup::print(&events);
+    assert_eq!(
+        text,
+        "[ext:0x0F:0,3]H[ext:0x10:0,32][ext:0x11:8][ext:0x04:][ext:0x00:3][fx:6,128][fx:1]\
+         [ext:0x1E:0][ext:0x21:0][ext:0x1F:2][ext:0x20:1][end]"
+    );
+    assert_eq!(
+        crate::encode_with(&text, PaperMario::builtin()).unwrap(),
+        raw
+    );
+    // Mario Story has no such commands
+    assert_matches!(
+        translate_with(&raw, &DecodeOptions::default()),
+        Err(crate::DecodeError::UnknownCommand { id: 0x0F, .. })
+    );
+    assert_eq!(
+        crate::encode_with("[fx:6]", PaperMario::builtin()),
+        Err(EncodeError::ExtParams {
+            id: 0x26,
+            expected: 2
+        })
+    );
+}
+
+#[test]
+fn test_ique() {
+    let glyphs = GlyphTable::parse_tbl("01=!\n28=H\n8140=你\n8141=好\n").unwrap();
+    let ique = Ique::with_glyphs(glyphs).unwrap();
+    let opts = DecodeOptions {
+        dialect: Some(std::sync::Arc::new(ique.clone())),
+        ..Default::default()
+    };
+    // An unknown two byte code, and a lead byte followed by what would be a terminator
+    let stream = [
+        0x81, 0x40, 0x81, 0x41, 0x01, 0x81, 0x42, 0xF7, 0x81, 0xFD, 0xFD,
+    ];
+    let raw = swap_words(&stream);
+    let events: Vec<Event> = translate_with(&raw, &opts)
+        .unwrap()
+        .items
+        .into_iter()
+        .map(|ev| ev.inner)
+        .collect();
+    assert_eq!(
+        events,
+        [
+            Event::Dialog("你好!".into()),
+            Event::RawWideChar { code: 0x8142 },
+            Event::Space,
+            Event::RawWideChar { code: 0x81FD },
+            Event::End
+        ]
+    );
+    assert_eq!(
+        crate::to_string_with(&raw, &opts).unwrap(),
+        "你好![raw:wide:8142] [raw:wide:81FD]"
+    );
+    assert_eq!(encode_events_with(&events, &ique).unwrap(), raw);
+    let tokens = crate::tokenize_with(&raw, &opts).unwrap().items;
+    let tokens: Vec<_> = tokens.into_iter().map(|tok| tok.inner).collect();
+    assert_eq!(crate::encode_tokens_with(&tokens, &ique).unwrap(), raw);
+    assert_matches!(
+        translate_with(&swap_words(&[0x28, 0xFD, 0x81]), &opts),
+        Err(crate::DecodeError::TruncatedChar { offset: 0, .. })
+    );
+    // Without a glyph table, two byte codes are still two byte codes
+    let opts = DecodeOptions {
+        dialect: Some(std::sync::Arc::new(Ique::default())),
+        ..Default::default()
+    };
+    assert_eq!(
+        crate::to_string_with(&raw, &opts).unwrap(),
+        "[raw:wide:8140][raw:wide:8141]![raw:wide:8142] [raw:wide:81FD]"
+    );
+    let glyphs = GlyphTable::parse_tbl("8140=你\nFD40=好\n").unwrap();
+    assert_eq!(
+        Ique::with_glyphs(glyphs).unwrap_err(),
+        LeadByteError { code: 0xFD40 }
+    );
+    // Other lead bytes, where 81 is a single byte code again
+    let glyphs = GlyphTable::parse_tbl("81=A\n9040=你\n").unwrap();
+    assert_eq!(
+        Ique::with_glyphs(glyphs.clone()).unwrap_err(),
+        LeadByteError { code: 0x9040 }
+    );
+    let ique = Ique::with_lead_bytes(glyphs, 0x90..=0x9F).unwrap();
+    let opts = DecodeOptions {
+        dialect: Some(std::sync::Arc::new(ique)),
+        ..Default::default()
+    };
+    let raw = swap_words(&[0x81, 0x90, 0x40, 0xFD]);
+    assert_eq!(crate::to_string_with(&raw, &opts).unwrap(), "A你");
+}
+
+#[test]
+fn test_glyph_modes() {
+    use crate::{encode, markup, GlyphMode};
+    // Small ん, the heart, and the space that isn't a control code
+    let raw = swap_words(&[0x2D, 0xC4, 0xBD, 0xF8, 0xFD]);
+    let decode = |glyphs| {
+        let opts = DecodeOptions {
+            glyphs,
+            ..Default::default()
+        };
+        let events: Vec<Event> = translate_with(&raw, &opts)
+            .unwrap()
+            .items
+            .into_iter()
+            .map(|ev| ev.inner)
+            .collect();
+        markup::print(&events)
+    };
+    let approximate = decode(GlyphMode::Approximate);
+    assert_eq!(approximate, "んん\u{2764}[raw:kana:F8][end]");
+    assert_ne!(encode(&approximate).unwrap(), raw);
+    let pua = decode(GlyphMode::PrivateUse);
+    assert_eq!(pua, "ん\u{E0C4}\u{E0BD}\u{E0F8}[end]");
+    assert_eq!(encode(&pua).unwrap(), raw);
+    let tagged = decode(GlyphMode::Tagged);
+    assert_eq!(tagged, "ん[raw:kana:C4][raw:kana:BD][raw:kana:F8][end]");
+    assert_eq!(encode(&tagged).unwrap(), raw);
+    assert_eq!(
+        font_mapping(MarioStory::builtin()),
+        include_str!("../tables/mario_story_pua.txt")
+    );
+}
@@ -0,0 +1,316 @@
+use crate::{
+    dialect::ext_param_count, from_private_use, markup, swap_words, CharCode, Control, Dialect,
+    Event, LookupTable, MarioStory, ParseError, ReverseError, Style, Token, BUFFER_SIZE,
+};
+
+#[cfg(test)]
+use std::assert_matches::assert_matches;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum EncodeError {
+    /// No lookup table contains this character
+    Unencodable { ch: char },
+    /// Several codes decode to this character, and the dialect has no rule for which to use
+    Ambiguous { ch: char, codes: Vec<CharCode> },
+    /// An [`Event::ExtCmdError`] can't be encoded, because its arguments were lost
+    ExtCmdError { id: u8 },
+    /// The markup couldn't be parsed
+    Markup(ParseError),
+    /// The encoded message doesn't fit into the immediate buffer
+    BufferOverflow { len: usize },
+    /// The dialect has no byte for this control code
+    Unsupported { control: Control },
+    /// An unknown code or command id that would decode as something else
+    InvalidCode { code: u8 },
+    /// The style takes a different number of parameters in this dialect
+    StyleParams { style: Style, expected: u8 },
+    /// The ext command takes a different number of parameters in this dialect
+    ExtParams { id: u8, expected: usize },
+}
+
+impl std::fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unencodable { ch } => write!(f, "character {ch:?} can't be encoded"),
+            Self::Ambiguous { ch, codes } => {
+                write!(
+                    f,
+                    "character {ch:?} is ambiguous, it could be any of {codes:?}"
+                )
+            }
+            Self::ExtCmdError { id } => write!(f, "erroneous ext command 0x{id:02X}"),
+            Self::Markup(e) => write!(f, "markup error: {e}"),
+            Self::BufferOverflow { len } => {
+                write!(f, "message is {len} bytes, but the buffer is {BUFFER_SIZE}")
+            }
+            Self::Unsupported { control } => write!(f, "{control:?} is not supported"),
+            Self::InvalidCode { code } => {
+                write!(f, "code 0x{code:02X} would decode as something else")
+            }
+            Self::StyleParams { style, expected } => {
+                write!(f, "style {style:?} takes {expected} parameters")
+            }
+            Self::ExtParams { id, expected } => {
+                write!(f, "ext command 0x{id:02X} takes {expected} parameters")
+            }
+        }
+    }
+}
+
+impl std::error::Error for EncodeError {}
+
+impl EncodeError {
+    pub(crate) fn from_reverse(ch: char, err: ReverseError) -> Self {
+        match err {
+            ReverseError::Missing => Self::Unencodable { ch },
+            ReverseError::Ambiguous { codes } => Self::Ambiguous { ch, codes },
+        }
+    }
+}
+
+impl From<ParseError> for EncodeError {
+    fn from(e: ParseError) -> Self {
+        Self::Markup(e)
+    }
+}
+
+/// Encode marked up text (see the [`markup`] module) into script bytes.
+///
+/// A terminator is appended if the text doesn't end with `[end]`.
+pub fn encode(text: &str) -> Result<Vec<u8>, EncodeError> {
+    encode_with(text, MarioStory::builtin())
+}
+
+/// Like [`encode`], but for another dialect
+pub fn encode_with(text: &str, dialect: &dyn Dialect) -> Result<Vec<u8>, EncodeError> {
+    let mut events = markup::parse(text)?;
+    if events.last() != Some(&Event::End) {
+        events.push(Event::End);
+    }
+    encode_events_with(&events, dialect)
+}
+
+/// Encode events into script bytes. This is the inverse of [`crate::translate`].
+pub fn encode_events(events: &[Event]) -> Result<Vec<u8>, EncodeError> {
+    encode_events_with(events, MarioStory::builtin())
+}
+
+/// Like [`encode_events`], but for another dialect
+pub fn encode_events_with(events: &[Event], dialect: &dyn Dialect) -> Result<Vec<u8>, EncodeError> {
+    let mut enc = Encoder::new(dialect);
+    for event in events {
+        enc.event(event)?;
+    }
+    Ok(swap_words(&enc.out))
+}
+
+/// Encode tokens into script bytes. This is the inverse of [`crate::tokenize`].
+pub fn encode_tokens(tokens: &[Token]) -> Result<Vec<u8>, EncodeError> {
+    encode_tokens_with(tokens, MarioStory::builtin())
+}
+
+/// Like [`encode_tokens`], but for another dialect
+pub fn encode_tokens_with(tokens: &[Token], dialect: &dyn Dialect) -> Result<Vec<u8>, EncodeError> {
+    let mut enc = Encoder::new(dialect);
+    for token in tokens {
+        match token {
+            Token::TableSwitch(table) => {
+                // Emit unconditionally, redundant switches are part of the input too
+                enc.lookup_table = *table;
+                enc.control(Control::TableSwitch(*table))?;
+            }
+            Token::Char { table, code, .. } => {
+                enc.switch_table(*table)?;
+                enc.out.push(*code);
+            }
+            Token::WideChar { code, .. } => enc.out.extend(code.to_be_bytes()),
+            Token::Event(event) => enc.event(event)?,
+        }
+    }
+    Ok(swap_words(&enc.out))
+}
+
+struct Encoder<'a> {
+    /// Output in stream order (not word swapped yet)
+    out: Vec<u8>,
+    /// The lookup table the decoder will be in at this point
+    lookup_table: LookupTable,
+    dialect: &'a dyn Dialect,
+}
+
+impl<'a> Encoder<'a> {
+    fn new(dialect: &'a dyn Dialect) -> Self {
+        Self {
+            out: Vec::new(),
+            lookup_table: dialect.initial_table(),
+            dialect,
+        }
+    }
+    fn event(&mut self, event: &Event) -> Result<(), EncodeError> {
+        match event {
+            Event::StyleChange(style) => self.style(*style, &[])?,
+            Event::StyleWithParams { style, params } => self.style(*style, params)?,
+            Event::Space => self.control(Control::Space)?,
+            Event::FullSpace => self.control(Control::FullSpace)?,
+            Event::HalfSpace => self.control(Control::HalfSpace)?,
+            Event::SaveColor => self.control(Control::SaveColor)?,
+            Event::Dialog(text) => {
+                for ch in text.chars() {
+                    if ch == self.dialect.space() {
+                        self.control(Control::Space)?;
+                        continue;
+                    }
+                    if let Some((table, code)) = from_private_use(ch) {
+                        // Only the codes font_mapping lists, anything else would decode as
+                        // a control code, a two byte code or another character
+                        if self.dialect.control(code).is_some()
+                            || self.dialect.is_lead_byte(code)
+                            || !self.dialect.is_approximate(table, code)
+                        {
+                            return Err(EncodeError::Unencodable { ch });
+                        }
+                        self.switch_table(table)?;
+                        self.out.push(code);
+                        continue;
+                    }
+                    match self
+                        .dialect
+                        .reverse(ch)
+                        .map_err(|e| EncodeError::from_reverse(ch, e))?
+                    {
+                        CharCode::Single(table, code) => {
+                            self.switch_table(table)?;
+                            self.out.push(code);
+                        }
+                        CharCode::Wide(code) => self.out.extend(code.to_be_bytes()),
+                    }
+                }
+            }
+            Event::End => self.control(Control::End)?,
+            Event::Linebreak => self.control(Control::Linebreak)?,
+            Event::Delay(amount) => {
+                self.control(Control::Delay)?;
+                self.out.push(*amount);
+            }
+            Event::Bell => self.control(Control::Bell)?,
+            Event::NextBubble => self.control(Control::NextBubble)?,
+            Event::Sparkly => self.control(Control::Sparkly)?,
+            Event::ButtonRef { rawcode, .. } => {
+                self.switch_table(LookupTable::Button)?;
+                self.out.push(*rawcode);
+            }
+            Event::ExtCmd(cmd) => {
+                let (id, args) = (cmd.id(), cmd.args());
+                match ext_param_count(self.dialect, id, &args) {
+                    Some(expected) if expected != args.len() => {
+                        return Err(EncodeError::ExtParams { id, expected })
+                    }
+                    _ => {}
+                }
+                self.control(Control::ExtCmd)?;
+                self.out.push(id);
+                self.out.extend(args);
+            }
+            Event::RawChar { table, code } => {
+                self.switch_table(*table)?;
+                self.out.push(*code);
+            }
+            Event::RawWideChar { code } => self.out.extend(code.to_be_bytes()),
+            Event::Unverified { table, code, .. } => {
+                self.switch_table(*table)?;
+                self.out.push(*code);
+            }
+            Event::ExtCmdError { id, .. } => return Err(EncodeError::ExtCmdError { id: *id }),
+            Event::Error { raw, .. } => self.out.extend(raw),
+        }
+        Ok(())
+    }
+    fn control(&mut self, control: Control) -> Result<(), EncodeError> {
+        let code = self
+            .dialect
+            .control_code(control)
+            .ok_or(EncodeError::Unsupported { control })?;
+        self.out.push(code);
+        Ok(())
+    }
+    fn style(&mut self, style: Style, params: &[u8]) -> Result<(), EncodeError> {
+        let expected = self.dialect.style_params(style);
+        if params.len() != usize::from(expected) {
+            return Err(EncodeError::StyleParams { style, expected });
+        }
+        self.control(Control::Style)?;
+        self.out.push(style as u8);
+        self.out.extend(params);
+        Ok(())
+    }
+    fn switch_table(&mut self, table: LookupTable) -> Result<(), EncodeError> {
+        if self.lookup_table == table {
+            return Ok(());
+        }
+        self.control(Control::TableSwitch(table))?;
+        self.lookup_table = table;
+        Ok(())
+    }
+}
+
+pub(crate) fn table_switch_code(table: LookupTable) -> u8 {
+    match table {
+        LookupTable::Kana => 0xF3,
+        LookupTable::Latin => 0xF4,
+        LookupTable::Kanji => 0xF5,
+        LookupTable::Button => 0xF6,
+    }
+}
+
+#[test]
+fn test_encode_roundtrip() {
+    let text = "[style:BubbleRight]ＡＢあ上\u{3000}[A]い[delay:5]\n[ext:0x05:2][next]";
+    let bytes = encode(text).unwrap();
+    assert_eq!(
+        swap_words(&bytes),
+        [
+            0xFC, 0x01, 0xF4, 0x00, 0x01, 0xF3, 0x00, 0xF5, 0x00, 0xF7, 0xF6, 0x00, 0xF3, 0x01,
+            0xF2, 0x05, 0xF0, 0xFF, 0x05, 0x02, 0xFB, 0xFD
+        ]
+    );
+    let mut events = markup::parse(text).unwrap();
+    events.push(Event::End);
+    assert_eq!(crate::translate(&bytes).unwrap(), events);
+}
+
+#[test]
+fn test_encode_unencodable() {
+    assert_eq!(encode("a"), Err(EncodeError::Unencodable { ch: 'a' }));
+    // Private use characters of the terminator, the ext command and a plain あ
+    for ch in ['\u{E0FD}', '\u{E0FF}', '\u{E000}'] {
+        assert_eq!(
+            encode(&format!("あ{ch}い")),
+            Err(EncodeError::Unencodable { ch })
+        );
+    }
+    assert_eq!(swap_words(&encode("\u{E0C4}").unwrap()), [0xC4, 0xFD]);
+}
+
+#[test]
+fn test_tokens_roundtrip() {
+    // Redundant table switches, ambiguous and unknown codes, and an odd length
+    let raw = swap_words(&[
+        0xFC, 0x02, 0xF3, 0xF3, 0x2D, 0xC4, 0x89, 0x7E, 0xF5, 0xE0, 0xF8, 0xF6, 0x03, 0xF7, 0xFF,
+        0x18, 1, 2, 3, 4, 5, 6, 7, 0xFF, 0x77, 0xFD, 0x00,
+    ]);
+    let tokens = crate::tokenize(&raw).unwrap();
+    assert_eq!(encode_tokens(&tokens).unwrap(), raw);
+    assert!(crate::tokenize(&swap_words(&[0x00, 0xFF, 0x18, 1])).is_err());
+}
+
+#[test]
+fn test_encode_ambiguous() {
+    let mut charset = crate::Charset::default();
+    charset.preferred.remove(&'ン');
+    let dialect = MarioStory::with_charset(charset);
+    assert_matches!(
+        encode_events_with(&[Event::Dialog("ン".into())], &dialect),
+        Err(EncodeError::Ambiguous { ch: 'ン', .. })
+    );
+    assert_eq!(swap_words(&encode("ン").unwrap()), [0x7E, 0xFD]);
+}
@@ -6,12 +6,15 @@ macro_rules! def {
                 _ => None
             }
         }
-        #[derive(Debug)]
+        #[derive(Debug, Clone, PartialEq, Eq)]
         pub enum ExtCmd {
             $(
                 $name{$($param: u8),*},
             )*
             Unknown(UnkCmd),
+            /// A command of the dialect that has no name here, or that takes a different
+            /// number of parameters than the named command with its id
+            Other { id: u8, args: Vec<u8> },
         }
         impl ExtCmd {
             pub fn from_id_and_args(id: u8, args: &[u8]) -> Option<Self> {
@@ -20,10 +23,40 @@ macro_rules! def {
                     _ => return None
                 })
             }
+            /// The command id byte that follows `0xFF`
+            pub fn id(&self) -> u8 {
+                match self {
+                    $(Self::$name{..} => $id,)*
+                    Self::Unknown(UnkCmd(id)) | Self::Other { id, .. } => *id,
+                }
+            }
+            /// The parameter bytes, in stream order
+            pub fn args(&self) -> Vec<u8> {
+                match self {
+                    $(Self::$name{$($param),*} => vec![$(*$param),*],)*
+                    Self::Unknown(_) => Vec::new(),
+                    Self::Other { args, .. } => args.clone(),
+                }
+            }
         }
     };
 }
 
+impl ExtCmd {
+    /// The named command if `args` fit it, [`ExtCmd::Other`] otherwise
+    pub fn new(id: u8, args: &[u8]) -> Self {
+        match n_params(id) {
+            Some(argc) if usize::from(argc) == args.len() => Self::from_id_and_args(id, args),
+            _ => None,
+        }
+        .unwrap_or_else(|| Self::Other {
+            id,
+            args: args.to_vec(),
+        })
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
 pub struct UnkCmd(pub u8);
 
 impl std::fmt::Debug for UnkCmd {
@@ -74,3 +107,24 @@ fn test_from_id_and_args() {
         })
     )
 }
+
+#[test]
+fn test_id_and_args_roundtrip() {
+    let cmd = ExtCmd::from_id_and_args(0x0D, &[3, 4]).unwrap();
+    assert_eq!(cmd.id(), 0x0D);
+    assert_eq!(
+        ExtCmd::from_id_and_args(cmd.id(), &cmd.args()),
+        Some(cmd.clone())
+    );
+    assert_eq!(ExtCmd::Unknown(UnkCmd(0x42)).id(), 0x42);
+    assert_eq!(ExtCmd::new(0x0D, &[3, 4]), cmd);
+    let other = ExtCmd::new(0x26, &[3, 0x80]);
+    assert_eq!(
+        other,
+        ExtCmd::Other {
+            id: 0x26,
+            args: vec![3, 0x80]
+        }
+    );
+    assert_eq!((other.id(), other.args()), (0x26, vec![3, 0x80]));
+}
@@ -0,0 +1,618 @@
+//! The message font graphics, for identifying glyphs the charsets can't map.
+//!
+//! Each lookup table has its own run of glyph tiles, one per code. A tile is a packed
+//! bitmap, rows from top to bottom and the leftmost pixel in the high bits, the same as the
+//! N64 texture formats. The pixels are either intensities (`I4`, `I8`), or indices into a
+//! palette of RGBA5551 colours (`CI4`, `CI8`), which is what the Paper Mario charsets use.
+//! All offsets are into the ROM in stream (z64) order, see
+//! [`Rom::to_order`](crate::rom::Rom::to_order).
+//!
+//! Where the tables and palettes live differs between games and versions, and this crate
+//! doesn't have verified offsets for any of them yet, so a [`FontLayout`] has to be
+//! supplied. [`find`] ranks candidate offsets to help with locating them.
+//!
+//! The game advances the pen by a width per glyph, stored as one byte per code next to the
+//! tiles. When a [`FontTable`] says where those are, [`Font::load`] reads them. Otherwise
+//! the widths are estimated from the ink of the tiles, see [`GlyphWidths::measure`]. No
+//! offsets or width tables are known yet, so widths are estimates unless one is supplied.
+//!
+//! [`extract`] draws every table as a sprite sheet of 16 codes per row, so the code of a
+//! glyph is its row and column in hex. Codes that neither decode to a character nor are
+//! control bytes also get their own image.
+
+#[cfg(test)]
+use crate::PaperMario;
+use {
+    crate::{markup::table_name, CodeTable, Dialect, Image, LookupTable, TblError},
+    std::collections::BTreeMap,
+};
+
+/// Colour of the gutter between the glyphs of a sprite sheet
+const GUTTER: [u8; 4] = [0x30, 0x30, 0x60, 0xFF];
+
+/// Size and pixel format of a glyph tile
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GlyphLayout {
+    pub width: usize,
+    pub height: usize,
+    /// Bits per pixel: 1, 2, 4 or 8 for intensities, 4 or 8 for palette indices
+    pub bpp: usize,
+    /// Offset of the palette for colour indexed tiles, `None` for intensity tiles
+    pub palette: Option<usize>,
+}
+
+impl GlyphLayout {
+    /// Size of one tile in bytes. Rows are padded to whole bytes.
+    pub fn size(&self) -> usize {
+        (self.width * self.bpp).div_ceil(8) * self.height
+    }
+}
+
+/// Where the tiles of one lookup table are
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FontTable {
+    pub table: LookupTable,
+    /// Offset of the tile for code 0
+    pub offset: usize,
+    /// Number of tiles
+    pub count: usize,
+    /// Offset of the advance widths, one byte per code, if known
+    pub widths: Option<usize>,
+}
+
+/// Where a game keeps its message font
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FontLayout {
+    pub glyph: GlyphLayout,
+    pub tables: Vec<FontTable>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FontError {
+    /// The tiles at `offset` extend past the end of the data
+    OutOfBounds {
+        offset: usize,
+        len: usize,
+    },
+    UnsupportedBpp {
+        bpp: usize,
+    },
+    /// A glyph or image without any pixels
+    Empty {
+        width: usize,
+        height: usize,
+    },
+    /// A sprite sheet with no columns
+    NoColumns,
+}
+
+impl std::fmt::Display for FontError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::OutOfBounds { offset, len } => write!(
+                f,
+                "Font data at {offset:#X} ({len:#X} bytes) extends past the end of the data"
+            ),
+            Self::UnsupportedBpp { bpp } => write!(f, "Unsupported glyph depth: {bpp} bpp"),
+            Self::Empty { width, height } => write!(f, "Empty {width}x{height} image"),
+            Self::NoColumns => write!(f, "A sprite sheet needs at least one column"),
+        }
+    }
+}
+
+impl std::error::Error for FontError {}
+
+fn check_layout(glyph: &GlyphLayout) -> Result<(), FontError> {
+    match (glyph.bpp, glyph.palette) {
+        (1 | 2, None) | (4 | 8, _) => {}
+        (bpp, _) => return Err(FontError::UnsupportedBpp { bpp }),
+    }
+    if glyph.width == 0 || glyph.height == 0 {
+        return Err(FontError::Empty {
+            width: glyph.width,
+            height: glyph.height,
+        });
+    }
+    Ok(())
+}
+
+fn tiles<'a>(
+    data: &'a [u8],
+    glyph: &GlyphLayout,
+    offset: usize,
+    count: usize,
+) -> Result<&'a [u8], FontError> {
+    check_layout(glyph)?;
+    let len = glyph.size() * count;
+    offset
+        .checked_add(len)
+        .and_then(|end| data.get(offset..end))
+        .ok_or(FontError::OutOfBounds { offset, len })
+}
+
+/// The colours of the palette of `glyph`, if it has one
+fn palette(data: &[u8], glyph: &GlyphLayout) -> Result<Option<Vec<[u8; 4]>>, FontError> {
+    let Some(offset) = glyph.palette else {
+        return Ok(None);
+    };
+    let len = 2 << glyph.bpp;
+    let raw = offset
+        .checked_add(len)
+        .and_then(|end| data.get(offset..end))
+        .ok_or(FontError::OutOfBounds { offset, len })?;
+    let scale = |v: u16| ((v & 0x1F) * 255 / 31) as u8;
+    let colours = raw
+        .chunks(2)
+        .map(|c| {
+            let v = u16::from_be_bytes([c[0], c[1]]);
+            let alpha = if v & 1 == 1 { 0xFF } else { 0 };
+            [scale(v >> 11), scale(v >> 6), scale(v >> 1), alpha]
+        })
+        .collect();
+    Ok(Some(colours))
+}
+
+/// The raw pixel values of one tile, row by row
+fn pixel_values(tile: &[u8], glyph: &GlyphLayout) -> Vec<u8> {
+    let stride = (glyph.width * glyph.bpp).div_ceil(8);
+    let mask = ((1u16 << glyph.bpp) - 1) as u8;
+    let mut out = Vec::with_capacity(glyph.width * glyph.height);
+    for row in tile.chunks(stride).take(glyph.height) {
+        for x in 0..glyph.width {
+            let bit = x * glyph.bpp;
+            let shift = 8 - glyph.bpp - bit % 8;
+            out.push((row[bit / 8] >> shift) & mask);
+        }
+    }
+    out
+}
+
+/// Colours of one tile, row by row. Intensities are light on black.
+fn colours(tile: &[u8], glyph: &GlyphLayout, palette: Option<&[[u8; 4]]>) -> Vec<[u8; 4]> {
+    let max = (1u16 << glyph.bpp) - 1;
+    pixel_values(tile, glyph)
+        .into_iter()
+        .map(|v| match palette {
+            Some(palette) => palette[usize::from(v)],
+            None => {
+                let v = (u16::from(v) * 255 / max) as u8;
+                [v, v, v, 0xFF]
+            }
+        })
+        .collect()
+}
+
+/// Pixel intensities of one tile, scaled to 0..=255, row by row. A palette colour counts
+/// as its brightest channel, and as blank if it's transparent.
+fn intensities(tile: &[u8], glyph: &GlyphLayout, palette: Option<&[[u8; 4]]>) -> Vec<u8> {
+    colours(tile, glyph, palette)
+        .into_iter()
+        .map(|[r, g, b, a]| if a == 0 { 0 } else { r.max(g).max(b) })
+        .collect()
+}
+
+/// Decode the tile at `offset`. Intensity tiles come out as light pixels on black.
+pub fn glyph(data: &[u8], glyph: &GlyphLayout, offset: usize) -> Result<Image, FontError> {
+    let tile = tiles(data, glyph, offset, 1)?;
+    let palette = palette(data, glyph)?;
+    let mut image = Image::new(glyph.width, glyph.height);
+    for (i, rgba) in colours(tile, glyph, palette.as_deref())
+        .into_iter()
+        .enumerate()
+    {
+        image.set(i % glyph.width, i / glyph.width, rgba);
+    }
+    Ok(image)
+}
+
+/// Draw `count` consecutive tiles starting at `offset`, `columns` to a row, with a 1 pixel
+/// gutter around every glyph
+pub fn sprite_sheet(
+    data: &[u8],
+    layout: &GlyphLayout,
+    offset: usize,
+    count: usize,
+    columns: usize,
+) -> Result<Image, FontError> {
+    tiles(data, layout, offset, count)?;
+    if columns == 0 {
+        return Err(FontError::NoColumns);
+    }
+    let rows = count.div_ceil(columns);
+    let mut sheet = Image::new(
+        columns * (layout.width + 1) + 1,
+        rows * (layout.height + 1) + 1,
+    );
+    sheet.fill(0, 0, sheet.width, sheet.height, GUTTER);
+    for i in 0..count {
+        let tile = glyph(data, layout, offset + i * layout.size())?;
+        sheet.blit(
+            &tile,
+            i % columns * (layout.width + 1) + 1,
+            i / columns * (layout.height + 1) + 1,
+        );
+    }
+    Ok(sheet)
+}
+
+/// Whether `dialect` has nothing to decode `code` in `table` to
+fn is_unknown(dialect: &dyn Dialect, table: LookupTable, code: u8) -> bool {
+    if dialect.control(code).is_some() || dialect.is_lead_byte(code) {
+        return false;
+    }
+    match table {
+        LookupTable::Button => dialect.button(code).is_none(),
+        _ => dialect.char(table, code).is_none(),
+    }
+}
+
+/// Draw the font as PNG files.
+///
+/// Returns a file name and the PNG data for every file: `sheet_<table>.png` for each table,
+/// and `unknown/<table>_<XX>.png` for every code `dialect` can't map.
+pub fn extract(
+    data: &[u8],
+    layout: &FontLayout,
+    dialect: &dyn Dialect,
+) -> Result<Vec<(String, Vec<u8>)>, FontError> {
+    let mut out = Vec::new();
+    for table in &layout.tables {
+        let name = table_name(table.table);
+        let sheet = sprite_sheet(data, &layout.glyph, table.offset, table.count, 16)?;
+        out.push((format!("sheet_{name}.png"), sheet.to_png()?));
+        for code in 0..table.count.min(0x100) {
+            if !is_unknown(dialect, table.table, code as u8) {
+                continue;
+            }
+            let offset = table.offset + code * layout.glyph.size();
+            let image = glyph(data, &layout.glyph, offset)?;
+            out.push((format!("unknown/{name}_{code:02X}.png"), image.to_png()?));
+        }
+    }
+    Ok(out)
+}
+
+/// How far the pen moves after every glyph, in pixels at the default font size.
+///
+/// **These are not the game's widths unless they were loaded.** No width table ships with
+/// this crate and no [`FontTable::widths`] offset is known for any game yet, so without a
+/// `.tbl` from [`GlyphWidths::load_tbl`] the widths are estimates from
+/// [`GlyphWidths::measure`], and line widths built on them are not pixel accurate.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GlyphWidths {
+    pub kana: CodeTable<u8>,
+    pub kanji: CodeTable<u8>,
+    pub latin: CodeTable<u8>,
+    pub button: CodeTable<u8>,
+    /// Width of codes without an entry
+    pub default: u8,
+    /// Width of a space
+    pub space: u8,
+    /// Width of a full-width space, a whole cell
+    pub full_space: u8,
+    /// Width of a half-width space, half a cell
+    pub half_space: u8,
+    /// Distance between tab stops. Where the game puts them hasn't been checked.
+    pub tab: usize,
+}
+
+impl GlyphWidths {
+    /// Every glyph and space `width` pixels wide, and half spaces half that
+    pub fn monospace(width: u8) -> Self {
+        Self {
+            kana: CodeTable::new(),
+            kanji: CodeTable::new(),
+            latin: CodeTable::new(),
+            button: CodeTable::new(),
+            default: width,
+            space: width,
+            full_space: width,
+            half_space: width / 2,
+            tab: 4 * usize::from(width),
+        }
+    }
+    fn table(&self, table: LookupTable) -> &CodeTable<u8> {
+        match table {
+            LookupTable::Kana => &self.kana,
+            LookupTable::Kanji => &self.kanji,
+            LookupTable::Latin => &self.latin,
+            LookupTable::Button => &self.button,
+        }
+    }
+    fn table_mut(&mut self, table: LookupTable) -> &mut CodeTable<u8> {
+        match table {
+            LookupTable::Kana => &mut self.kana,
+            LookupTable::Kanji => &mut self.kanji,
+            LookupTable::Latin => &mut self.latin,
+            LookupTable::Button => &mut self.button,
+        }
+    }
+    pub fn get(&self, table: LookupTable, code: u8) -> u8 {
+        self.table(table).get(code).unwrap_or(self.default)
+    }
+    /// Estimate the widths of the glyphs of `font`, for fonts without a width table.
+    ///
+    /// A glyph is as wide as its inked columns. The game adds its own spacing, which isn't
+    /// known without the width table, so these are too narrow by that much. Blank glyphs
+    /// and spaces are a whole tile wide, and half spaces half of one.
+    pub fn measure(font: &Font) -> Self {
+        let (w, h) = (font.glyph.width, font.glyph.height);
+        let mut widths = Self::monospace(w.min(255) as u8);
+        for (&(table, code), tile) in &font.tiles {
+            let inked = (0..w).rev().find(|&x| (0..h).any(|y| tile[y * w + x] != 0));
+            if let Some(x) = inked {
+                let width = (x + 1).min(255) as u8;
+                widths.table_mut(table).set(code, Some(width));
+            }
+        }
+        widths
+    }
+    /// Replace the widths of one table with the contents of a `.tbl` file, one decimal
+    /// width per code like `2D=12`
+    pub fn load_tbl(&mut self, table: LookupTable, text: &str) -> Result<(), TblError> {
+        *self.table_mut(table) = CodeTable::parse_tbl(text)?;
+        Ok(())
+    }
+    /// Write the widths of one table in `.tbl` format
+    pub fn to_tbl(&self, table: LookupTable) -> String {
+        self.table(table).to_tbl()
+    }
+}
+
+/// The decoded tiles of a font, for drawing text with
+#[derive(Debug, Clone)]
+pub struct Font {
+    pub glyph: GlyphLayout,
+    /// Glyph widths, read or estimated by [`Font::load`]
+    pub widths: GlyphWidths,
+    /// Pixel intensities of every tile, scaled to 0..=255
+    tiles: BTreeMap<(LookupTable, u8), Vec<u8>>,
+}
+
+impl Font {
+    /// Decode the tiles of every table in `layout`, and the widths of the tables that have
+    /// them. The other widths are estimated with [`GlyphWidths::measure`].
+    pub fn load(data: &[u8], layout: &FontLayout) -> Result<Self, FontError> {
+        let mut tiles = BTreeMap::new();
+        check_layout(&layout.glyph)?;
+        let palette = palette(data, &layout.glyph)?;
+        for table in &layout.tables {
+            let count = table.count.min(0x100);
+            let all = self::tiles(data, &layout.glyph, table.offset, count)?;
+            for (code, tile) in all.chunks(layout.glyph.size()).enumerate() {
+                let pixels = intensities(tile, &layout.glyph, palette.as_deref());
+                tiles.insert((table.table, code as u8), pixels);
+            }
+        }
+        let mut font = Self {
+            glyph: layout.glyph,
+            widths: GlyphWidths::monospace(0),
+            tiles,
+        };
+        font.widths = GlyphWidths::measure(&font);
+        for table in &layout.tables {
+            let Some(offset) = table.widths else {
+                continue;
+            };
+            let count = table.count.min(0x100);
+            let widths = offset
+                .checked_add(count)
+                .and_then(|end| data.get(offset..end))
+                .ok_or(FontError::OutOfBounds { offset, len: count })?;
+            let mut codes = CodeTable::new();
+            for (code, &width) in widths.iter().enumerate() {
+                codes.set(code as u8, Some(width));
+            }
+            *font.widths.table_mut(table.table) = codes;
+        }
+        Ok(font)
+    }
+    /// Pixel intensities of the glyph for `code` in `table`, row by row
+    pub fn tile(&self, table: LookupTable, code: u8) -> Option<&[u8]> {
+        self.tiles.get(&(table, code)).map(Vec::as_slice)
+    }
+}
+
+/// How much the `count` tiles at `offset` look like font glyphs, from 0 to 1.
+///
+/// Glyphs are mostly blank with some ink, and leave their last column and row blank as
+/// spacing. This is a heuristic, so check the best candidates by looking at them.
+pub fn score(
+    data: &[u8],
+    glyph: &GlyphLayout,
+    offset: usize,
+    count: usize,
+) -> Result<f32, FontError> {
+    let tiles = tiles(data, glyph, offset, count)?;
+    let palette = palette(data, glyph)?;
+    if count == 0 {
+        return Ok(0.0);
+    }
+    let (w, h) = (glyph.width, glyph.height);
+    let plausible = tiles
+        .chunks(glyph.size())
+        .filter(|tile| {
+            let pixels = intensities(tile, glyph, palette.as_deref());
+            let ink = pixels.iter().filter(|&&v| v != 0).count();
+            let spaced = (0..h).all(|y| pixels[y * w + w - 1] == 0)
+                && pixels[(h - 1) * w..].iter().all(|&v| v == 0);
+            spaced && ink > 0 && ink <= w * h / 2
+        })
+        .count();
+    Ok(plausible as f32 / count as f32)
+}
+
+/// Rank the offsets of `data` by how much the `count` tiles there look like font glyphs,
+/// see [`score`]. Returns up to `max` offsets, best first.
+///
+/// Only offsets aligned to the tile size are tried, since fonts are usually aligned at
+/// least that much.
+pub fn find(data: &[u8], glyph: &GlyphLayout, count: usize, max: usize) -> Vec<(usize, f32)> {
+    if check_layout(glyph).is_err() || palette(data, glyph).is_err() {
+        return Vec::new();
+    }
+    let end = data.len().saturating_sub(glyph.size() * count);
+    let mut candidates: Vec<(usize, f32)> = (0..=end)
+        .step_by(glyph.size())
+        .filter_map(|offset| Some((offset, score(data, glyph, offset, count).ok()?)))
+        .filter(|&(_, score)| score > 0.0)
+        .collect();
+    candidates.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+    candidates.truncate(max);
+    candidates
+}
+
+#[cfg(test)]
+const TEST_GLYPH: GlyphLayout = GlyphLayout {
+    width: 4,
+    height: 3,
+    bpp: 4,
+    palette: None,
+};
+
+#[cfg(test)]
+fn test_font() -> Vec<u8> {
+    let mut data = vec![0xEE; 6];
+    // Kana 00: a diagonal line
+    data.extend([0xF0, 0x00, 0x08, 0x00, 0x00, 0x00]);
+    // Kana 01: a dot
+    data.extend([0x00, 0x00, 0x0F, 0x00, 0x00, 0x00]);
+    // Kana 02: blank
+    data.extend([0x00; 6]);
+    data
+}
+
+#[test]
+fn test_glyph() {
+    let data = test_font();
+    let image = glyph(&data, &TEST_GLYPH, 6).unwrap();
+    assert_eq!(image.get(0, 0), [0xFF, 0xFF, 0xFF, 0xFF]);
+    assert_eq!(image.get(1, 0), [0, 0, 0, 0xFF]);
+    assert_eq!(image.get(1, 1), [0x88, 0x88, 0x88, 0xFF]);
+    assert_eq!(
+        glyph(&data, &TEST_GLYPH, 20),
+        Err(FontError::OutOfBounds { offset: 20, len: 6 })
+    );
+    let sheet = sprite_sheet(&data, &TEST_GLYPH, 6, 3, 2).unwrap();
+    assert_eq!((sheet.width, sheet.height), (11, 9));
+    assert_eq!(sheet.get(0, 0), GUTTER);
+    assert_eq!(sheet.get(1, 1), [0xFF, 0xFF, 0xFF, 0xFF]);
+    assert_eq!(sheet.get(7, 2), [0xFF, 0xFF, 0xFF, 0xFF]);
+    assert_eq!(
+        find(&data, &TEST_GLYPH, 2, 3),
+        [(6, 1.0), (0, 0.5), (12, 0.5)]
+    );
+    assert_eq!(
+        sprite_sheet(&data, &TEST_GLYPH, 6, 3, 0),
+        Err(FontError::NoColumns)
+    );
+    let empty = GlyphLayout {
+        width: 0,
+        ..TEST_GLYPH
+    };
+    assert_eq!(
+        score(&data, &empty, 0, 2),
+        Err(FontError::Empty {
+            width: 0,
+            height: 3
+        })
+    );
+    assert_eq!(find(&data, &empty, 2, 3), []);
+}
+
+#[test]
+fn test_ci4() {
+    // Transparent, then opaque red and a dim opaque grey, in RGBA5551
+    let mut data = [0x00, 0x00, 0xF8, 0x01, 0x42, 0x11].to_vec();
+    data.resize(32, 0);
+    // Kana 00: red in the top left corner, grey next to it
+    data.extend([0x12, 0x00, 0x00, 0x00, 0x00, 0x00]);
+    let layout = GlyphLayout {
+        palette: Some(0),
+        ..TEST_GLYPH
+    };
+    let image = glyph(&data, &layout, 32).unwrap();
+    assert_eq!(image.get(0, 0), [0xFF, 0, 0, 0xFF]);
+    assert_eq!(image.get(1, 0), [0x41, 0x41, 0x41, 0xFF]);
+    assert_eq!(image.get(2, 0), [0, 0, 0, 0]);
+    let font = Font::load(
+        &data,
+        &FontLayout {
+            glyph: layout,
+            tables: vec![FontTable {
+                table: LookupTable::Kana,
+                offset: 32,
+                count: 1,
+                widths: None,
+            }],
+        },
+    )
+    .unwrap();
+    assert_eq!(
+        font.tile(LookupTable::Kana, 0).unwrap(),
+        [0xFF, 0x41, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]
+    );
+    assert_eq!(font.widths.get(LookupTable::Kana, 0), 2);
+    // The width table wins over the estimate
+    let mut tables = vec![FontTable {
+        table: LookupTable::Kana,
+        offset: 32,
+        count: 1,
+        widths: Some(38),
+    }];
+    let with_widths = |data: &[u8], tables: &[FontTable]| {
+        let layout = FontLayout {
+            glyph: layout,
+            tables: tables.to_vec(),
+        };
+        Font::load(data, &layout).map(|font| font.widths.get(LookupTable::Kana, 0))
+    };
+    data.push(7);
+    assert_eq!(with_widths(&data, &tables), Ok(7));
+    tables[0].widths = Some(39);
+    assert_eq!(
+        with_widths(&data, &tables),
+        Err(FontError::OutOfBounds { offset: 39, len: 1 })
+    );
+    assert_eq!(score(&data, &layout, 32, 1), Ok(1.0));
+    assert_eq!(
+        glyph(&data[..20], &layout, 0),
+        Err(FontError::OutOfBounds { offset: 0, len: 32 })
+    );
+    assert_eq!(
+        glyph(&data, &GlyphLayout { bpp: 2, ..layout }, 32),
+        Err(FontError::UnsupportedBpp { bpp: 2 })
+    );
+}
+
+#[test]
+fn test_extract() {
+    let mut latin = CodeTable::new();
+    latin.set(0x00, Some('A'));
+    let layout = FontLayout {
+        glyph: TEST_GLYPH,
+        tables: vec![FontTable {
+            table: LookupTable::Latin,
+            offset: 6,
+            count: 3,
+            widths: None,
+        }],
+    };
+    let files = extract(&test_font(), &layout, &PaperMario::with_table(latin)).unwrap();
+    let names: Vec<&str> = files.iter().map(|(name, _)| name.as_str()).collect();
+    assert_eq!(
+        names,
+        [
+            "sheet_latin.png",
+            "unknown/latin_01.png",
+            "unknown/latin_02.png"
+        ]
+    );
+    assert_eq!(
+        files[1].1,
+        glyph(&test_font(), &TEST_GLYPH, 12)
+            .unwrap()
+            .to_png()
+            .unwrap()
+    );
+}
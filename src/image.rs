@@ -0,0 +1,142 @@
+use crate::{font::FontError, patch::crc32};
+
+/// An RGBA image, 8 bits per channel
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    /// Rows from top to bottom, 4 bytes per pixel
+    pub pixels: Vec<u8>,
+}
+
+impl Image {
+    /// A transparent image
+    pub fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            pixels: vec![0; width * height * 4],
+        }
+    }
+    pub fn get(&self, x: usize, y: usize) -> [u8; 4] {
+        let i = (y * self.width + x) * 4;
+        self.pixels[i..i + 4].try_into().unwrap()
+    }
+    /// Set a pixel. Pixels outside the image are ignored.
+    pub fn set(&mut self, x: usize, y: usize, rgba: [u8; 4]) {
+        if x >= self.width || y >= self.height {
+            return;
+        }
+        let i = (y * self.width + x) * 4;
+        self.pixels[i..i + 4].copy_from_slice(&rgba);
+    }
+    /// Fill a rectangle, clipped to the image
+    pub fn fill(&mut self, x: usize, y: usize, width: usize, height: usize, rgba: [u8; 4]) {
+        for y in y..(y + height).min(self.height) {
+            for x in x..(x + width).min(self.width) {
+                self.set(x, y, rgba);
+            }
+        }
+    }
+    /// Copy `other` into this image with its top left corner at `x`, `y`
+    pub fn blit(&mut self, other: &Image, x: usize, y: usize) {
+        for oy in 0..other.height {
+            for ox in 0..other.width {
+                self.set(x + ox, y + oy, other.get(ox, oy));
+            }
+        }
+    }
+    /// Encode as a PNG file. PNG has no empty images, so both sides have to be at least 1.
+    ///
+    /// The image data is stored uncompressed, which keeps the encoder small. Compress the
+    /// files with an external tool if size matters.
+    pub fn to_png(&self) -> Result<Vec<u8>, FontError> {
+        if self.width == 0 || self.height == 0 {
+            return Err(FontError::Empty {
+                width: self.width,
+                height: self.height,
+            });
+        }
+        let mut raw = Vec::with_capacity((self.width * 4 + 1) * self.height);
+        for row in self.pixels.chunks(self.width * 4).take(self.height) {
+            // Filter type: none
+            raw.push(0);
+            raw.extend_from_slice(row);
+        }
+        let mut ihdr = Vec::new();
+        ihdr.extend((self.width as u32).to_be_bytes());
+        ihdr.extend((self.height as u32).to_be_bytes());
+        // 8 bits per channel, RGBA, default compression, filtering and no interlacing
+        ihdr.extend([8, 6, 0, 0, 0]);
+        let mut out = b"\x89PNG\r\n\x1a\n".to_vec();
+        write_chunk(&mut out, b"IHDR", &ihdr);
+        write_chunk(&mut out, b"IDAT", &zlib_stored(&raw));
+        write_chunk(&mut out, b"IEND", &[]);
+        Ok(out)
+    }
+}
+
+fn write_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend((data.len() as u32).to_be_bytes());
+    let start = out.len();
+    out.extend(kind);
+    out.extend(data);
+    let crc = crc32(&out[start..]);
+    out.extend(crc.to_be_bytes());
+}
+
+/// A zlib stream of uncompressed deflate blocks
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    // Deflate, 32K window, no preset dictionary, fastest compression
+    let mut out = vec![0x78, 0x01];
+    let mut blocks = data.chunks(0xFFFF).peekable();
+    if blocks.peek().is_none() {
+        out.extend([1, 0, 0, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        out.push(u8::from(blocks.peek().is_none()));
+        let len = block.len() as u16;
+        out.extend(len.to_le_bytes());
+        out.extend((!len).to_le_bytes());
+        out.extend(block);
+    }
+    out.extend(adler32(data).to_be_bytes());
+    out
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (a, b) = data.iter().fold((1u32, 0u32), |(a, b), &byte| {
+        let a = (a + u32::from(byte)) % 65521;
+        (a, (b + a) % 65521)
+    });
+    b << 16 | a
+}
+
+#[test]
+fn test_png() {
+    assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+    let mut image = Image::new(3, 2);
+    image.set(1, 1, [1, 2, 3, 4]);
+    image.set(5, 5, [1, 2, 3, 4]);
+    assert_eq!(image.get(1, 1), [1, 2, 3, 4]);
+    let png = image.to_png().unwrap();
+    assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+    assert_eq!(&png[12..16], b"IHDR");
+    assert_eq!(&png[16..24], [0, 0, 0, 3, 0, 0, 0, 2]);
+    assert_eq!(&png[png.len() - 8..png.len() - 4], b"IEND");
+    // The IDAT payload is the zlib header, one stored block and the checksum
+    let idat_len = u32::from_be_bytes(png[33..37].try_into().unwrap()) as usize;
+    assert_eq!(&png[37..41], b"IDAT");
+    assert_eq!(idat_len, 2 + 5 + 2 * (3 * 4 + 1) + 4);
+    assert_eq!(png[41 + 2 + 5 + 13 + 5..41 + 2 + 5 + 13 + 9], [1, 2, 3, 4]);
+    assert_eq!(
+        Image::new(0, 2).to_png(),
+        Err(FontError::Empty {
+            width: 0,
+            height: 2
+        })
+    );
+    // Big images need several stored blocks
+    let big = zlib_stored(&[7; 0x20000]);
+    assert_eq!(big.len(), 2 + 3 * 5 + 0x20000 + 4);
+}
@@ -1,6 +1,12 @@
-use crate::{charsets::Button, LookupTable};
+use crate::{
+    charsets::Button, swap_words, CharCode, DecodeOptions, Decoded, Dialect, EncodeError,
+    LookupTable, MarioStory, Span, Spanned, Strictness, BUFFER_SIZE,
+};
 
-#[derive(Debug)]
+#[cfg(test)]
+use std::assert_matches::assert_matches;
+
+#[derive(Debug, Clone, PartialEq)]
 #[allow(dead_code)]
 pub enum Event {
     BubbleStyle(u8),
@@ -42,48 +48,192 @@ pub enum Event {
     ExtTextHoffset(u8),
     ExtCmdUnk14(u8),
     ExtCmdUnk15(u8),
+    /// Lookup table switch (lossless mode only)
+    TableSwitch(LookupTable),
+    /// Character code with what it decodes to (lossless mode only)
+    Code {
+        table: LookupTable,
+        code: u8,
+        ch: Option<char>,
+    },
+    /// Terminator (lossless mode only)
+    End,
+    /// Bytes after the terminator (lossless mode only)
+    Trailing(Vec<u8>),
+    /// Malformed input (lenient mode only)
+    Error(DecodeError),
+}
+
+/// Error decoding an immediate buffer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    /// Buffer ends in the middle of the command starting at `offset`
+    Truncated { offset: usize },
+    /// Buffer has no `0xFB` terminator
+    MissingTerminator { offset: usize },
 }
 
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Truncated { offset } => write!(f, "truncated command at offset 0x{offset:X}"),
+            Self::MissingTerminator { offset } => {
+                write!(f, "missing terminator at offset 0x{offset:X}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
 type Iter<'a> = &'a mut (dyn Iterator<Item = u8> + 'a);
 
 pub struct Decoder<'a> {
     events: Vec<Event>,
     lookup_table: LookupTable,
     iter: Iter<'a>,
+    /// Stream position of the next byte
+    pos: usize,
+    lossless: bool,
+    terminated: bool,
+    dialect: &'a dyn Dialect,
 }
 
 impl<'a> Decoder<'a> {
-    fn new(iter: Iter<'a>) -> Self {
+    fn new(iter: Iter<'a>, lossless: bool, dialect: &'a dyn Dialect) -> Self {
         Self {
             events: Vec::new(),
-            lookup_table: LookupTable::Kana,
+            lookup_table: dialect.initial_table(),
             iter,
+            pos: 0,
+            lossless,
+            terminated: false,
+            dialect,
         }
     }
+    fn byte(&mut self) -> Option<u8> {
+        let b = self.iter.next()?;
+        self.pos += 1;
+        Some(b)
+    }
 }
 
 pub fn decode_events(raw: &[u8]) -> Vec<Event> {
     let mut iter = raw.chunks(4).flat_map(move |chk| chk.iter().rev().cloned());
-    let mut decoder = Decoder::new(&mut iter);
+    let mut decoder = Decoder::new(&mut iter, false, MarioStory::builtin());
     while decoder.next().is_some() {}
     decoder.events
 }
 
+/// Like [`decode_events`], but every byte of `raw` is accounted for.
+///
+/// Table switches, character codes, the terminator and the bytes after it are all kept,
+/// so [`encode_events`] gives back the exact input.
+pub fn decode_events_lossless(raw: &[u8]) -> Vec<Event> {
+    let mut iter = raw.chunks(4).flat_map(move |chk| chk.iter().rev().cloned());
+    let mut decoder = Decoder::new(&mut iter, true, MarioStory::builtin());
+    let mut trailing = Vec::new();
+    loop {
+        let start = decoder.pos;
+        if decoder.next().is_some() {
+            continue;
+        }
+        // The bytes of a command cut short by the end of the input
+        if !decoder.terminated {
+            trailing = swap_words(raw)[start..decoder.pos].to_vec();
+        }
+        break;
+    }
+    trailing.extend(decoder.iter);
+    if !trailing.is_empty() {
+        decoder.events.push(Event::Trailing(trailing));
+    }
+    decoder.events
+}
+
+/// Like [`decode_events`], but with the location of every event
+pub fn decode_events_spanned(raw: &[u8]) -> Vec<Spanned<Event>> {
+    let opts = DecodeOptions {
+        strictness: Strictness::ReportOnly,
+        ..Default::default()
+    };
+    match decode_events_with(raw, &opts) {
+        Ok(decoded) => decoded.items,
+        Err(_) => unreachable!("report-only decoding never fails"),
+    }
+}
+
+/// Like [`decode_events_spanned`], but with configurable error handling.
+///
+/// Every byte is valid somewhere in an immediate buffer, so the only errors are a command
+/// cut short by the end of the input and a missing terminator. Strictness only decides
+/// what happens then: [`Strictness::Lenient`] adds an [`Event::Error`] at the end, and
+/// [`Strictness::ReportOnly`] only reports it.
+pub fn decode_events_with(
+    raw: &[u8],
+    opts: &DecodeOptions,
+) -> Result<Decoded<Event, DecodeError>, DecodeError> {
+    let mut iter = raw.chunks(4).flat_map(move |chk| chk.iter().rev().cloned());
+    let mut decoder = Decoder::new(&mut iter, false, opts.dialect());
+    let mut items = Vec::new();
+    let mut errors = Vec::new();
+    loop {
+        let start = decoder.pos;
+        let more = decoder.next().is_some();
+        let span = Span::from_stream(start..decoder.pos, raw.len());
+        // Every call to `next` decodes at most one event
+        if let Some(inner) = decoder.events.pop() {
+            items.push(Spanned { inner, span });
+        }
+        if more {
+            continue;
+        }
+        if decoder.terminated {
+            break;
+        }
+        let err = if decoder.pos > start {
+            DecodeError::Truncated {
+                offset: crate::file_offset(start, raw.len()),
+            }
+        } else {
+            DecodeError::MissingTerminator { offset: raw.len() }
+        };
+        match opts.strictness {
+            Strictness::Strict => return Err(err),
+            Strictness::Lenient => items.push(Spanned {
+                inner: Event::Error(err.clone()),
+                span,
+            }),
+            Strictness::ReportOnly => {}
+        }
+        errors.push(err);
+        break;
+    }
+    Ok(Decoded { items, errors })
+}
+
 impl<'a> Decoder<'a> {
     fn next(&mut self) -> Option<()> {
-        match self.iter.next()? {
+        match self.byte()? {
             0xF8 => {
-                self.events.push(Event::BubbleStyle(self.iter.next()?));
+                let style = self.byte()?;
+                self.events.push(Event::BubbleStyle(style));
             }
             0xF0 => self.events.push(Event::Newline),
-            0xF1 => self.lookup_table = LookupTable::Kana,
-            0xF2 => self.lookup_table = LookupTable::Latin,
-            0xF3 => self.lookup_table = LookupTable::Kanji,
-            0xF4 => self.lookup_table = LookupTable::Button,
+            0xF1 => self.switch_table(LookupTable::Kana),
+            0xF2 => self.switch_table(LookupTable::Latin),
+            0xF3 => self.switch_table(LookupTable::Kanji),
+            0xF4 => self.switch_table(LookupTable::Button),
             0xF5 => self.events.push(Event::Space),
             0xF6 => self.events.push(Event::Tab),
             0xFA => self.events.push(Event::NextBubble),
-            0xFB => return None,
+            0xFB => {
+                self.terminated = true;
+                if self.lossless {
+                    self.events.push(Event::End);
+                }
+                return None;
+            }
             0xFF => {
                 let ev = self.next_extcmd()?;
                 self.events.push(ev);
@@ -93,43 +243,51 @@ impl<'a> Decoder<'a> {
         Some(())
     }
 
+    fn switch_table(&mut self, table: LookupTable) {
+        self.lookup_table = table;
+        if self.lossless {
+            self.events.push(Event::TableSwitch(table));
+        }
+    }
+
     fn add_char(&mut self, byte: u8) {
-        let ev = match self.lookup_table {
-            LookupTable::Kana => match crate::charsets::kana(byte) {
-                Some(ch) => Event::Char(ch),
-                None => Event::UnkKana(byte),
-            },
-            LookupTable::Kanji => match crate::charsets::kanji(byte) {
-                Some(ch) => Event::Char(ch),
-                None => Event::UnkKanji(byte),
-            },
-            LookupTable::Latin => match crate::charsets::latin(byte) {
-                Some(ch) => Event::Char(ch),
-                None => Event::UnkLatin(byte),
-            },
-            LookupTable::Button => match crate::charsets::button(byte) {
+        let ch = self.dialect.char(self.lookup_table, byte);
+        if self.lossless {
+            self.events.push(Event::Code {
+                table: self.lookup_table,
+                code: byte,
+                ch,
+            });
+            return;
+        }
+        let ev = match (self.lookup_table, ch) {
+            (LookupTable::Button, _) => match self.dialect.button(byte) {
                 Some(b) => Event::Btn(b),
                 None => Event::UnkBtn(byte),
             },
+            (_, Some(ch)) => Event::Char(ch),
+            (LookupTable::Kana, None) => Event::UnkKana(byte),
+            (LookupTable::Kanji, None) => Event::UnkKanji(byte),
+            (LookupTable::Latin, None) => Event::UnkLatin(byte),
         };
         self.events.push(ev);
     }
 
     fn next_extcmd(&mut self) -> Option<Event> {
-        let ev = match self.iter.next()? {
-            0x04 => Event::ExtSetColor(self.iter.next()?),
-            0x0B => Event::ExtCmd0B(self.iter.next()?),
-            0x0C => Event::ExtCmd0C(self.iter.next()?),
-            0x06 => Event::ExtCmd06(self.iter.next()?, self.iter.next()?),
-            0x14 => Event::ExtCmdUnk14(self.iter.next()?),
-            0x15 => Event::ExtCmdUnk15(self.iter.next()?),
+        let ev = match self.byte()? {
+            0x04 => Event::ExtSetColor(self.byte()?),
+            0x0B => Event::ExtCmd0B(self.byte()?),
+            0x0C => Event::ExtCmd0C(self.byte()?),
+            0x06 => Event::ExtCmd06(self.byte()?, self.byte()?),
+            0x14 => Event::ExtCmdUnk14(self.byte()?),
+            0x15 => Event::ExtCmdUnk15(self.byte()?),
             0x1C => self.next_text_effect()?,
-            0x1D => Event::ExtCmd1D(self.iter.next()?),
+            0x1D => Event::ExtCmd1D(self.byte()?),
             0x1A => Event::ExtStoreColor,
             0x1B => Event::ExtLoadColor,
-            0x1E => Event::ExtTextHoffset(self.iter.next()?),
-            0xFF => match self.iter.next()? {
-                0x0B => Event::ExtExtVOffset(self.iter.next()?),
+            0x1E => Event::ExtTextHoffset(self.byte()?),
+            0xFF => match self.byte()? {
+                0x0B => Event::ExtExtVOffset(self.byte()?),
                 etc => Event::UnkExtExtCmd(etc),
             },
             etc => Event::UnkExtCmd(etc),
@@ -138,14 +296,14 @@ impl<'a> Decoder<'a> {
     }
 
     fn next_text_effect(&mut self) -> Option<Event> {
-        let ev = match self.iter.next()? {
+        let ev = match self.byte()? {
             0x00 => Event::TextEffectShaky1,
             0x01 => Event::TextEffectWavy1,
             0x02 => Event::TextEffectDarkStar,
-            0x03 => Event::TextEffectNoise(self.iter.next()?),
-            0x05 => Event::TextEffectShaky2(self.iter.next()?),
+            0x03 => Event::TextEffectNoise(self.byte()?),
+            0x05 => Event::TextEffectShaky2(self.byte()?),
             0x06 => Event::TextEffectRainbow1,
-            0x07 => Event::TextEffectStar(self.iter.next()?),
+            0x07 => Event::TextEffectStar(self.byte()?),
             0x08 => Event::TextEffectWavy2,
             0x09 => Event::TextEffectRainbow2,
             0x0A => Event::TextEffectBowserLaugh,
@@ -157,3 +315,282 @@ impl<'a> Decoder<'a> {
         Some(ev)
     }
 }
+
+/// Encode events into an immediate buffer. This is the inverse of [`decode_events`].
+///
+/// The terminator is appended unless `events` contains [`Event::End`],
+/// and the rest of the buffer is filled with zeroes.
+pub fn encode_events(events: &[Event]) -> Result<[u8; BUFFER_SIZE], EncodeError> {
+    let mut enc = Encoder {
+        out: Vec::new(),
+        lookup_table: LookupTable::Kana,
+    };
+    for event in events {
+        enc.event(event)?;
+    }
+    if !events.contains(&Event::End) {
+        enc.out.push(0xFB);
+    }
+    if enc.out.len() > BUFFER_SIZE {
+        return Err(EncodeError::BufferOverflow { len: enc.out.len() });
+    }
+    enc.out.resize(BUFFER_SIZE, 0);
+    let mut buf = [0; BUFFER_SIZE];
+    buf.copy_from_slice(&swap_words(&enc.out));
+    Ok(buf)
+}
+
+struct Encoder {
+    out: Vec<u8>,
+    lookup_table: LookupTable,
+}
+
+impl Encoder {
+    fn event(&mut self, event: &Event) -> Result<(), EncodeError> {
+        match *event {
+            Event::BubbleStyle(style) => self.out.extend([0xF8, style]),
+            Event::Char(ch) => {
+                match crate::charsets::reverse(ch).map_err(|e| EncodeError::from_reverse(ch, e))? {
+                    CharCode::Single(table, code) => self.char(table, code),
+                    CharCode::Wide(_) => return Err(EncodeError::Unencodable { ch }),
+                }
+            }
+            Event::Btn(btn) => self.char(LookupTable::Button, btn.code()),
+            Event::UnkKana(code) => self.unknown_char(LookupTable::Kana, code)?,
+            Event::UnkKanji(code) => self.unknown_char(LookupTable::Kanji, code)?,
+            Event::UnkLatin(code) => self.unknown_char(LookupTable::Latin, code)?,
+            Event::UnkBtn(code) => self.unknown_char(LookupTable::Button, code)?,
+            Event::Newline => self.out.push(0xF0),
+            Event::Space => self.out.push(0xF5),
+            Event::Tab => self.out.push(0xF6),
+            Event::NextBubble => self.out.push(0xFA),
+            Event::UnkExtCmd(id) => {
+                if EXT_CMDS.contains(&id) {
+                    return Err(EncodeError::InvalidCode { code: id });
+                }
+                self.out.extend([0xFF, id]);
+            }
+            Event::ExtCmd1D(p) => self.out.extend([0xFF, 0x1D, p]),
+            Event::ExtSetColor(color) => self.out.extend([0xFF, 0x04, color]),
+            Event::ExtStoreColor => self.out.extend([0xFF, 0x1A]),
+            Event::ExtLoadColor => self.out.extend([0xFF, 0x1B]),
+            Event::ExtCmd0B(p) => self.out.extend([0xFF, 0x0B, p]),
+            Event::ExtCmd06(p1, p2) => self.out.extend([0xFF, 0x06, p1, p2]),
+            Event::ExtCmd0C(p) => self.out.extend([0xFF, 0x0C, p]),
+            Event::ExtCmdUnk14(p) => self.out.extend([0xFF, 0x14, p]),
+            Event::ExtCmdUnk15(p) => self.out.extend([0xFF, 0x15, p]),
+            Event::ExtTextHoffset(off) => self.out.extend([0xFF, 0x1E, off]),
+            Event::TableSwitch(table) => {
+                self.lookup_table = table;
+                self.out.push(table_switch_code(table));
+            }
+            Event::Code { table, code, .. } => {
+                if is_control(code) {
+                    return Err(EncodeError::InvalidCode { code });
+                }
+                self.char(table, code);
+            }
+            Event::End => self.out.push(0xFB),
+            Event::Trailing(ref bytes) => self.out.extend(bytes),
+            Event::Error(_) => {}
+            Event::UnkExtExtCmd(0x0B) => return Err(EncodeError::InvalidCode { code: 0x0B }),
+            Event::UnkExtExtCmd(id) => self.out.extend([0xFF, 0xFF, id]),
+            Event::ExtExtVOffset(off) => self.out.extend([0xFF, 0xFF, 0x0B, off]),
+            Event::UnkTextEffect(id) => {
+                if TEXT_EFFECTS.contains(&id) {
+                    return Err(EncodeError::InvalidCode { code: id });
+                }
+                self.text_effect(&[id]);
+            }
+            Event::TextEffectShaky1 => self.text_effect(&[0x00]),
+            Event::TextEffectWavy1 => self.text_effect(&[0x01]),
+            Event::TextEffectDarkStar => self.text_effect(&[0x02]),
+            Event::TextEffectNoise(p) => self.text_effect(&[0x03, p]),
+            Event::TextEffectShaky2(p) => self.text_effect(&[0x05, p]),
+            Event::TextEffectRainbow1 => self.text_effect(&[0x06]),
+            Event::TextEffectStar(p) => self.text_effect(&[0x07, p]),
+            Event::TextEffectWavy2 => self.text_effect(&[0x08]),
+            Event::TextEffectRainbow2 => self.text_effect(&[0x09]),
+            Event::TextEffectBowserLaugh => self.text_effect(&[0x0A]),
+            Event::TextEffectQuickPulse => self.text_effect(&[0x0C]),
+            Event::TextEffectWavePulse => self.text_effect(&[0x0D]),
+            Event::TextEffectShadow => self.text_effect(&[0x0E]),
+        }
+        Ok(())
+    }
+
+    fn char(&mut self, table: LookupTable, code: u8) {
+        if self.lookup_table != table {
+            self.out.push(table_switch_code(table));
+            self.lookup_table = table;
+        }
+        self.out.push(code);
+    }
+
+    /// A code the decoder has no character for. Codes it would decode as a control code or
+    /// a known character are rejected.
+    fn unknown_char(&mut self, table: LookupTable, code: u8) -> Result<(), EncodeError> {
+        let dialect = MarioStory::builtin();
+        let known = match table {
+            LookupTable::Button => dialect.button(code).is_some(),
+            _ => dialect.char(table, code).is_some(),
+        };
+        if is_control(code) || known {
+            return Err(EncodeError::InvalidCode { code });
+        }
+        self.char(table, code);
+        Ok(())
+    }
+
+    fn text_effect(&mut self, bytes: &[u8]) {
+        self.out.extend([0xFF, 0x1C]);
+        self.out.extend(bytes);
+    }
+}
+
+/// Bytes the decoder reads as control codes, see [`Decoder::next`]
+fn is_control(byte: u8) -> bool {
+    matches!(byte, 0xF0..=0xF6 | 0xF8 | 0xFA | 0xFB | 0xFF)
+}
+
+/// Ext command ids the decoder knows, see [`Decoder::next_extcmd`]
+const EXT_CMDS: [u8; 12] = [
+    0x04, 0x06, 0x0B, 0x0C, 0x14, 0x15, 0x1A, 0x1B, 0x1C, 0x1D, 0x1E, 0xFF,
+];
+
+/// Text effect ids the decoder knows, see [`Decoder::next_text_effect`]
+const TEXT_EFFECTS: [u8; 13] = [
+    0x00, 0x01, 0x02, 0x03, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A, 0x0C, 0x0D, 0x0E,
+];
+
+fn table_switch_code(table: LookupTable) -> u8 {
+    match table {
+        LookupTable::Kana => 0xF1,
+        LookupTable::Latin => 0xF2,
+        LookupTable::Kanji => 0xF3,
+        LookupTable::Button => 0xF4,
+    }
+}
+
+#[test]
+fn test_encode_roundtrip() {
+    let events = vec![
+        Event::BubbleStyle(0x07),
+        Event::ExtExtVOffset(0x10),
+        Event::Char('あ'),
+        Event::Char('Ｚ'),
+        Event::Btn(Button::Start),
+        Event::UnkKanji(0xE0),
+        Event::Newline,
+        Event::TextEffectNoise(3),
+        Event::ExtCmd06(1, 2),
+        Event::NextBubble,
+    ];
+    let buf = encode_events(&events).unwrap();
+    assert_eq!(decode_events(&buf), events);
+    assert_eq!(
+        encode_events(&vec![Event::Space; BUFFER_SIZE]),
+        Err(EncodeError::BufferOverflow {
+            len: BUFFER_SIZE + 1
+        })
+    );
+}
+
+#[test]
+fn test_encode_invalid() {
+    for event in [
+        Event::UnkExtCmd(0x04),
+        Event::UnkKana(0xF5),
+        Event::UnkKana(0x00),
+        Event::UnkBtn(0x00),
+        Event::UnkExtExtCmd(0x0B),
+        Event::UnkTextEffect(0x03),
+        Event::Code {
+            table: LookupTable::Latin,
+            code: 0xFB,
+            ch: None,
+        },
+    ] {
+        assert_matches!(
+            encode_events(&[event]),
+            Err(EncodeError::InvalidCode { .. })
+        );
+    }
+    let events = [
+        Event::UnkKana(0xF9),
+        Event::UnkExtCmd(0x30),
+        Event::UnkTextEffect(0x04),
+    ];
+    assert_eq!(decode_events(&encode_events(&events).unwrap()), events);
+}
+
+#[test]
+fn test_lossless_roundtrip() {
+    let mut stream = vec![0xF8, 0x03, 0xF1, 0x2D, 0xC4, 0xF3, 0xF3, 0x77, 0xF4, 0x09];
+    stream.extend([0xFF, 0x1C, 0x03, 0x01, 0xFB]);
+    stream.resize(BUFFER_SIZE, 0xAA);
+    let raw = swap_words(&stream);
+    let events = decode_events_lossless(&raw);
+    assert_eq!(encode_events(&events).unwrap(), raw[..]);
+}
+
+#[test]
+fn test_lossless_truncated() {
+    let code = |code| Event::Code {
+        table: LookupTable::Kana,
+        code,
+        ch: Some('あ'),
+    };
+    let raw = swap_words(&[0x00, 0xFF]);
+    assert_eq!(
+        decode_events_lossless(&raw),
+        [code(0x00), Event::Trailing(vec![0xFF])]
+    );
+    let raw = swap_words(&[0x00, 0x00, 0x00, 0x00, 0xF8]);
+    let events = decode_events_lossless(&raw);
+    assert_eq!(events.len(), 5);
+    assert_eq!(events[4], Event::Trailing(vec![0xF8]));
+}
+
+#[test]
+fn test_spans() {
+    // Stream order: F8 07 00 FF | 1E 04 FB
+    let raw = [0xFF, 0x00, 0x07, 0xF8, 0xFB, 0x04, 0x1E];
+    let spans: Vec<_> = decode_events_spanned(&raw)
+        .into_iter()
+        .map(|ev| ev.span.range())
+        .collect();
+    assert_eq!(spans, [2..4, 1..2, 0..7]);
+}
+
+#[test]
+fn test_strictness() {
+    // Vertical offset command cut short, no terminator
+    let raw = swap_words(&[0x00, 0xFF, 0xFF, 0x0B]);
+    let with = |raw, strictness| {
+        decode_events_with(
+            raw,
+            &DecodeOptions {
+                strictness,
+                ..Default::default()
+            },
+        )
+    };
+    assert_eq!(
+        with(&raw, Strictness::Strict).unwrap_err(),
+        DecodeError::Truncated { offset: 2 }
+    );
+    let lenient = with(&raw, Strictness::Lenient).unwrap();
+    assert_eq!(lenient.errors, [DecodeError::Truncated { offset: 2 }]);
+    assert_eq!(
+        lenient.items.last().unwrap().inner,
+        Event::Error(DecodeError::Truncated { offset: 2 })
+    );
+    let raw = swap_words(&[0x00, 0x01]);
+    let report = with(&raw, Strictness::ReportOnly).unwrap();
+    assert_eq!(report.items.len(), 2);
+    assert_eq!(
+        report.errors,
+        [DecodeError::MissingTerminator { offset: 2 }]
+    );
+}
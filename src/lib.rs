@@ -1,13 +1,48 @@
 #![feature(macro_metavar_expr, assert_matches)]
 
+pub use {
+    charsets::{
+        from_private_use, private_use, Ambiguity, Button, CharCode, Charset, CodeTable, Confidence,
+        GlyphCategory, GlyphInfo, GlyphTable, ReverseError, TblError, TblValue,
+        MARIO_STORY_APPROXIMATED, MARIO_STORY_GLYPH_NOTES, MARIO_STORY_PREFERRED,
+    },
+    dialect::{font_mapping, Control, Dialect, Ique, LeadByteError, MarioStory, PaperMario},
+    encode::{
+        encode, encode_events, encode_events_with, encode_tokens, encode_tokens_with, encode_with,
+        EncodeError,
+    },
+    extcmd::{ExtCmd, UnkCmd},
+    image::Image,
+    markup::ParseError,
+    scan::{scan, Candidate, ScanOptions},
+};
 use {
-    crate::extcmd::UnkCmd, charsets::Button, extcmd::ExtCmd, num_enum::TryFromPrimitive,
-    std::ops::ControlFlow,
+    num_enum::TryFromPrimitive,
+    std::{
+        ops::{ControlFlow, Range},
+        sync::Arc,
+    },
 };
 
+#[cfg(test)]
+use std::assert_matches::assert_matches;
+
 mod charsets;
+pub mod decomp;
+mod dialect;
+mod encode;
 mod extcmd;
-mod imm;
+pub mod font;
+mod image;
+pub mod imm;
+pub mod markup;
+pub mod msg;
+pub mod patch;
+pub mod po;
+pub mod render;
+pub mod rom;
+mod scan;
+pub mod yay0;
 
 /// The size of a dialog buffer
 pub const BUFFER_SIZE: usize = 1024;
@@ -70,17 +105,9 @@ fn events_to_lines(events: Vec<imm::Event>) -> EventsToLinesOut {
             | imm::Event::TextEffectWavePulse
             | imm::Event::TextEffectWavy1
             | imm::Event::TextEffectWavy2 => {}
-            imm::Event::Btn(btn) => {
-                let str = match btn {
-                    Button::A => "[A]",
-                    Button::B => "[B]",
-                    Button::Start => "[START]",
-                    Button::CDown => "[C⬇]",
-                    Button::CLeft => "[C◀]",
-                    Button::Z => "[Z]",
-                };
-                linebuf.push_str(str);
-            }
+            imm::Event::Btn(btn) => linebuf.push_str(btn.label()),
+            imm::Event::Code { ch: Some(ch), .. } => linebuf.push(ch),
+            imm::Event::TableSwitch(_) | imm::Event::End | imm::Event::Trailing(_) => {}
             imm::Event::ExtTextHoffset(off) => hoffset = off,
             imm::Event::ExtExtVOffset(off) => start_scroll = off as u32,
             _ => linebuf.push_str(&format!(" ( {event:02X?}) ")),
@@ -149,10 +176,21 @@ pub fn decode_imm_buf(data: &[u8], mut scroll: u32) -> DecodeImmBufOut {
     DecodeImmBufOut { text: buf, hoffs }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Event {
     StyleChange(Style),
+    /// A style with parameters, see [`Dialect::style_params`]
+    StyleWithParams {
+        style: Style,
+        params: Vec<u8>,
+    },
     Space,
+    /// A space as wide as a full width character (Paper Mario)
+    FullSpace,
+    /// A space half as wide as [`Event::FullSpace`] (Paper Mario)
+    HalfSpace,
+    /// Remember the current text color (Paper Mario)
+    SaveColor,
     Dialog(String),
     End,
     Linebreak,
@@ -166,14 +204,37 @@ pub enum Event {
         rawcode: u8,
     },
     ExtCmd(extcmd::ExtCmd),
+    /// An ext command whose parameters don't fit it. The decoders keep such commands as
+    /// [`ExtCmd::Other`] now, so this only comes from markup.
     ExtCmdError {
         id: u8,
         argc: u8,
         args_got: u8,
     },
+    /// A character code the lookup table has no character for
+    RawChar {
+        table: LookupTable,
+        code: u8,
+    },
+    /// A character whose glyph isn't verified, see [`DecodeOptions::flag_unverified`]
+    Unverified {
+        table: LookupTable,
+        code: u8,
+        ch: char,
+    },
+    /// A two byte character code the dialect has no character for
+    RawWideChar {
+        code: u16,
+    },
+    /// Malformed input that was skipped in lenient mode
+    Error {
+        error: DecodeError,
+        /// The skipped bytes, in stream order
+        raw: Vec<u8>,
+    },
 }
 
-#[derive(Debug, TryFromPrimitive)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromPrimitive)]
 #[repr(u8)]
 pub enum Style {
     Invalid = 0x00,
@@ -194,15 +255,108 @@ pub enum Style {
     NarrationB = 0x0F,
 }
 
-enum Status {
+/// State of the script decoder
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
     Init,
     Style,
+    /// After a style that takes parameters
+    StyleParams {
+        style: Style,
+        argc: u8,
+        argidx: u8,
+    },
     Delay,
     ExtCmd,
-    ExtCmdParams { id: u8, argc: u8, argidx: u8 },
+    ExtCmdParams {
+        id: u8,
+        argc: u8,
+        argidx: u8,
+    },
+    /// After the lead byte of a two byte character code
+    WideChar {
+        lead: u8,
+    },
+}
+
+/// Error decoding a script stream.
+///
+/// `offset` is the file offset of the offending byte, or of the start of the
+/// command for truncation errors. `state` is the decoder state at the error.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The byte after `0xFC` is not a valid [`Style`]
+    InvalidStyle {
+        offset: usize,
+        state: Status,
+        byte: u8,
+    },
+    /// Stream ends right after `0xFC`, or inside the style's parameters
+    TruncatedStyle { offset: usize, state: Status },
+    /// Stream ends inside an ext command or its parameters
+    TruncatedExtCmd { offset: usize, state: Status },
+    /// Stream ends right after `0xF2`
+    TruncatedDelay { offset: usize, state: Status },
+    /// Stream ends right after the lead byte of a two byte character code
+    TruncatedChar { offset: usize, state: Status },
+    /// Stream has no `0xFD` terminator
+    MissingTerminator { offset: usize, state: Status },
+    /// Ext command with an id that isn't known, so its parameter count is unknown
+    UnknownCommand {
+        offset: usize,
+        state: Status,
+        id: u8,
+    },
 }
 
-pub fn to_string_nth_bubble(raw: &[u8], bubble_idx: u8) -> Result<String, String> {
+impl DecodeError {
+    pub fn offset(&self) -> usize {
+        match *self {
+            Self::InvalidStyle { offset, .. }
+            | Self::TruncatedStyle { offset, .. }
+            | Self::TruncatedExtCmd { offset, .. }
+            | Self::TruncatedDelay { offset, .. }
+            | Self::TruncatedChar { offset, .. }
+            | Self::MissingTerminator { offset, .. }
+            | Self::UnknownCommand { offset, .. } => offset,
+        }
+    }
+    pub fn state(&self) -> Status {
+        match *self {
+            Self::InvalidStyle { state, .. }
+            | Self::TruncatedStyle { state, .. }
+            | Self::TruncatedExtCmd { state, .. }
+            | Self::TruncatedDelay { state, .. }
+            | Self::TruncatedChar { state, .. }
+            | Self::MissingTerminator { state, .. }
+            | Self::UnknownCommand { state, .. } => state,
+        }
+    }
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidStyle { byte, .. } => write!(f, "invalid style 0x{byte:02X}")?,
+            Self::TruncatedStyle { .. } => f.write_str("truncated style change")?,
+            Self::TruncatedExtCmd { .. } => f.write_str("truncated ext command")?,
+            Self::TruncatedDelay { .. } => f.write_str("truncated delay")?,
+            Self::TruncatedChar { .. } => f.write_str("truncated character")?,
+            Self::MissingTerminator { .. } => f.write_str("missing terminator")?,
+            Self::UnknownCommand { id, .. } => write!(f, "unknown ext command 0x{id:02X}")?,
+        }
+        write!(
+            f,
+            " at offset 0x{:X} (state: {:?})",
+            self.offset(),
+            self.state()
+        )
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+pub fn to_string_nth_bubble(raw: &[u8], bubble_idx: u8) -> Result<String, DecodeError> {
     let mut s = String::new();
     let mut current_idx = 0;
     for event in translate(raw)? {
@@ -210,7 +364,8 @@ pub fn to_string_nth_bubble(raw: &[u8], bubble_idx: u8) -> Result<String, String
             if let Event::NextBubble = &event {
                 return Ok(s);
             }
-            if let ControlFlow::Break(_) = write_event_string(&event, &mut s) {
+            if let ControlFlow::Break(_) = write_event_string(&event, &mut s, MarioStory::builtin())
+            {
                 break;
             }
         } else if let Event::NextBubble = &event {
@@ -220,37 +375,88 @@ pub fn to_string_nth_bubble(raw: &[u8], bubble_idx: u8) -> Result<String, String
     Ok(s)
 }
 
-pub fn to_string(raw: &[u8]) -> Result<String, String> {
+pub fn to_string(raw: &[u8]) -> Result<String, DecodeError> {
+    to_string_with(raw, &DecodeOptions::tolerant())
+}
+
+/// Decode to marked up text, keeping every control code. See the [`markup`] module.
+pub fn to_markup(raw: &[u8]) -> Result<String, DecodeError> {
+    Ok(markup::print(&translate(raw)?))
+}
+
+/// Like [`to_string`], but with configurable error handling
+pub fn to_string_with(raw: &[u8], opts: &DecodeOptions) -> Result<String, DecodeError> {
     let mut s = String::new();
-    for event in translate(raw)? {
-        if let ControlFlow::Break(_) = write_event_string(&event, &mut s) {
+    for event in translate_with(raw, opts)?.items {
+        if let ControlFlow::Break(_) = write_event_string(&event.inner, &mut s, opts.dialect()) {
             break;
         }
     }
     Ok(s)
 }
 
+/// Decoded text, along with where each character came from
+#[derive(Debug)]
+pub struct MappedString {
+    pub text: String,
+    /// Span of every `char` in `text`
+    pub spans: Vec<Span>,
+}
+
+impl MappedString {
+    /// Index of the first `char` of `text` that was decoded from the byte at file `offset`
+    pub fn char_at_offset(&self, offset: usize) -> Option<usize> {
+        self.spans
+            .iter()
+            .position(|span| span.range().contains(&offset))
+    }
+}
+
+/// Like [`to_string`], but also maps every character back to the input
+pub fn to_string_mapped(raw: &[u8]) -> Result<MappedString, DecodeError> {
+    let mut text = String::new();
+    let mut spans = Vec::new();
+    for Spanned { inner, span } in tokenize_spanned(raw)? {
+        let mut s = String::new();
+        let flow = match inner {
+            Token::TableSwitch(_) => ControlFlow::Continue(()),
+            Token::Char { table, code, ch } => {
+                push_char(table, code, ch, &mut s);
+                ControlFlow::Continue(())
+            }
+            Token::WideChar { code, ch } => {
+                match ch {
+                    Some(ch) => s.push(ch),
+                    None => markup::write_event(&Event::RawWideChar { code }, &mut s),
+                }
+                ControlFlow::Continue(())
+            }
+            Token::Event(event) => write_event_string(&event, &mut s, MarioStory::builtin()),
+        };
+        if flow.is_break() {
+            break;
+        }
+        spans.extend(std::iter::repeat_n(span, s.chars().count()));
+        text.push_str(&s);
+    }
+    Ok(MappedString { text, spans })
+}
+
 #[must_use]
-fn write_event_string(event: &Event, s: &mut String) -> ControlFlow<()> {
+fn write_event_string(event: &Event, s: &mut String, dialect: &dyn Dialect) -> ControlFlow<()> {
     match event {
-        Event::StyleChange(_) => {}
-        // Ideographic (full width) space
-        Event::Space => s.push('\u{3000}'),
+        Event::StyleChange(_) | Event::StyleWithParams { .. } => {}
+        Event::Space => s.push(dialect.space()),
+        Event::FullSpace | Event::HalfSpace => s.push(' '),
+        Event::SaveColor => {}
         Event::Dialog(str) => s.push_str(str),
         Event::End => return ControlFlow::Break(()),
         Event::Linebreak => s.push('\n'),
         Event::Delay(_) => {}
         Event::Bell => {}
-        Event::ButtonRef { button, rawcode } => match button {
-            Some(b) => s.push_str(match b {
-                Button::A => "[A]",
-                Button::B => "[B]",
-                Button::Start => "[START]",
-                Button::CDown => "[C⬇]",
-                Button::CLeft => "[C◀]",
-                Button::Z => "[Z]",
-            }),
-            None => s.push_str(&format!("{{buttonref:{rawcode:02X}}}")),
+        Event::ButtonRef { button, .. } => match button {
+            Some(b) => s.push_str(b.label()),
+            None => markup::write_event(event, s),
         },
         Event::NextBubble => s.push_str("⭐\n"),
         Event::Sparkly => {}
@@ -269,168 +475,715 @@ fn write_event_string(event: &Event, s: &mut String) -> ControlFlow<()> {
             | ExtCmd::AutoScroll { .. }
             | ExtCmd::FontSize { .. }
             | ExtCmd::FontSizeReset { .. } => {}
-            _ => s.push_str(&format!(" ( {cmd:?} ) ")),
+            _ => markup::write_event(event, s),
         },
         Event::ExtCmdError { id, argc, args_got } => {
             s.push_str(&format!(
                 "[extcmd_error] id: 0x{id:02X}, argc: {argc}, got: {args_got}"
             ));
         }
+        Event::RawChar { table, code } => markup::write_raw_char(*table, *code, s),
+        Event::RawWideChar { .. } | Event::Unverified { .. } => markup::write_event(event, s),
+        Event::Error { error, .. } => s.push_str(&format!("[decode_error] {error}")),
     }
     ControlFlow::Continue(())
 }
 
-enum LookupTable {
+/// The character table the following character codes are looked up in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LookupTable {
     Kana,
     Kanji,
     Latin,
     Button,
 }
 
-pub fn translate(raw: &[u8]) -> Result<Vec<Event>, String> {
+/// Reverse the byte order of every 4 byte word.
+///
+/// Dialog data is stored with swapped words, so this converts between stream order and
+/// the order the decoders expect. Applying it twice gives back the original bytes.
+pub fn swap_words(bytes: &[u8]) -> Vec<u8> {
+    bytes
+        .chunks(4)
+        .flat_map(|chk| chk.iter().rev().copied())
+        .collect()
+}
+
+/// A single unit of the dialog stream.
+///
+/// Unlike [`Event`], tokens account for every input byte, so encoding them with
+/// [`encode_tokens`] gives back the exact input.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token {
+    /// Lookup table switch (`0xF3..=0xF6` in Mario Story)
+    TableSwitch(LookupTable),
+    /// A character code, and what it decodes to in its lookup table
+    Char {
+        table: LookupTable,
+        code: u8,
+        ch: Option<char>,
+    },
+    /// A two byte character code, with the lead byte as the high byte
+    WideChar { code: u16, ch: Option<char> },
+    /// Any other event. Never [`Event::Dialog`].
+    Event(Event),
+}
+
+/// Location of decoded data in the input, in file order.
+///
+/// Because of the word swap, the bytes of an event that crosses a word boundary are not
+/// contiguous in the file. In that case the span covers all of them, and some bytes
+/// of neighbouring events as well.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    /// File offset of the first byte
+    pub offset: usize,
+    /// Number of bytes covered
+    pub len: usize,
+}
+
+impl Span {
+    /// Span covering the stream positions `range` of an input that is `total` bytes long
+    pub(crate) fn from_stream(range: Range<usize>, total: usize) -> Self {
+        let mut offsets = range.map(|pos| file_offset(pos, total));
+        let Some(first) = offsets.next() else {
+            return Self { offset: 0, len: 0 };
+        };
+        let (min, max) = offsets.fold((first, first), |(min, max), off| {
+            (min.min(off), max.max(off))
+        });
+        Self {
+            offset: min,
+            len: max - min + 1,
+        }
+    }
+    /// Smallest span that covers both `self` and `other`
+    pub fn merge(self, other: Self) -> Self {
+        let start = self.offset.min(other.offset);
+        let end = self.range().end.max(other.range().end);
+        Self {
+            offset: start,
+            len: end - start,
+        }
+    }
+    pub fn range(&self) -> Range<usize> {
+        self.offset..self.offset + self.len
+    }
+}
+
+/// File offset of the byte at stream position `pos`, in an input that is `total` bytes long
+pub fn file_offset(pos: usize, total: usize) -> usize {
+    let chunk_start = pos & !3;
+    let chunk_len = (total - chunk_start).min(4);
+    chunk_start + chunk_len - 1 - (pos - chunk_start)
+}
+
+/// Decoded data along with where it came from
+#[derive(Debug, Clone, PartialEq)]
+pub struct Spanned<T> {
+    pub inner: T,
+    pub span: Span,
+}
+
+/// How the decoders react to malformed input
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Strictness {
+    /// Fail on the first error
+    #[default]
+    Strict,
+    /// Put an error event in place of the bad bytes, resynchronise and continue
+    Lenient,
+    /// Like [`Strictness::Lenient`], but errors are only reported in [`Decoded::errors`],
+    /// and the bad bytes are left out of the decoded stream
+    ReportOnly,
+}
+
+/// How the decoders output codes the dialect only approximates with a character
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum GlyphMode {
+    /// Use the approximating character. Re-encoding may give a different code.
+    #[default]
+    Approximate,
+    /// Use the [`private_use`] code point of the code, which the encoders map back to it
+    PrivateUse,
+    /// Output [`Event::RawChar`], a `[raw:kana:C4]` tag in markup
+    Tagged,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DecodeOptions {
+    pub strictness: Strictness,
+    /// Stop decoding after the first terminator, ignoring the rest of the input
+    pub stop_at_end: bool,
+    /// How codes that are only approximated come out
+    pub glyphs: GlyphMode,
+    /// Output characters the dialect's [`GlyphInfo`] doesn't list as verified as
+    /// [`Event::Unverified`], so they can be double-checked
+    pub flag_unverified: bool,
+    /// Text format of the input. `None` uses [`MarioStory::builtin`].
+    pub dialect: Option<Arc<dyn Dialect>>,
+    /// Decode ext commands with an unknown id as [`ExtCmd::Unknown`] instead of reporting
+    /// an error. Their parameter count is unknown, so the bytes after them decode as text.
+    pub allow_unknown_commands: bool,
+    /// Don't report input that has no terminator, like a partial selection
+    pub allow_unterminated: bool,
+}
+
+impl DecodeOptions {
+    /// What the functions without options use: unknown ext commands and a missing
+    /// terminator are accepted, everything else is an error
+    fn tolerant() -> Self {
+        Self {
+            allow_unknown_commands: true,
+            allow_unterminated: true,
+            ..Default::default()
+        }
+    }
+    pub(crate) fn dialect(&self) -> &dyn Dialect {
+        match &self.dialect {
+            Some(dialect) => dialect.as_ref(),
+            None => MarioStory::builtin(),
+        }
+    }
+}
+
+/// Output of a decoder that was run with [`DecodeOptions`]
+#[derive(Debug)]
+pub struct Decoded<T, E = DecodeError> {
+    pub items: Vec<Spanned<T>>,
+    /// Errors that were recovered from
+    pub errors: Vec<E>,
+}
+
+/// Losslessly decode the dialog stream into tokens
+pub fn tokenize(raw: &[u8]) -> Result<Vec<Token>, DecodeError> {
+    Ok(tokenize_spanned(raw)?
+        .into_iter()
+        .map(|tok| tok.inner)
+        .collect())
+}
+
+/// Like [`tokenize`], but with the location of every token
+pub fn tokenize_spanned(raw: &[u8]) -> Result<Vec<Spanned<Token>>, DecodeError> {
+    Ok(tokenize_with(raw, &DecodeOptions::tolerant())?.items)
+}
+
+/// Like [`tokenize_spanned`], but with configurable error handling.
+///
+/// In lenient mode, bad bytes are kept in [`Event::Error`] tokens, so the output is
+/// still lossless.
+pub fn tokenize_with(raw: &[u8], opts: &DecodeOptions) -> Result<Decoded<Token>, DecodeError> {
+    tokenize_inner(raw, opts)
+}
+
+/// Decode the dialog stream into events.
+///
+/// Like the other functions without [`DecodeOptions`], this accepts unknown ext commands
+/// and input without a terminator, see [`DecodeOptions::allow_unknown_commands`] and
+/// [`DecodeOptions::allow_unterminated`].
+pub fn translate(raw: &[u8]) -> Result<Vec<Event>, DecodeError> {
+    Ok(translate_spanned(raw)?
+        .into_iter()
+        .map(|ev| ev.inner)
+        .collect())
+}
+
+/// Like [`translate`], but with the location of every event
+pub fn translate_spanned(raw: &[u8]) -> Result<Vec<Spanned<Event>>, DecodeError> {
+    Ok(translate_with(raw, &DecodeOptions::tolerant())?.items)
+}
+
+/// Like [`translate_spanned`], but with configurable error handling
+pub fn translate_with(raw: &[u8], opts: &DecodeOptions) -> Result<Decoded<Event>, DecodeError> {
     let mut events = Vec::new();
     let mut buf = String::new();
-    let mut status = Status::Init;
-    let mut lookup_table = LookupTable::Kana;
-    let mut argbuf = Vec::new();
+    let mut buf_span: Option<Span> = None;
     macro_rules! flushbuf {
         () => {
-            if !buf.is_empty() {
-                events.push(Event::Dialog(std::mem::take(&mut buf)));
+            if let Some(span) = buf_span.take() {
+                events.push(Spanned {
+                    inner: Event::Dialog(std::mem::take(&mut buf)),
+                    span,
+                });
             }
         };
     }
-    for chk in raw.chunks(4) {
-        for &b in chk.iter().rev() {
-            match &mut status {
-                Status::Init => match b {
-                    0xD9 => {
-                        flushbuf!();
-                        events.push(Event::Sparkly);
-                    }
-                    0xFC => {
-                        flushbuf!();
-                        status = Status::Style;
-                    }
-                    0xF7 => {
-                        flushbuf!();
-                        events.push(Event::Space);
-                    }
-                    0xF0 => {
-                        flushbuf!();
-                        events.push(Event::Linebreak);
-                    }
-                    0xF1 => {
-                        flushbuf!();
-                        events.push(Event::Bell);
-                    }
-                    0xF2 => {
-                        flushbuf!();
-                        status = Status::Delay;
-                    }
-                    0xF3 => {
-                        lookup_table = LookupTable::Kana;
-                    }
-                    0xF4 => {
-                        lookup_table = LookupTable::Latin;
-                    }
-                    0xF5 => {
-                        lookup_table = LookupTable::Kanji;
-                    }
-                    0xF6 => {
-                        lookup_table = LookupTable::Button;
+    let Decoded { items, errors } = tokenize_inner(raw, opts)?;
+    let dialect = opts.dialect();
+    let unverified = |table, code| {
+        opts.flag_unverified
+            && dialect
+                .glyph_info(table, code)
+                .is_some_and(|info| info.confidence != Confidence::Verified)
+    };
+    for Spanned { inner, span } in items {
+        match inner {
+            Token::TableSwitch(_) => {}
+            Token::Char {
+                table,
+                code,
+                ch: Some(ch),
+            } if unverified(table, code) => {
+                flushbuf!();
+                events.push(Spanned {
+                    inner: Event::Unverified { table, code, ch },
+                    span,
+                });
+            }
+            // Markup reads an ideographic space as a space, so such a character keeps its code
+            Token::Char { ch: Some(ch), .. } | Token::WideChar { ch: Some(ch), .. }
+                if ch != markup::SPACE =>
+            {
+                buf.push(ch);
+                buf_span = Some(buf_span.map_or(span, |s| s.merge(span)));
+            }
+            Token::Char { table, code, .. } => {
+                flushbuf!();
+                events.push(Spanned {
+                    inner: Event::RawChar { table, code },
+                    span,
+                });
+            }
+            Token::WideChar { code, .. } => {
+                flushbuf!();
+                events.push(Spanned {
+                    inner: Event::RawWideChar { code },
+                    span,
+                });
+            }
+            Token::Event(event) => {
+                flushbuf!();
+                events.push(Spanned { inner: event, span });
+            }
+        }
+    }
+    flushbuf!();
+    Ok(Decoded {
+        items: events,
+        errors,
+    })
+}
+
+fn push_char(table: LookupTable, code: u8, ch: Option<char>, s: &mut String) {
+    match ch {
+        Some(ch) => s.push(ch),
+        None => markup::write_raw_char(table, code, s),
+    }
+}
+
+fn tokenize_inner(raw: &[u8], opts: &DecodeOptions) -> Result<Decoded<Token>, DecodeError> {
+    tokenize_stream(
+        &swap_words(raw),
+        |range| Span::from_stream(range, raw.len()),
+        opts,
+    )
+}
+
+/// Tokenize bytes that are already in stream order.
+///
+/// `span_of` maps a range of positions in `stream` to a span in the caller's input.
+pub(crate) fn tokenize_stream(
+    stream: &[u8],
+    span_of: impl Fn(Range<usize>) -> Span,
+    opts: &DecodeOptions,
+) -> Result<Decoded<Token>, DecodeError> {
+    let mut tokens = Vec::new();
+    let mut errors = Vec::new();
+    let mut status = Status::Init;
+    let dialect = opts.dialect();
+    let mut lookup_table = dialect.initial_table();
+    let mut argbuf = Vec::new();
+    // Stream position and bytes of the current token
+    let mut start = 0;
+    let mut cmdbuf = Vec::new();
+    // Report an error, then either bail out or keep going depending on strictness
+    macro_rules! fail {
+        ($err:expr, $span:expr) => {
+            match opts.strictness {
+                Strictness::Strict => return Err($err),
+                Strictness::Lenient => {
+                    errors.push($err);
+                    tokens.push(Spanned {
+                        inner: Token::Event(Event::Error {
+                            error: $err,
+                            raw: std::mem::take(&mut cmdbuf),
+                        }),
+                        span: $span,
+                    });
+                }
+                Strictness::ReportOnly => errors.push($err),
+            }
+        };
+    }
+    for (pos, &b) in stream.iter().enumerate() {
+        if let Status::Init = status {
+            start = pos;
+            cmdbuf.clear();
+        }
+        cmdbuf.push(b);
+        let span = span_of(start..pos + 1);
+        macro_rules! push {
+            ($tok:expr) => {
+                tokens.push(Spanned { inner: $tok, span })
+            };
+        }
+        match &mut status {
+            Status::Init if dialect.is_lead_byte(b) => status = Status::WideChar { lead: b },
+            Status::Init => match dialect.control(b) {
+                Some(Control::Sparkly) => push!(Token::Event(Event::Sparkly)),
+                Some(Control::Style) => status = Status::Style,
+                Some(Control::Space) => push!(Token::Event(Event::Space)),
+                Some(Control::FullSpace) => push!(Token::Event(Event::FullSpace)),
+                Some(Control::HalfSpace) => push!(Token::Event(Event::HalfSpace)),
+                Some(Control::SaveColor) => push!(Token::Event(Event::SaveColor)),
+                Some(Control::Linebreak) => push!(Token::Event(Event::Linebreak)),
+                Some(Control::Bell) => push!(Token::Event(Event::Bell)),
+                Some(Control::Delay) => status = Status::Delay,
+                Some(Control::TableSwitch(table)) => {
+                    lookup_table = table;
+                    push!(Token::TableSwitch(lookup_table));
+                }
+                Some(Control::NextBubble) => push!(Token::Event(Event::NextBubble)),
+                Some(Control::End) => {
+                    push!(Token::Event(Event::End));
+                    if opts.stop_at_end {
+                        break;
                     }
-                    0xFB => {
-                        flushbuf!();
-                        events.push(Event::NextBubble);
+                }
+                Some(Control::ExtCmd) => status = Status::ExtCmd,
+                None => {
+                    if lookup_table == LookupTable::Button {
+                        push!(Token::Event(Event::ButtonRef {
+                            button: dialect.button(b),
+                            rawcode: b,
+                        }));
+                        continue;
                     }
-                    0xFD => {
-                        flushbuf!();
-                        events.push(Event::End);
+                    let mut ch = dialect.char(lookup_table, b);
+                    // Checked last, because finding out is slow
+                    if ch.is_some()
+                        && opts.glyphs != GlyphMode::Approximate
+                        && dialect.is_approximate(lookup_table, b)
+                    {
+                        ch = match opts.glyphs {
+                            GlyphMode::PrivateUse => private_use(lookup_table, b),
+                            _ => None,
+                        };
                     }
-                    0xFF => {
-                        flushbuf!();
-                        status = Status::ExtCmd;
+                    push!(Token::Char {
+                        table: lookup_table,
+                        code: b,
+                        ch,
+                    });
+                }
+            },
+            Status::Style => match Style::try_from(b) {
+                Ok(style) => match dialect.style_params(style) {
+                    0 => {
+                        push!(Token::Event(Event::StyleChange(style)));
+                        status = Status::Init;
                     }
-                    _ => 'nospecial: {
-                        let (kind, opt_ch) = match lookup_table {
-                            LookupTable::Kana => ("kana", charsets::kana(b)),
-                            LookupTable::Kanji => ("kanji", charsets::kanji(b)),
-                            LookupTable::Latin => ("latin", charsets::latin(b)),
-                            LookupTable::Button => {
-                                events.push(Event::ButtonRef {
-                                    button: charsets::button(b),
-                                    rawcode: b,
-                                });
-                                break 'nospecial;
-                            }
+                    argc => {
+                        status = Status::StyleParams {
+                            style,
+                            argc,
+                            argidx: 0,
                         };
-                        match opt_ch {
-                            Some(ch) => buf.push(ch),
-                            None => buf.push_str(&format!("{{{kind}:{b:02X}}}")),
-                        }
+                        argbuf.clear();
                     }
                 },
-                Status::Style => match Style::try_from(b) {
-                    Ok(s) => {
-                        events.push(Event::StyleChange(s));
+                Err(_) => {
+                    let err = DecodeError::InvalidStyle {
+                        offset: span_of(pos..pos + 1).offset,
+                        state: status,
+                        byte: b,
+                    };
+                    // Resynchronise by skipping the bad style byte
+                    status = Status::Init;
+                    fail!(err.clone(), span);
+                }
+            },
+            Status::StyleParams {
+                style,
+                argc,
+                argidx,
+            } => {
+                argbuf.push(b);
+                if *argidx + 1 == *argc {
+                    push!(Token::Event(Event::StyleWithParams {
+                        style: *style,
+                        params: std::mem::take(&mut argbuf),
+                    }));
+                    status = Status::Init;
+                } else {
+                    *argidx += 1;
+                }
+            }
+            Status::Delay => {
+                push!(Token::Event(Event::Delay(b)));
+                status = Status::Init;
+            }
+            Status::WideChar { lead } => {
+                let code = u16::from_be_bytes([*lead, b]);
+                push!(Token::WideChar {
+                    code,
+                    ch: dialect.wide_char(code),
+                });
+                status = Status::Init;
+            }
+            Status::ExtCmd => match dialect.ext_params(b) {
+                Some(argc) => {
+                    let argc = match argc {
+                        0 => dialect.ext_extra_params(b, &[]),
+                        argc => argc,
+                    };
+                    if argc == 0 {
+                        push!(Token::Event(Event::ExtCmd(ExtCmd::new(b, &[]))));
                         status = Status::Init;
+                    } else {
+                        status = Status::ExtCmdParams {
+                            argc,
+                            argidx: 0,
+                            id: b,
+                        };
+                        argbuf.clear();
                     }
-                    Err(e) => return Err(e.to_string()),
-                },
-                Status::Delay => {
-                    events.push(Event::Delay(b));
+                }
+                None if opts.allow_unknown_commands => {
+                    push!(Token::Event(Event::ExtCmd(ExtCmd::Unknown(UnkCmd(b)))));
                     status = Status::Init;
                 }
-                Status::ExtCmd => match extcmd::n_params(b) {
-                    Some(argc) => {
-                        if argc == 0 {
-                            match extcmd::ExtCmd::from_id_and_args(b, &[]) {
-                                Some(extcmd) => {
-                                    events.push(Event::ExtCmd(extcmd));
-                                }
-                                None => events.push(Event::ExtCmdError {
-                                    id: b,
-                                    argc: 0,
-                                    args_got: 0,
-                                }),
-                            }
+                None => {
+                    let err = DecodeError::UnknownCommand {
+                        offset: span_of(pos..pos + 1).offset,
+                        state: status,
+                        id: b,
+                    };
+                    // The parameter count is unknown, so assume there are none
+                    status = Status::Init;
+                    fail!(err.clone(), span);
+                }
+            },
+            Status::ExtCmdParams { id, argc, argidx } => {
+                argbuf.push(b);
+                if (*argidx + 1) == *argc {
+                    // Some commands read more parameters depending on the ones before
+                    match dialect.ext_extra_params(*id, &argbuf) {
+                        0 => {
+                            push!(Token::Event(Event::ExtCmd(ExtCmd::new(*id, &argbuf))));
                             status = Status::Init;
-                        } else {
-                            status = Status::ExtCmdParams {
-                                argc,
-                                argidx: 0,
-                                id: b,
-                            };
-                            argbuf.clear();
                         }
-                    }
-                    None => {
-                        events.push(Event::ExtCmd(extcmd::ExtCmd::Unknown(UnkCmd(b))));
-                        status = Status::Init;
-                    }
-                },
-                Status::ExtCmdParams { id, argc, argidx } => {
-                    argbuf.push(b);
-                    if (*argidx + 1) == *argc {
-                        match extcmd::ExtCmd::from_id_and_args(*id, &argbuf) {
-                            Some(extcmd) => {
-                                events.push(Event::ExtCmd(extcmd));
-                            }
-                            None => events.push(Event::ExtCmdError {
-                                id: *id,
-                                argc: *argc,
-                                args_got: argbuf.len() as u8,
-                            }),
+                        more => {
+                            *argc += more;
+                            *argidx += 1;
                         }
-                        status = Status::Init;
-                    } else {
-                        *argidx += 1;
                     }
+                } else {
+                    *argidx += 1;
                 }
             }
         }
     }
-    flushbuf!();
-    Ok(events)
+    let offset = span_of(start..start + 1).offset;
+    let span = span_of(start..stream.len());
+    let truncated = match status {
+        Status::Init => None,
+        Status::Style | Status::StyleParams { .. } => Some(DecodeError::TruncatedStyle {
+            offset,
+            state: status,
+        }),
+        Status::Delay => Some(DecodeError::TruncatedDelay {
+            offset,
+            state: status,
+        }),
+        Status::WideChar { .. } => Some(DecodeError::TruncatedChar {
+            offset,
+            state: status,
+        }),
+        Status::ExtCmd | Status::ExtCmdParams { .. } => Some(DecodeError::TruncatedExtCmd {
+            offset,
+            state: status,
+        }),
+    };
+    if let Some(err) = truncated {
+        fail!(err.clone(), span);
+    }
+    if !opts.allow_unterminated
+        && !tokens
+            .iter()
+            .any(|tok| tok.inner == Token::Event(Event::End))
+    {
+        let end = span_of(0..stream.len()).range().end;
+        let err = DecodeError::MissingTerminator {
+            offset: end,
+            state: status,
+        };
+        let span = Span {
+            offset: end,
+            len: 0,
+        };
+        cmdbuf.clear();
+        fail!(err.clone(), span);
+    }
+    Ok(Decoded {
+        items: tokens,
+        errors,
+    })
+}
+
+#[test]
+fn test_spans() {
+    // Stream order: FC 01 00 01 | F2 05 FD
+    let raw = [0x01, 0x00, 0x01, 0xFC, 0xFD, 0x05, 0xF2];
+    let events = translate_spanned(&raw).unwrap();
+    let spans: Vec<_> = events.iter().map(|ev| ev.span.range()).collect();
+    assert_eq!(spans, [2..4, 0..2, 5..7, 4..5]);
+    let mapped = to_string_mapped(&raw).unwrap();
+    assert_eq!(mapped.text, "あい");
+    assert_eq!(mapped.spans[1], Span { offset: 0, len: 1 });
+    assert_eq!(mapped.char_at_offset(1), Some(0));
+}
+
+#[test]
+fn test_decode_errors() {
+    let err =
+        |stream: &[u8]| translate_with(&swap_words(stream), &DecodeOptions::default()).unwrap_err();
+    assert_matches!(
+        err(&[0x00, 0xFC, 0x42, 0xFD]),
+        DecodeError::InvalidStyle {
+            offset: 1,
+            state: Status::Style,
+            byte: 0x42
+        }
+    );
+    assert_matches!(
+        err(&[0x00, 0xFF, 0x77, 0xFD]),
+        DecodeError::UnknownCommand {
+            offset: 1,
+            id: 0x77,
+            ..
+        }
+    );
+    assert_matches!(
+        err(&[0x00, 0x00, 0x00, 0xFD, 0xFF, 0x18, 1]),
+        DecodeError::TruncatedExtCmd {
+            offset: 6,
+            state: Status::ExtCmdParams {
+                id: 0x18,
+                argc: 7,
+                argidx: 1
+            }
+        }
+    );
+    assert_matches!(
+        err(&[0xFD, 0xF2]),
+        DecodeError::TruncatedDelay { offset: 0, .. }
+    );
+    assert_matches!(
+        err(&[0x00, 0x01]),
+        DecodeError::MissingTerminator { offset: 2, .. }
+    );
+}
+
+#[test]
+fn test_tolerant() {
+    // Unknown command, no terminator
+    let raw = swap_words(&[0x00, 0xFF, 0x77, 0x01]);
+    assert_eq!(
+        translate(&raw).unwrap(),
+        [
+            Event::Dialog("あ".into()),
+            Event::ExtCmd(ExtCmd::Unknown(UnkCmd(0x77))),
+            Event::Dialog("い".into()),
+        ]
+    );
+    assert_eq!(to_string(&raw).unwrap(), "あ[ext:0x77]い");
+    assert_matches!(
+        translate(&swap_words(&[0xFC, 0x42, 0xFD])),
+        Err(DecodeError::InvalidStyle { .. })
+    );
+}
+
+#[test]
+fn test_strictness() {
+    // Bad style, unknown command, no terminator
+    let raw = swap_words(&[0x00, 0xFC, 0x42, 0x01, 0xFF, 0x77, 0x02]);
+    let with = |strictness| {
+        translate_with(
+            &raw,
+            &DecodeOptions {
+                strictness,
+                ..Default::default()
+            },
+        )
+    };
+    assert_matches!(
+        with(Strictness::Strict),
+        Err(DecodeError::InvalidStyle { .. })
+    );
+    let lenient = with(Strictness::Lenient).unwrap();
+    assert_eq!(lenient.errors.len(), 3);
+    let events: Vec<_> = lenient.items.into_iter().map(|ev| ev.inner).collect();
+    assert_matches!(
+        &events[..],
+        [
+            Event::Dialog(_),
+            Event::Error { raw: style, .. },
+            Event::Dialog(_),
+            Event::Error { raw: cmd, .. },
+            Event::Dialog(_),
+            Event::Error { raw: end, .. },
+        ] if style == &[0xFC, 0x42] && cmd == &[0xFF, 0x77] && end.is_empty()
+    );
+    assert_eq!(encode_events(&events).unwrap(), raw);
+    let report = with(Strictness::ReportOnly).unwrap();
+    assert_eq!(report.errors.len(), 3);
+    let events: Vec<_> = report.items.into_iter().map(|ev| ev.inner).collect();
+    // Like the bad style byte, the unknown command is left out
+    assert_eq!(events, [Event::Dialog("あいう".into())]);
+}
+
+#[test]
+fn test_charset() {
+    let mut charset = Charset::default();
+    charset.load_tbl(LookupTable::Kana, "00=X\n").unwrap();
+    let opts = DecodeOptions {
+        dialect: Some(Arc::new(MarioStory::with_charset(charset))),
+        ..Default::default()
+    };
+    let raw = swap_words(&[0x00, 0x01, 0xFD]);
+    assert_eq!(to_string(&raw).unwrap(), "あい");
+    assert_eq!(to_string_with(&raw, &opts).unwrap(), "X[raw:kana:01]");
+    let imm = swap_words(&[0x00, 0xFB]);
+    let events = imm::decode_events_with(&imm, &opts).unwrap().items;
+    assert_eq!(events[0].inner, imm::Event::Char('X'));
+}
+
+#[test]
+fn test_flag_unverified() {
+    let opts = DecodeOptions {
+        flag_unverified: true,
+        ..Default::default()
+    };
+    let raw = swap_words(&[0x4C, 0x00, 0xFD]);
+    let events: Vec<Event> = translate_with(&raw, &opts)
+        .unwrap()
+        .items
+        .into_iter()
+        .map(|ev| ev.inner)
+        .collect();
+    assert_eq!(
+        events[0],
+        Event::Unverified {
+            table: LookupTable::Kana,
+            code: 0x4C,
+            ch: 'ょ'
+        }
+    );
+    let text = markup::print(&events);
+    // Nothing is verified, so every character is flagged
+    assert_eq!(text, "[check:kana:4C:ょ][check:kana:00:あ][end]");
+    assert_eq!(markup::parse(&text).unwrap(), events);
+    assert_eq!(encode(&text).unwrap(), raw);
+    assert_eq!(to_string(&raw).unwrap(), "ょあ");
 }
@@ -0,0 +1,511 @@
+//! Text markup for dialog.
+//!
+//! [`parse`] turns marked up text into events for the encoder, and [`print`] turns decoded
+//! events back into marked up text. Printing and parsing gives back the same events, so
+//! text survives a round trip through bytes unchanged.
+//!
+//! Plain text is encoded as-is. A newline is a linebreak, and an ideographic space (U+3000)
+//! is a space, so decoders keep a character that decodes to one as a `raw` tag. The
+//! Private Use Area characters of [`GlyphMode::PrivateUse`](crate::GlyphMode) encode to
+//! the code they stand for, if it's one that [`font_mapping`](crate::font_mapping) lists.
+//! Control codes are written as bracketed tags:
+//!
+//! | Tag                           | Event                           |
+//! |-------------------------------|---------------------------------|
+//! | `[style:BubbleLeft]`          | [`Event::StyleChange`]          |
+//! | `[style:BlueMessage:40]`      | [`Event::StyleWithParams`]      |
+//! | `[full_space]`                | [`Event::FullSpace`]            |
+//! | `[half_space]`                | [`Event::HalfSpace`]            |
+//! | `[push_color]`                | [`Event::SaveColor`]            |
+//! | `[delay:10]`                  | [`Event::Delay`]                |
+//! | `[bell]`                      | [`Event::Bell`]                 |
+//! | `[sparkly]`                   | [`Event::Sparkly`]              |
+//! | `[next]`                      | [`Event::NextBubble`]           |
+//! | `[end]`                       | [`Event::End`]                  |
+//! | `[A]`, `[btn:A]`, `[btn:3]`   | [`Event::ButtonRef`]            |
+//! | `[color:5]`, `[size:8,8]`, …  | [`Event::ExtCmd`], see below    |
+//! | `[ext:0x13:5]`                | [`Event::ExtCmd`] by id         |
+//! | `[ext:0x10:0,40]`, `[ext:0x04:]` | [`ExtCmd::Other`]            |
+//! | `[raw:kanji:7F]`              | [`Event::RawChar`]              |
+//! | `[raw:wide:8140]`             | [`Event::RawWideChar`]          |
+//! | `[check:kana:4C:ょ]`          | [`Event::Unverified`]           |
+//! | `[bytes:FF 0D 08]`            | [`Event::Error`]                |
+//! | `[ext_error:0x18:7:3]`        | [`Event::ExtCmdError`]          |
+//!
+//! Ext commands with a known purpose have names:
+//!
+//! | Tag                   | Command                      |
+//! |-----------------------|------------------------------|
+//! | `[color:c]`           | [`ExtCmd::TextColor`]        |
+//! | `[scroll:n]`          | [`ExtCmd::AutoScroll`]       |
+//! | `[size:x,y]`          | [`ExtCmd::FontSize`]         |
+//! | `[size_reset]`        | [`ExtCmd::FontSizeReset`]    |
+//! | `[image:a,b,c,d,e,f,g]` | [`ExtCmd::GraphicsB`]      |
+//! | `[save_color]`        | [`ExtCmd::SaveTextColor`]    |
+//! | `[restore_color]`     | [`ExtCmd::LoadTextColor`]    |
+//! | `[fx:id]`             | [`ExtCmd::StartEffect`]      |
+//! | `[end_fx:id]`         | [`ExtCmd::EndEffect`]        |
+//! | `[voice:n]`           | [`ExtCmd::Voice`]            |
+//!
+//! Ext commands of other dialects that Mario Story doesn't have, or that take other
+//! parameters than the named one, are [`ExtCmd::Other`]. They always have a parameter list,
+//! empty for none like `[ext:0x04:]`, and the encoder checks it against the dialect.
+//!
+//! Numbers are decimal, or hexadecimal with a `0x` prefix. The character codes of `raw`
+//! and the bytes of `bytes` are always hexadecimal. A literal `[` is written as `[[`.
+//!
+//! `bytes` keeps malformed input that lenient decoding skipped. The error it was reported
+//! as is not part of the markup, it is found again when decoding the bytes.
+//! `ext_error` can be parsed, but not encoded, because the command's arguments are lost.
+
+use crate::{charsets, Button, DecodeError, Event, ExtCmd, LookupTable, Status, Style, UnkCmd};
+
+/// Names of the ext commands that have one
+const EXT_NAMES: &[(u8, &str)] = &[
+    (0x05, "color"),
+    (0x0C, "scroll"),
+    (0x0D, "size"),
+    (0x0E, "size_reset"),
+    (0x18, "image"),
+    (0x24, "save_color"),
+    (0x25, "restore_color"),
+    (0x26, "fx"),
+    (0x27, "end_fx"),
+    (0x2F, "voice"),
+];
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ParseError {
+    /// A `[` without a matching `]`
+    UnterminatedTag { pos: usize },
+    /// Tag name that isn't recognized
+    UnknownTag { pos: usize, tag: String },
+    /// Tag is known, but its arguments are invalid
+    InvalidArgument { pos: usize, tag: String },
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnterminatedTag { pos } => write!(f, "unterminated tag at {pos}"),
+            Self::UnknownTag { pos, tag } => write!(f, "unknown tag `{tag}` at {pos}"),
+            Self::InvalidArgument { pos, tag } => {
+                write!(f, "invalid argument for tag `{tag}` at {pos}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// How [`Event::Space`] is written, an ideographic space
+pub(crate) const SPACE: char = '\u{3000}';
+
+/// Parse marked up text into dialog events
+pub fn parse(text: &str) -> Result<Vec<Event>, ParseError> {
+    let mut events = Vec::new();
+    let mut buf = String::new();
+    let mut chars = text.char_indices().peekable();
+    macro_rules! flushbuf {
+        () => {
+            if !buf.is_empty() {
+                events.push(Event::Dialog(std::mem::take(&mut buf)));
+            }
+        };
+    }
+    while let Some((pos, ch)) = chars.next() {
+        match ch {
+            '\n' => {
+                flushbuf!();
+                events.push(Event::Linebreak);
+            }
+            SPACE => {
+                flushbuf!();
+                events.push(Event::Space);
+            }
+            '[' => {
+                if chars.next_if(|&(_, ch)| ch == '[').is_some() {
+                    buf.push('[');
+                    continue;
+                }
+                let Some(len) = text[pos..].find(']') else {
+                    return Err(ParseError::UnterminatedTag { pos });
+                };
+                let tag = &text[pos + 1..pos + len];
+                // Skip over the tag contents and the closing bracket
+                while chars.next_if(|&(i, _)| i <= pos + len).is_some() {}
+                flushbuf!();
+                events.push(parse_tag(tag, pos)?);
+            }
+            _ => buf.push(ch),
+        }
+    }
+    flushbuf!();
+    Ok(events)
+}
+
+/// Print events as marked up text. This is the inverse of [`parse`].
+pub fn print(events: &[Event]) -> String {
+    let mut s = String::new();
+    for event in events {
+        write_event(event, &mut s);
+    }
+    s
+}
+
+/// Append the markup for a single event to `s`
+pub(crate) fn write_event(event: &Event, s: &mut String) {
+    match event {
+        Event::StyleChange(style) => s.push_str(&format!("[style:{style:?}]")),
+        Event::StyleWithParams { style, params } => {
+            let params: Vec<String> = params.iter().map(u8::to_string).collect();
+            s.push_str(&format!("[style:{style:?}:{}]", params.join(",")));
+        }
+        Event::Space => s.push(SPACE),
+        Event::FullSpace => s.push_str("[full_space]"),
+        Event::HalfSpace => s.push_str("[half_space]"),
+        Event::SaveColor => s.push_str("[push_color]"),
+        Event::Dialog(text) => s.push_str(&text.replace('[', "[[")),
+        Event::End => s.push_str("[end]"),
+        Event::Linebreak => s.push('\n'),
+        Event::Delay(amount) => s.push_str(&format!("[delay:{amount}]")),
+        Event::Bell => s.push_str("[bell]"),
+        Event::NextBubble => s.push_str("[next]"),
+        Event::Sparkly => s.push_str("[sparkly]"),
+        Event::ButtonRef { button, rawcode } => match button {
+            Some(button) if button.code() == *rawcode => s.push_str(button.label()),
+            _ => s.push_str(&format!("[btn:{rawcode}]")),
+        },
+        Event::ExtCmd(cmd) => {
+            match EXT_NAMES.iter().find(|(id, _)| *id == cmd.id()) {
+                Some((_, name)) => s.push_str(&format!("[{name}")),
+                None => s.push_str(&format!("[ext:0x{:02X}", cmd.id())),
+            }
+            let args: Vec<String> = cmd.args().iter().map(u8::to_string).collect();
+            // An empty parameter list tells commands without parameters from unknown ones
+            if !args.is_empty() || matches!(cmd, ExtCmd::Other { .. }) {
+                s.push(':');
+                s.push_str(&args.join(","));
+            }
+            s.push(']');
+        }
+        Event::ExtCmdError { id, argc, args_got } => {
+            s.push_str(&format!("[ext_error:0x{id:02X}:{argc}:{args_got}]"));
+        }
+        Event::RawChar { table, code } => write_raw_char(*table, *code, s),
+        Event::RawWideChar { code } => s.push_str(&format!("[raw:wide:{code:04X}]")),
+        Event::Unverified { table, code, ch } => {
+            s.push_str(&format!("[check:{}:{code:02X}:{ch}]", table_name(*table)))
+        }
+        Event::Error { raw, .. } => {
+            // Nothing was skipped, like for a missing terminator
+            if raw.is_empty() {
+                return;
+            }
+            let bytes: Vec<String> = raw.iter().map(|b| format!("{b:02X}")).collect();
+            s.push_str(&format!("[bytes:{}]", bytes.join(" ")));
+        }
+    }
+}
+
+/// Append the markup for a character code with no known character to `s`
+pub(crate) fn write_raw_char(table: LookupTable, code: u8, s: &mut String) {
+    s.push_str(&format!("[raw:{}:{code:02X}]", table_name(table)));
+}
+
+pub(crate) fn table_name(table: LookupTable) -> &'static str {
+    match table {
+        LookupTable::Kana => "kana",
+        LookupTable::Kanji => "kanji",
+        LookupTable::Latin => "latin",
+        LookupTable::Button => "button",
+    }
+}
+
+fn parse_table(name: &str) -> Option<LookupTable> {
+    [LookupTable::Kana, LookupTable::Kanji, LookupTable::Latin]
+        .into_iter()
+        .find(|&t| table_name(t) == name)
+}
+
+fn parse_tag(tag: &str, pos: usize) -> Result<Event, ParseError> {
+    let invalid = || ParseError::InvalidArgument {
+        pos,
+        tag: tag.to_owned(),
+    };
+    if let Some(button) = Button::from_label(&format!("[{tag}]")) {
+        return Ok(Event::ButtonRef {
+            button: Some(button),
+            rawcode: button.code(),
+        });
+    }
+    let (name, args) = match tag.split_once(':') {
+        Some((name, args)) => (name, Some(args)),
+        None => (tag, None),
+    };
+    let event = match (name, args) {
+        ("bell", None) => Event::Bell,
+        ("sparkly", None) => Event::Sparkly,
+        ("next", None) => Event::NextBubble,
+        ("end", None) => Event::End,
+        ("full_space", None) => Event::FullSpace,
+        ("half_space", None) => Event::HalfSpace,
+        ("push_color", None) => Event::SaveColor,
+        ("delay", Some(arg)) => Event::Delay(parse_num(arg).ok_or_else(invalid)?),
+        ("style", Some(args)) => match args.split_once(':') {
+            Some((style, params)) => Event::StyleWithParams {
+                style: parse_style(style).ok_or_else(invalid)?,
+                params: params
+                    .split(',')
+                    .map(parse_num)
+                    .collect::<Option<_>>()
+                    .ok_or_else(invalid)?,
+            },
+            None => Event::StyleChange(parse_style(args).ok_or_else(invalid)?),
+        },
+        ("btn", Some(arg)) => parse_button(arg).ok_or_else(invalid)?,
+        ("ext", Some(args)) => {
+            let (id, params) = match args.split_once(':') {
+                Some((id, params)) => (id, Some(params)),
+                None => (args, None),
+            };
+            let id = parse_num(id).ok_or_else(invalid)?;
+            Event::ExtCmd(parse_extcmd(id, params).ok_or_else(invalid)?)
+        }
+        ("ext_error", Some(args)) => {
+            let nums: Option<Vec<u8>> = args.split(':').map(parse_num).collect();
+            match nums.as_deref() {
+                Some(&[id, argc, args_got]) => Event::ExtCmdError { id, argc, args_got },
+                _ => return Err(invalid()),
+            }
+        }
+        ("raw", Some(args)) => {
+            let (table, code) = args.split_once(':').ok_or_else(invalid)?;
+            if table == "wide" {
+                let code = u16::from_str_radix(code.trim(), 16).map_err(|_| invalid())?;
+                return Ok(Event::RawWideChar { code });
+            }
+            let table = parse_table(table).ok_or_else(invalid)?;
+            Event::RawChar {
+                table,
+                code: parse_hex(code).ok_or_else(invalid)?,
+            }
+        }
+        ("check", Some(args)) => {
+            let mut parts = args.splitn(3, ':');
+            let (Some(table), Some(code), Some(ch)) = (parts.next(), parts.next(), parts.next())
+            else {
+                return Err(invalid());
+            };
+            let mut chars = ch.chars();
+            let (Some(ch), None) = (chars.next(), chars.next()) else {
+                return Err(invalid());
+            };
+            Event::Unverified {
+                table: parse_table(table).ok_or_else(invalid)?,
+                code: parse_hex(code).ok_or_else(invalid)?,
+                ch,
+            }
+        }
+        ("bytes", Some(args)) => {
+            let raw: Vec<u8> = args
+                .split_whitespace()
+                .map(parse_hex)
+                .collect::<Option<_>>()
+                .filter(|raw: &Vec<u8>| !raw.is_empty())
+                .ok_or_else(invalid)?;
+            Event::Error {
+                error: error_for_bytes(&raw),
+                raw,
+            }
+        }
+        _ => match EXT_NAMES.iter().find(|(_, ext_name)| *ext_name == name) {
+            Some(&(id, _)) => Event::ExtCmd(parse_extcmd(id, args).ok_or_else(invalid)?),
+            None => {
+                return Err(ParseError::UnknownTag {
+                    pos,
+                    tag: tag.to_owned(),
+                })
+            }
+        },
+    };
+    Ok(event)
+}
+
+/// The error lenient decoding reports for skipped bytes on their own
+fn error_for_bytes(raw: &[u8]) -> DecodeError {
+    let opts = crate::DecodeOptions {
+        strictness: crate::Strictness::Lenient,
+        stop_at_end: false,
+        ..Default::default()
+    };
+    let span_of = |r: std::ops::Range<usize>| crate::Span {
+        offset: r.start,
+        len: r.len(),
+    };
+    crate::tokenize_stream(raw, span_of, &opts)
+        .ok()
+        .and_then(|decoded| decoded.errors.into_iter().next())
+        .unwrap_or(DecodeError::MissingTerminator {
+            offset: raw.len(),
+            state: Status::Init,
+        })
+}
+
+fn parse_button(arg: &str) -> Option<Event> {
+    let named = [
+        Button::A,
+        Button::B,
+        Button::Start,
+        Button::CDown,
+        Button::CLeft,
+        Button::Z,
+    ]
+    .into_iter()
+    .find(|button| format!("{button:?}") == arg);
+    if let Some(button) = named {
+        return Some(Event::ButtonRef {
+            button: Some(button),
+            rawcode: button.code(),
+        });
+    }
+    let rawcode = parse_num(arg)?;
+    Some(Event::ButtonRef {
+        button: charsets::button(rawcode),
+        rawcode,
+    })
+}
+
+fn parse_extcmd(id: u8, params: Option<&str>) -> Option<ExtCmd> {
+    let args: Vec<u8> = match params {
+        Some("") | None => Vec::new(),
+        Some(params) => params.split(',').map(parse_num).collect::<Option<_>>()?,
+    };
+    match (crate::extcmd::n_params(id), params) {
+        (Some(argc), _) if usize::from(argc) == args.len() => ExtCmd::from_id_and_args(id, &args),
+        (None, None) => Some(ExtCmd::Unknown(UnkCmd(id))),
+        // Parameters the named command doesn't take, which the dialect checks when encoding
+        (_, Some(_)) => Some(ExtCmd::new(id, &args)),
+        (Some(_), None) => None,
+    }
+}
+
+fn parse_style(arg: &str) -> Option<Style> {
+    if let Some(num) = parse_num(arg) {
+        return Style::try_from(num).ok();
+    }
+    (0x00..=0xFF)
+        .filter_map(|b| Style::try_from(b).ok())
+        .find(|style| format!("{style:?}") == arg)
+}
+
+fn parse_num(s: &str) -> Option<u8> {
+    let s = s.trim();
+    match s.strip_prefix("0x") {
+        Some(hex) => u8::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+fn parse_hex(s: &str) -> Option<u8> {
+    let s = s.trim();
+    u8::from_str_radix(s.strip_prefix("0x").unwrap_or(s), 16).ok()
+}
+
+#[test]
+fn test_parse() {
+    assert_eq!(
+        parse("[style:BubbleLeft]あ[[い\n[A][delay:0x10][ext:0x0D:8,8][end]"),
+        Ok(vec![
+            Event::StyleChange(Style::BubbleLeft),
+            Event::Dialog("あ[い".into()),
+            Event::Linebreak,
+            Event::ButtonRef {
+                button: Some(Button::A),
+                rawcode: 0
+            },
+            Event::Delay(0x10),
+            Event::ExtCmd(ExtCmd::FontSize { x: 8, y: 8 }),
+            Event::End,
+        ])
+    );
+    assert_eq!(
+        parse("[ext:0x0D]"),
+        Err(ParseError::InvalidArgument {
+            pos: 0,
+            tag: "ext:0x0D".into()
+        })
+    );
+    // The wrong number of parameters is only known to be wrong by the dialect
+    assert_eq!(
+        parse("[ext:0x0D:8][ext:0x04:]"),
+        Ok(vec![
+            Event::ExtCmd(ExtCmd::Other {
+                id: 0x0D,
+                args: vec![8]
+            }),
+            Event::ExtCmd(ExtCmd::Other {
+                id: 0x04,
+                args: vec![]
+            }),
+        ])
+    );
+    assert_eq!(
+        crate::encode("[ext:0x0D:8]"),
+        Err(crate::EncodeError::ExtParams {
+            id: 0x0D,
+            expected: 2
+        })
+    );
+    assert_eq!(
+        parse("あ[next"),
+        Err(ParseError::UnterminatedTag { pos: 3 })
+    );
+    assert_eq!(
+        parse("[color:5][btn:Z][raw:kanji:7F][raw:wide:8140]"),
+        Ok(vec![
+            Event::ExtCmd(ExtCmd::TextColor { c: 5 }),
+            Event::ButtonRef {
+                button: Some(Button::Z),
+                rawcode: 7
+            },
+            Event::RawChar {
+                table: LookupTable::Kanji,
+                code: 0x7F
+            },
+            Event::RawWideChar { code: 0x8140 },
+        ])
+    );
+}
+
+#[test]
+fn test_print_roundtrip() {
+    assert_eq!(print(&parse("あ[[い").unwrap()), "あ[[い");
+    let text = "[style:SignPost]あい[raw:kana:EE]\u{3000}[A][btn:3]\n[delay:16][bell][sparkly]\
+                [color:5][size:8,8][size_reset][image:1,2,3,4,5,6,7][save_color][restore_color]\
+                [fx:2][end_fx:2][scroll:1][voice:3][ext:0x08][ext:0x13:5][ext:0x14:1][ext:0x29:9]\
+                [next][end]";
+    let events = parse(text).unwrap();
+    assert_eq!(print(&events), text);
+    let bytes = crate::encode_events(&events).unwrap();
+    assert_eq!(print(&crate::translate(&bytes).unwrap()), text);
+    // Kana F8 decodes to an ideographic space, which would be read back as a space
+    let space = crate::swap_words(&[0xF8, 0xFD]);
+    let text = print(&crate::translate(&space).unwrap());
+    assert_eq!(text, "[raw:kana:F8][end]");
+    assert_eq!(crate::encode(&text).unwrap(), space);
+    // Skipped bytes, and an unknown command, survive lenient decoding
+    let opts = crate::DecodeOptions {
+        strictness: crate::Strictness::Lenient,
+        ..Default::default()
+    };
+    let text = "あ[bytes:FC 42]い[bytes:FF 77][end]";
+    let bytes = crate::encode_events(&parse(text).unwrap()).unwrap();
+    let events: Vec<Event> = crate::translate_with(&bytes, &opts)
+        .unwrap()
+        .items
+        .into_iter()
+        .map(|ev| ev.inner)
+        .collect();
+    assert_eq!(print(&events), text);
+}
@@ -0,0 +1,532 @@
+//! The message section, where the game keeps all of its dialog.
+//!
+//! The section starts with a table of section offsets. Each of those points to a table
+//! of message offsets. All offsets are big endian `u32`s relative to the start of the
+//! message section, and both kinds of tables end with a zero entry.
+//!
+//! A message is addressed by a [`MessageId`], the section number in the upper 16 bits,
+//! and the index in the lower 16 bits.
+//!
+//! After editing, [`rebuild`] lays the whole section out again from a [`MessageSet`]
+//! and rewrites the offset tables.
+//!
+//! [`MessageTable::load`] also finds sections stored in a Yay0 block.
+
+use {
+    crate::{
+        swap_words, tokenize_stream, yay0, DecodeError, DecodeOptions, Event, Span, Strictness,
+    },
+    std::{borrow::Cow, collections::BTreeSet, ops::Range},
+};
+
+/// Identifies a message by section and index
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct MessageId(pub u32);
+
+impl MessageId {
+    pub fn new(section: u16, index: u16) -> Self {
+        Self(u32::from(section) << 16 | u32::from(index))
+    }
+    pub fn section(self) -> u16 {
+        (self.0 >> 16) as u16
+    }
+    pub fn index(self) -> u16 {
+        self.0 as u16
+    }
+}
+
+impl std::fmt::Display for MessageId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "0x{:04X}_{:04X}", self.section(), self.index())
+    }
+}
+
+impl std::str::FromStr for MessageId {
+    type Err = std::num::ParseIntError;
+
+    /// Parses the [`Display`](std::fmt::Display) format, like `0x0012_0034`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hex: String = s
+            .trim_start_matches("0x")
+            .chars()
+            .filter(|&c| c != '_')
+            .collect();
+        u32::from_str_radix(&hex, 16).map(Self)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TableError {
+    /// A table or offset points outside of the data
+    OutOfBounds { offset: usize },
+    /// The section base is not word aligned
+    Unaligned { base: usize },
+    /// No message with this id
+    NoSuchMessage(MessageId),
+    /// The message couldn't be decoded
+    Decode { id: MessageId, error: DecodeError },
+    /// The section is Yay0 compressed, and couldn't be decompressed
+    Yay0(yay0::Yay0Error),
+}
+
+impl std::fmt::Display for TableError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::OutOfBounds { offset } => write!(f, "offset 0x{offset:X} is out of bounds"),
+            Self::Unaligned { base } => write!(f, "section base 0x{base:X} is not aligned"),
+            Self::NoSuchMessage(id) => write!(f, "no message {id}"),
+            Self::Decode { id, error } => write!(f, "message {id}: {error}"),
+            Self::Yay0(e) => write!(f, "compressed section: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for TableError {}
+
+/// The layout of a message section
+#[derive(Debug, Clone)]
+pub struct MessageTable {
+    /// Stream order offset of the message section
+    pub base: usize,
+    /// Byte ranges of every message, by section and index.
+    /// The ranges are stream order offsets into the data the table was read from.
+    pub sections: Vec<Vec<Range<usize>>>,
+    /// Stream order offset of the message offset table of each section
+    pub section_tables: Vec<usize>,
+}
+
+impl MessageTable {
+    /// Read the message section starting at `base`.
+    ///
+    /// `data` is in the same word swapped order [`crate::translate`] expects, and `base`
+    /// is a stream order offset, like a z64 ROM offset.
+    pub fn read(data: &[u8], base: usize) -> Result<Self, TableError> {
+        if !base.is_multiple_of(4) {
+            return Err(TableError::Unaligned { base });
+        }
+        let stream = swap_words(data);
+        // Everything a table points to. A table never runs into any of these.
+        let mut referenced = BTreeSet::new();
+        let section_tables = read_offset_table(&stream, base, base, &mut referenced)?;
+        let mut starts = Vec::new();
+        for &table in &section_tables {
+            starts.push(read_offset_table(&stream, base, table, &mut referenced)?);
+        }
+        let decode_opts = DecodeOptions {
+            strictness: Strictness::Lenient,
+            stop_at_end: true,
+            ..Default::default()
+        };
+        let sections = starts
+            .into_iter()
+            .map(|starts| {
+                starts
+                    .into_iter()
+                    .map(|start| {
+                        // A message ends at its terminator, but never runs into other data
+                        let limit = referenced
+                            .range(start + 1..)
+                            .next()
+                            .copied()
+                            .unwrap_or(stream.len());
+                        let span_of = |r: Range<usize>| Span {
+                            offset: r.start,
+                            len: r.len(),
+                        };
+                        let len = tokenize_stream(&stream[start..limit], span_of, &decode_opts)
+                            .ok()
+                            .and_then(|tokens| Some(tokens.items.last()?.span.range().end))
+                            .unwrap_or(limit - start);
+                        start..start + len
+                    })
+                    .collect()
+            })
+            .collect();
+        Ok(Self {
+            base,
+            sections,
+            section_tables,
+        })
+    }
+    /// Like [`Self::read`], but if there is a Yay0 block at `base`, read the section
+    /// from inside of it.
+    ///
+    /// Returns the data the table refers to, which is the decompressed block in that case.
+    pub fn load(data: &[u8], base: usize) -> Result<(Self, Cow<'_, [u8]>), TableError> {
+        let is_yay0 = (0..4).all(|i| stream_byte(data, base + i) == Some(yay0::MAGIC[i]));
+        if !is_yay0 {
+            return Ok((Self::read(data, base)?, Cow::Borrowed(data)));
+        }
+        let stream = swap_words(data);
+        let block = yay0::decompress(&stream[base..]).map_err(TableError::Yay0)?;
+        let data = swap_words(&block);
+        Ok((Self::read(&data, 0)?, Cow::Owned(data)))
+    }
+
+    /// Stream order byte range of a message
+    pub fn range(&self, id: MessageId) -> Option<Range<usize>> {
+        self.sections
+            .get(usize::from(id.section()))?
+            .get(usize::from(id.index()))
+            .cloned()
+    }
+
+    /// Every message, in id order
+    pub fn messages(&self) -> impl Iterator<Item = (MessageId, Range<usize>)> + '_ {
+        self.sections.iter().enumerate().flat_map(|(sec, msgs)| {
+            msgs.iter()
+                .enumerate()
+                .map(move |(idx, range)| (MessageId::new(sec as u16, idx as u16), range.clone()))
+        })
+    }
+
+    /// The bytes of a message, in the word swapped order [`crate::translate`] expects
+    pub fn message_bytes(&self, data: &[u8], id: MessageId) -> Result<Vec<u8>, TableError> {
+        let range = self.range(id).ok_or(TableError::NoSuchMessage(id))?;
+        let stream: Vec<u8> = range
+            .clone()
+            .map(|pos| stream_byte(data, pos))
+            .collect::<Option<_>>()
+            .ok_or(TableError::OutOfBounds { offset: range.end })?;
+        Ok(swap_words(&stream))
+    }
+
+    /// [`crate::translate`] a message by id
+    pub fn translate(&self, data: &[u8], id: MessageId) -> Result<Vec<Event>, TableError> {
+        let bytes = self.message_bytes(data, id)?;
+        crate::translate(&bytes).map_err(|error| TableError::Decode { id, error })
+    }
+
+    /// [`crate::to_string`] a message by id
+    pub fn to_string(&self, data: &[u8], id: MessageId) -> Result<String, TableError> {
+        let bytes = self.message_bytes(data, id)?;
+        crate::to_string(&bytes).map_err(|error| TableError::Decode { id, error })
+    }
+
+    /// Stream order byte range taken up by the whole message section
+    pub fn extent(&self) -> Range<usize> {
+        let base_table_end = self.base + (self.section_tables.len() + 1) * 4;
+        let table_ends = self
+            .section_tables
+            .iter()
+            .zip(&self.sections)
+            .map(|(table, msgs)| table + (msgs.len() + 1) * 4);
+        let msg_ends = self.sections.iter().flatten().map(|range| range.end);
+        let end = table_ends.chain(msg_ends).fold(base_table_end, usize::max);
+        self.base..end.next_multiple_of(4)
+    }
+}
+
+/// A full set of messages to lay out into a message section
+#[derive(Debug, Clone, Default)]
+pub struct MessageSet {
+    /// The bytes of every message by section and index, in the word swapped order
+    /// [`crate::encode_events`] produces
+    pub sections: Vec<Vec<Vec<u8>>>,
+}
+
+impl MessageSet {
+    /// Take all messages from an existing message section
+    pub fn from_table(table: &MessageTable, data: &[u8]) -> Result<Self, TableError> {
+        let mut sections = Vec::new();
+        for (sec, msgs) in table.sections.iter().enumerate() {
+            let mut out = Vec::new();
+            for idx in 0..msgs.len() {
+                out.push(table.message_bytes(data, MessageId::new(sec as u16, idx as u16))?);
+            }
+            sections.push(out);
+        }
+        Ok(Self { sections })
+    }
+    /// Replace a message. Fails if there is no message with this id.
+    pub fn set(&mut self, id: MessageId, bytes: Vec<u8>) -> Result<(), TableError> {
+        let msg = self
+            .sections
+            .get_mut(usize::from(id.section()))
+            .and_then(|msgs| msgs.get_mut(usize::from(id.index())))
+            .ok_or(TableError::NoSuchMessage(id))?;
+        *msg = bytes;
+        Ok(())
+    }
+    /// Lay out the message section, in stream order.
+    ///
+    /// All offsets are relative to the section start, so the result can be placed anywhere.
+    /// Messages are padded to word alignment.
+    pub fn build(&self) -> Vec<u8> {
+        let tables_len: usize = (self.sections.len() + 1) * 4
+            + self
+                .sections
+                .iter()
+                .map(|msgs| (msgs.len() + 1) * 4)
+                .sum::<usize>();
+        let mut tables = Vec::with_capacity(tables_len);
+        let mut table_pos = (self.sections.len() + 1) * 4;
+        for msgs in &self.sections {
+            tables.extend((table_pos as u32).to_be_bytes());
+            table_pos += (msgs.len() + 1) * 4;
+        }
+        tables.extend([0; 4]);
+        let mut data = Vec::new();
+        for msgs in &self.sections {
+            for msg in msgs {
+                tables.extend(((tables_len + data.len()) as u32).to_be_bytes());
+                data.extend(swap_words(msg));
+                data.resize(data.len().next_multiple_of(4), 0);
+            }
+            tables.extend([0; 4]);
+        }
+        tables.extend(data);
+        tables
+    }
+}
+
+/// Where to write a rebuilt message section
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Placement {
+    /// Over the original section
+    InPlace,
+    /// To a free region of the ROM, given as a stream order offset and size.
+    ///
+    /// `pointer` is the stream order offset of a big endian `u32` that holds the section
+    /// start, like an entry of a DMA table. It has to hold the old start, and is updated to
+    /// `base`. Code that computes the start in other ways has to be updated separately.
+    Relocate {
+        base: usize,
+        capacity: usize,
+        pointer: Option<usize>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RebuildError {
+    /// The rebuilt section is `needed` bytes, but only `available` bytes fit
+    Overflow { needed: usize, available: usize },
+    /// The target region is not word aligned
+    Unaligned { base: usize },
+    /// The target region is outside of the data
+    OutOfBounds { end: usize },
+    /// The pointer to the section doesn't hold the section start
+    Pointer { offset: usize, found: Option<u32> },
+    /// The rebuilt section couldn't be read back
+    Table(TableError),
+}
+
+impl std::fmt::Display for RebuildError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Overflow { needed, available } => write!(
+                f,
+                "message section needs {needed} bytes, but only {available} are available"
+            ),
+            Self::Unaligned { base } => write!(f, "target 0x{base:X} is not aligned"),
+            Self::OutOfBounds { end } => write!(f, "target ends out of bounds at 0x{end:X}"),
+            Self::Pointer {
+                offset,
+                found: Some(found),
+            } => write!(
+                f,
+                "pointer at 0x{offset:X} holds 0x{found:X}, not the section start"
+            ),
+            Self::Pointer {
+                offset,
+                found: None,
+            } => write!(f, "pointer at 0x{offset:X} is out of bounds"),
+            Self::Table(e) => write!(f, "rebuilt section: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for RebuildError {}
+
+/// Rebuild the message section of `data` from `set`, and rewrite all pointer tables.
+///
+/// `table` is the section currently in `data`. Any space left over in the target region
+/// is zeroed, and the pointer of [`Placement::Relocate`] is updated. Returns the layout of
+/// the new section.
+pub fn rebuild(
+    data: &mut [u8],
+    table: &MessageTable,
+    set: &MessageSet,
+    placement: Placement,
+) -> Result<MessageTable, RebuildError> {
+    let (region, pointer) = match placement {
+        Placement::InPlace => (table.extent(), None),
+        Placement::Relocate {
+            base,
+            capacity,
+            pointer,
+        } => (base..base + capacity, pointer),
+    };
+    if !region.start.is_multiple_of(4) {
+        return Err(RebuildError::Unaligned { base: region.start });
+    }
+    if region.end > data.len() {
+        return Err(RebuildError::OutOfBounds { end: region.end });
+    }
+    let mut section = set.build();
+    if section.len() > region.len() {
+        return Err(RebuildError::Overflow {
+            needed: section.len(),
+            available: region.len(),
+        });
+    }
+    if let Some(offset) = pointer {
+        let found: Option<Vec<u8>> = (offset..offset + 4)
+            .map(|pos| stream_byte(data, pos))
+            .collect();
+        let found = found.map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap()));
+        if found != Some(table.base as u32) {
+            return Err(RebuildError::Pointer { offset, found });
+        }
+    }
+    section.resize(region.len(), 0);
+    let total = data.len();
+    for (pos, b) in region.clone().zip(section) {
+        data[crate::file_offset(pos, total)] = b;
+    }
+    if let Some(offset) = pointer {
+        for (pos, b) in (offset..).zip((region.start as u32).to_be_bytes()) {
+            data[crate::file_offset(pos, total)] = b;
+        }
+    }
+    MessageTable::read(data, region.start).map_err(RebuildError::Table)
+}
+
+/// Byte at stream order position `pos` of word swapped `data`
+fn stream_byte(data: &[u8], pos: usize) -> Option<u8> {
+    if pos >= data.len() {
+        return None;
+    }
+    data.get(crate::file_offset(pos, data.len())).copied()
+}
+
+/// Read a big endian `u32` at a stream order offset
+fn read_u32(stream: &[u8], offset: usize) -> Result<u32, TableError> {
+    let bytes = stream
+        .get(offset..offset + 4)
+        .ok_or(TableError::OutOfBounds { offset })?;
+    Ok(u32::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+/// Read a zero terminated table of offsets relative to the section base.
+///
+/// Returns absolute stream offsets, and adds them to `referenced`.
+fn read_offset_table(
+    stream: &[u8],
+    base: usize,
+    table: usize,
+    referenced: &mut BTreeSet<usize>,
+) -> Result<Vec<usize>, TableError> {
+    let mut offsets = Vec::new();
+    let mut pos = table;
+    loop {
+        let rel = read_u32(stream, pos)? as usize;
+        if rel == 0 {
+            break;
+        }
+        let abs = base + rel;
+        if abs >= stream.len() {
+            return Err(TableError::OutOfBounds { offset: abs });
+        }
+        offsets.push(abs);
+        referenced.insert(abs);
+        pos += 4;
+    }
+    Ok(offsets)
+}
+
+#[test]
+fn test_message_table() {
+    let set = MessageSet {
+        sections: vec![
+            vec![
+                swap_words(&[0xFC, 0x01, 0x00, 0xFD]),
+                swap_words(&[0x01, 0x02, 0xFD]),
+            ],
+            vec![swap_words(&[0xF4, 0x19, 0xFD])],
+        ],
+    };
+    let data = swap_words(&set.build());
+    let table = MessageTable::read(&data, 0).unwrap();
+    let id: MessageId = "0x0001_0000".parse().unwrap();
+    assert_eq!(id, MessageId::new(1, 0));
+    assert_eq!(id.to_string(), "0x0001_0000");
+    assert_eq!(table.messages().count(), 3);
+    assert_eq!(table.range(MessageId::new(0, 1)), Some(0x24..0x27));
+    assert_eq!(
+        table.to_string(&data, MessageId::new(0, 1)).unwrap(),
+        "いう"
+    );
+    assert_eq!(table.to_string(&data, id).unwrap(), "Ｚ");
+    assert_eq!(
+        table.to_string(&data, MessageId::new(1, 1)),
+        Err(TableError::NoSuchMessage(MessageId::new(1, 1)))
+    );
+}
+
+#[test]
+fn test_rebuild() {
+    let set = MessageSet {
+        sections: vec![vec![
+            crate::encode("あ").unwrap(),
+            crate::encode("い").unwrap(),
+        ]],
+    };
+    let mut data = swap_words(&set.build());
+    // Room to move the section to, and a pointer to it
+    data.resize(0x48, 0);
+    let table = MessageTable::read(&data, 0).unwrap();
+    assert_eq!(table.extent(), 0..0x1C);
+    let mut set = MessageSet::from_table(&table, &data).unwrap();
+    let id = MessageId::new(0, 1);
+    set.set(id, crate::encode("いうえ").unwrap()).unwrap();
+    let new = rebuild(&mut data, &table, &set, Placement::InPlace).unwrap();
+    assert_eq!(new.to_string(&data, id).unwrap(), "いうえ");
+    assert_eq!(new.to_string(&data, MessageId::new(0, 0)).unwrap(), "あ");
+    set.set(id, crate::encode("いうえおかき").unwrap()).unwrap();
+    assert_eq!(
+        rebuild(&mut data, &new, &set, Placement::InPlace).unwrap_err(),
+        RebuildError::Overflow {
+            needed: 0x20,
+            available: 0x1C
+        }
+    );
+    let placement = Placement::Relocate {
+        base: 0x20,
+        capacity: 0x20,
+        pointer: Some(0x44),
+    };
+    data[0x44..0x48].copy_from_slice(&[0x34, 0x12, 0x00, 0x00]);
+    let before = data.clone();
+    assert_eq!(
+        rebuild(&mut data, &new, &set, placement).unwrap_err(),
+        RebuildError::Pointer {
+            offset: 0x44,
+            found: Some(0x1234)
+        }
+    );
+    assert_eq!(data, before);
+    data[0x44..0x48].fill(0);
+    let moved = rebuild(&mut data, &new, &set, placement).unwrap();
+    assert_eq!(moved.to_string(&data, id).unwrap(), "いうえおかき");
+    assert_eq!(data[0x44..0x48], [0x20, 0x00, 0x00, 0x00]);
+}
+
+#[test]
+fn test_load_yay0() {
+    let set = MessageSet {
+        sections: vec![vec![
+            crate::encode("あ").unwrap(),
+            crate::encode("い").unwrap(),
+        ]],
+    };
+    let section = set.build();
+    let mut stream = vec![0; 8];
+    stream.extend(yay0::compress(&section));
+    let rom = swap_words(&stream);
+    let (table, data) = MessageTable::load(&rom, 8).unwrap();
+    assert_eq!(table.base, 0);
+    assert_eq!(table.to_string(&data, MessageId::new(0, 1)).unwrap(), "い");
+    let (table, _) = MessageTable::load(&swap_words(&section), 0).unwrap();
+    assert_eq!(table.messages().count(), 2);
+}
@@ -0,0 +1,650 @@
+//! Turning a modified ROM into something that boots and can be distributed.
+//!
+//! [`fix_crc`] recomputes the boot checksums in the header, which the boot code checks
+//! against the first megabyte of the program. [`create_bps`] and [`create_ips`] make
+//! patches between two ROMs, and [`apply_bps`] and [`apply_ips`] apply them.
+//!
+//! Patches work on plain bytes, so both ROMs need to be in the same byte order.
+//! Patches for N64 games are usually made against z64 order, see [`Rom::to_order`].
+//!
+//! [`build_patch`] does all of it in one go: it encodes edited messages, rebuilds the
+//! message section, fixes the checksums and creates the patch.
+
+use crate::{
+    encode_with,
+    msg::{self, MessageId, MessageSet, MessageTable, Placement, RebuildError, TableError},
+    rom::{ByteOrder, Rom},
+    swap_words, Dialect, EncodeError,
+};
+
+#[cfg(test)]
+use std::assert_matches::assert_matches;
+
+/// Start of the checksummed area
+const CHECKSUM_START: usize = 0x1000;
+/// Size of the checksummed area
+const CHECKSUM_LEN: usize = 0x10_0000;
+/// Location of the boot code
+const BOOTCODE: std::ops::Range<usize> = 0x40..0x1000;
+
+/// The checksum chip (CIC) a cartridge was made for. Each one uses a different boot code
+/// and checksum variant.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cic {
+    Cic6101,
+    Cic6102,
+    /// Paper Mario and Mario Story use this one
+    Cic6103,
+    Cic6105,
+    Cic6106,
+}
+
+impl Cic {
+    /// Detect the CIC from the CRC32 of the boot code in n64 order `data`
+    pub fn detect(data: &[u8]) -> Option<Self> {
+        let bootcode = swap_words(data.get(BOOTCODE)?);
+        match crc32(&bootcode) {
+            0x6170_A4A1 => Some(Self::Cic6101),
+            0x90BB_6CB5 => Some(Self::Cic6102),
+            0x0B05_0EE0 => Some(Self::Cic6103),
+            0x98BC_2C86 => Some(Self::Cic6105),
+            0xACC8_580A => Some(Self::Cic6106),
+            _ => None,
+        }
+    }
+    fn seed(self) -> u32 {
+        match self {
+            Self::Cic6101 | Self::Cic6102 => 0xF8CA_4DDC,
+            Self::Cic6103 => 0xA388_6759,
+            Self::Cic6105 => 0xDF26_F436,
+            Self::Cic6106 => 0x1FEA_617A,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PatchError {
+    /// The ROM is too small to be checksummed
+    TooSmall { len: usize },
+    /// The boot code doesn't match any known CIC
+    UnknownCic { crc: u32 },
+    /// The patch is malformed
+    Malformed { offset: usize },
+    /// The patch was made for a different source file
+    SourceMismatch,
+    /// The patched output doesn't match the checksum in the patch
+    TargetMismatch,
+    /// The patch itself is corrupted
+    PatchMismatch,
+    /// IPS can't address data past 16 MiB
+    IpsOutOfRange { offset: usize },
+}
+
+impl std::fmt::Display for PatchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::TooSmall { len } => write!(f, "{len} bytes is too small to checksum"),
+            Self::UnknownCic { crc } => write!(f, "unknown boot code (CRC32 {crc:08X})"),
+            Self::Malformed { offset } => write!(f, "malformed patch at offset 0x{offset:X}"),
+            Self::SourceMismatch => f.write_str("patch is for a different source file"),
+            Self::TargetMismatch => f.write_str("patched output has the wrong checksum"),
+            Self::PatchMismatch => f.write_str("patch checksum mismatch"),
+            Self::IpsOutOfRange { offset } => {
+                write!(f, "offset 0x{offset:X} is out of range for IPS")
+            }
+        }
+    }
+}
+
+impl std::error::Error for PatchError {}
+
+/// Standard (zlib) CRC32
+pub fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |crc, &b| {
+        (0..8).fold(crc ^ u32::from(b), |crc, _| {
+            (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg())
+        })
+    })
+}
+
+/// Compute the CRC1 and CRC2 header checksums of n64 order `data`
+pub fn compute_crc(data: &[u8], cic: Cic) -> Result<(u32, u32), PatchError> {
+    if data.len() < CHECKSUM_START + CHECKSUM_LEN {
+        return Err(PatchError::TooSmall { len: data.len() });
+    }
+    // In n64 order, an aligned big endian word reads as little endian
+    let word = |pos: usize| u32::from_le_bytes(data[pos..pos + 4].try_into().unwrap());
+    let seed = cic.seed();
+    let (mut t1, mut t2, mut t3, mut t4, mut t5, mut t6) = (seed, seed, seed, seed, seed, seed);
+    for pos in (CHECKSUM_START..CHECKSUM_START + CHECKSUM_LEN).step_by(4) {
+        let d = word(pos);
+        let (sum, carry) = t6.overflowing_add(d);
+        if carry {
+            t4 = t4.wrapping_add(1);
+        }
+        t6 = sum;
+        t3 ^= d;
+        let r = d.rotate_left(d & 0x1F);
+        t5 = t5.wrapping_add(r);
+        if t2 > d {
+            t2 ^= r;
+        } else {
+            t2 ^= t6 ^ d;
+        }
+        t1 = t1.wrapping_add(match cic {
+            Cic::Cic6105 => word(0x750 + (pos & 0xFF)) ^ d,
+            _ => t5 ^ d,
+        });
+    }
+    Ok(match cic {
+        Cic::Cic6103 => ((t6 ^ t4).wrapping_add(t3), (t5 ^ t2).wrapping_add(t1)),
+        Cic::Cic6106 => (
+            t6.wrapping_mul(t4).wrapping_add(t3),
+            t5.wrapping_mul(t2).wrapping_add(t1),
+        ),
+        _ => (t6 ^ t4 ^ t3, t5 ^ t2 ^ t1),
+    })
+}
+
+/// Recompute the header checksums of `rom` and write them back.
+///
+/// Returns the detected CIC.
+pub fn fix_crc(rom: &mut Rom) -> Result<Cic, PatchError> {
+    let cic = Cic::detect(&rom.data).ok_or_else(|| PatchError::UnknownCic {
+        crc: crc32(&swap_words(rom.data.get(BOOTCODE).unwrap_or_default())),
+    })?;
+    fix_crc_with(rom, cic)?;
+    Ok(cic)
+}
+
+/// Like [`fix_crc`], but for a known CIC
+pub fn fix_crc_with(rom: &mut Rom, cic: Cic) -> Result<(), PatchError> {
+    let (crc1, crc2) = compute_crc(&rom.data, cic)?;
+    rom.data[0x10..0x14].copy_from_slice(&crc1.to_le_bytes());
+    rom.data[0x14..0x18].copy_from_slice(&crc2.to_le_bytes());
+    rom.header.crc1 = crc1;
+    rom.header.crc2 = crc2;
+    Ok(())
+}
+
+/// Patch format for [`build_patch`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PatchFormat {
+    Bps,
+    Ips,
+}
+
+/// What to build with [`build_patch`]
+#[derive(Debug, Clone, Copy)]
+pub struct BuildOptions<'a> {
+    /// Text format to encode the edits with
+    pub dialect: &'a dyn Dialect,
+    /// Where to put the rebuilt message section
+    pub placement: Placement,
+    pub format: PatchFormat,
+    /// The CIC to checksum for, or `None` to detect it from the boot code
+    pub cic: Option<Cic>,
+}
+
+/// Result of [`build_patch`]
+#[derive(Debug, Clone)]
+pub struct BuildOutput {
+    /// The modified ROM, with fixed checksums
+    pub rom: Rom,
+    /// Layout of the rebuilt message section
+    pub table: MessageTable,
+    /// Patch from the original to the modified ROM, both in z64 order
+    pub patch: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum BuildError {
+    Table(TableError),
+    /// An edited message couldn't be encoded
+    Encode {
+        id: MessageId,
+        error: EncodeError,
+    },
+    Rebuild(RebuildError),
+    Patch(PatchError),
+}
+
+impl std::fmt::Display for BuildError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Table(e) => write!(f, "{e}"),
+            Self::Encode { id, error } => write!(f, "message {id}: {error}"),
+            Self::Rebuild(e) => write!(f, "{e}"),
+            Self::Patch(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+/// Replace messages of `original` with marked up text, and create a patch for the result.
+///
+/// The edits are encoded, the message section at `table` is rebuilt with them, and the
+/// checksums are fixed, before the patch is created against `original`.
+pub fn build_patch(
+    original: &Rom,
+    table: &MessageTable,
+    edits: &[(MessageId, &str)],
+    opts: &BuildOptions,
+) -> Result<BuildOutput, BuildError> {
+    let mut rom = original.clone();
+    let mut set = MessageSet::from_table(table, &rom.data).map_err(BuildError::Table)?;
+    for &(id, text) in edits {
+        let bytes =
+            encode_with(text, opts.dialect).map_err(|error| BuildError::Encode { id, error })?;
+        set.set(id, bytes).map_err(BuildError::Table)?;
+    }
+    let table =
+        msg::rebuild(&mut rom.data, table, &set, opts.placement).map_err(BuildError::Rebuild)?;
+    match opts.cic {
+        Some(cic) => fix_crc_with(&mut rom, cic),
+        None => fix_crc(&mut rom).map(|_| ()),
+    }
+    .map_err(BuildError::Patch)?;
+    let source = original.to_order(ByteOrder::Z64);
+    let target = rom.to_order(ByteOrder::Z64);
+    let patch = match opts.format {
+        PatchFormat::Bps => create_bps(&source, &target),
+        PatchFormat::Ips => create_ips(&source, &target).map_err(BuildError::Patch)?,
+    };
+    Ok(BuildOutput { rom, table, patch })
+}
+
+/// Unchanged bytes needed before a BPS patch switches back from literal data to the source
+const BPS_MIN_RUN: usize = 4;
+
+/// Create a BPS patch that turns `source` into `target`
+pub fn create_bps(source: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut out = b"BPS1".to_vec();
+    write_bps_num(&mut out, source.len() as u64);
+    write_bps_num(&mut out, target.len() as u64);
+    // No metadata
+    write_bps_num(&mut out, 0);
+    let same = |pos: usize| source.get(pos) == Some(&target[pos]);
+    let mut pos = 0;
+    while pos < target.len() {
+        let start = pos;
+        if same(pos) {
+            while pos < target.len() && same(pos) {
+                pos += 1;
+            }
+            write_bps_action(&mut out, BpsAction::SourceRead, pos - start);
+        } else {
+            // Short unchanged runs are cheaper to store as literal data than as an action
+            while pos < target.len()
+                && !(pos..pos + BPS_MIN_RUN).all(|p| p >= target.len() || same(p))
+            {
+                pos += 1;
+            }
+            write_bps_action(&mut out, BpsAction::TargetRead, pos - start);
+            out.extend_from_slice(&target[start..pos]);
+        }
+    }
+    out.extend(crc32(source).to_le_bytes());
+    out.extend(crc32(target).to_le_bytes());
+    out.extend(crc32(&out).to_le_bytes());
+    out
+}
+
+/// Apply a BPS patch to `source`.
+///
+/// All three checksums in the patch are verified.
+pub fn apply_bps(source: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let malformed = |offset| PatchError::Malformed { offset };
+    if patch.len() < 4 + 12 || &patch[..4] != b"BPS1" {
+        return Err(malformed(0));
+    }
+    let footer = patch.len() - 12;
+    let crc_at = |pos: usize| u32::from_le_bytes(patch[pos..pos + 4].try_into().unwrap());
+    if crc32(&patch[..footer + 8]) != crc_at(footer + 8) {
+        return Err(PatchError::PatchMismatch);
+    }
+    if crc32(source) != crc_at(footer) {
+        return Err(PatchError::SourceMismatch);
+    }
+    let mut reader = BpsReader {
+        data: &patch[..footer],
+        pos: 4,
+    };
+    let source_len = reader.num()?;
+    let target_len = reader.num()?;
+    if source_len != source.len() {
+        return Err(PatchError::SourceMismatch);
+    }
+    let metadata_len = reader.num()?;
+    reader.take(metadata_len)?;
+    let mut out = Vec::with_capacity(target_len);
+    let (mut source_rel, mut target_rel) = (0usize, 0usize);
+    while reader.pos < reader.data.len() {
+        let action_offset = reader.pos;
+        let action = reader.num()?;
+        let len = (action >> 2) + 1;
+        match action & 3 {
+            0 => {
+                let bytes = source
+                    .get(out.len()..out.len() + len)
+                    .ok_or(malformed(action_offset))?;
+                out.extend_from_slice(bytes);
+            }
+            1 => out.extend_from_slice(reader.take(len)?),
+            2 => {
+                source_rel = reader.relative(source_rel)?;
+                let bytes = source
+                    .get(source_rel..source_rel + len)
+                    .ok_or(malformed(action_offset))?;
+                out.extend_from_slice(bytes);
+                source_rel += len;
+            }
+            _ => {
+                target_rel = reader.relative(target_rel)?;
+                if target_rel >= out.len() {
+                    return Err(malformed(action_offset));
+                }
+                // The copy may overlap its own output, so go byte by byte
+                for i in 0..len {
+                    out.push(out[target_rel + i]);
+                }
+                target_rel += len;
+            }
+        }
+    }
+    if out.len() != target_len || crc32(&out) != crc_at(footer + 4) {
+        return Err(PatchError::TargetMismatch);
+    }
+    Ok(out)
+}
+
+#[derive(Clone, Copy)]
+enum BpsAction {
+    SourceRead = 0,
+    TargetRead = 1,
+}
+
+fn write_bps_action(out: &mut Vec<u8>, action: BpsAction, len: usize) {
+    write_bps_num(out, (((len - 1) as u64) << 2) | action as u64);
+}
+
+fn write_bps_num(out: &mut Vec<u8>, mut num: u64) {
+    loop {
+        let low = (num & 0x7F) as u8;
+        num >>= 7;
+        if num == 0 {
+            out.push(0x80 | low);
+            return;
+        }
+        out.push(low);
+        num -= 1;
+    }
+}
+
+struct BpsReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BpsReader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], PatchError> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos.saturating_add(len))
+            .ok_or(PatchError::Malformed { offset: self.pos })?;
+        self.pos += len;
+        Ok(bytes)
+    }
+    fn num(&mut self) -> Result<usize, PatchError> {
+        let start = self.pos;
+        let mut num: u64 = 0;
+        let mut shift: u64 = 1;
+        loop {
+            let b = self.take(1)?[0];
+            num = (u64::from(b & 0x7F))
+                .checked_mul(shift)
+                .and_then(|n| n.checked_add(num))
+                .ok_or(PatchError::Malformed { offset: start })?;
+            if b & 0x80 != 0 {
+                break;
+            }
+            shift = shift
+                .checked_shl(7)
+                .filter(|&s| s < 1 << 56)
+                .ok_or(PatchError::Malformed { offset: start })?;
+            num += shift;
+        }
+        usize::try_from(num).map_err(|_| PatchError::Malformed { offset: start })
+    }
+    /// Read a signed offset and apply it to `base`
+    fn relative(&mut self, base: usize) -> Result<usize, PatchError> {
+        let start = self.pos;
+        let num = self.num()?;
+        let offset = num >> 1;
+        let result = if num & 1 == 0 {
+            base.checked_add(offset)
+        } else {
+            base.checked_sub(offset)
+        };
+        result.ok_or(PatchError::Malformed { offset: start })
+    }
+}
+
+/// IPS offsets are 24 bits
+const IPS_MAX_LEN: usize = 0x100_0000;
+/// A record at this offset would read as the `EOF` marker
+const IPS_EOF_OFFSET: usize = 0x45_4F46;
+/// Longest record IPS can store
+const IPS_MAX_RECORD: usize = 0xFFFF;
+
+/// Create an IPS patch that turns `source` into `target`.
+///
+/// If `target` is shorter, the patch uses the common truncation extension.
+/// Changes past the first 16 MiB can't be expressed, use BPS for those.
+pub fn create_ips(source: &[u8], target: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let mut out = b"PATCH".to_vec();
+    let changed = |pos: usize| source.get(pos) != Some(&target[pos]);
+    let mut pos = 0;
+    while pos < target.len() {
+        if !changed(pos) {
+            pos += 1;
+            continue;
+        }
+        // Start one byte early rather than write an offset that reads as `EOF`
+        let start = if pos == IPS_EOF_OFFSET { pos - 1 } else { pos };
+        if pos >= IPS_MAX_LEN {
+            return Err(PatchError::IpsOutOfRange { offset: pos });
+        }
+        let mut end = pos;
+        while end < target.len() && end - start < IPS_MAX_RECORD && changed(end) {
+            end += 1;
+        }
+        out.extend(&(start as u32).to_be_bytes()[1..]);
+        out.extend(((end - start) as u16).to_be_bytes());
+        out.extend_from_slice(&target[start..end]);
+        pos = end;
+    }
+    out.extend(b"EOF");
+    if target.len() < source.len() {
+        if target.len() >= IPS_MAX_LEN {
+            return Err(PatchError::IpsOutOfRange {
+                offset: target.len(),
+            });
+        }
+        out.extend(&(target.len() as u32).to_be_bytes()[1..]);
+    }
+    Ok(out)
+}
+
+/// Apply an IPS patch to `source`
+pub fn apply_ips(source: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    if patch.get(..5) != Some(b"PATCH") {
+        return Err(PatchError::Malformed { offset: 0 });
+    }
+    let mut out = source.to_vec();
+    let mut pos = 5;
+    let mut take = |len: usize| {
+        let bytes = patch
+            .get(pos..pos + len)
+            .ok_or(PatchError::Malformed { offset: pos });
+        pos += len;
+        bytes
+    };
+    let be = |bytes: &[u8]| bytes.iter().fold(0, |n, &b| (n << 8) | usize::from(b));
+    loop {
+        let offset = take(3)?;
+        if offset == b"EOF" {
+            break;
+        }
+        let offset = be(offset);
+        let len = be(take(2)?);
+        let (len, data) = if len == 0 {
+            // Run length encoded record
+            let len = be(take(2)?);
+            (len, vec![take(1)?[0]; len])
+        } else {
+            (len, take(len)?.to_vec())
+        };
+        if out.len() < offset + len {
+            out.resize(offset + len, 0);
+        }
+        out[offset..offset + len].copy_from_slice(&data);
+    }
+    if let Ok(len) = take(3) {
+        out.truncate(be(len));
+    }
+    Ok(out)
+}
+
+#[test]
+fn test_crc() {
+    assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    let z64: Vec<u8> = (0..CHECKSUM_START + CHECKSUM_LEN)
+        .map(|i| ((i as u32).wrapping_mul(2_654_435_761) >> 13) as u8)
+        .collect();
+    let data = swap_words(&z64);
+    // Expected values are from this implementation, to catch changes to it.
+    // test_crc_real_rom checks it against a real header.
+    assert_eq!(
+        compute_crc(&data, Cic::Cic6102),
+        Ok((0xF5CE_50DC, 0x432F_D320))
+    );
+    assert_eq!(
+        compute_crc(&data, Cic::Cic6103),
+        Ok((0xA694_7459, 0xA268_7D91))
+    );
+    assert_eq!(
+        compute_crc(&data, Cic::Cic6105),
+        Ok((0xDC2A_F736, 0x480F_A2E6))
+    );
+    assert_eq!(
+        compute_crc(&data, Cic::Cic6106),
+        Ok((0x5B34_7D9E, 0x8ABE_E6B1))
+    );
+    assert_eq!(
+        compute_crc(&data[..0x1000], Cic::Cic6103),
+        Err(PatchError::TooSmall { len: 0x1000 })
+    );
+}
+
+/// Check the checksums against the header of the ROM `N64_ROM` points to. Run it with
+/// `cargo test -- --ignored`.
+///
+/// The header values were written when the game was built, so they don't depend on this
+/// implementation.
+#[test]
+#[ignore = "needs a ROM in N64_ROM"]
+fn test_crc_real_rom() {
+    let path = std::env::var_os("N64_ROM").expect("N64_ROM is not set");
+    let rom = Rom::new(&std::fs::read(path).unwrap()).unwrap();
+    let cic = Cic::detect(&rom.data).expect("unknown CIC");
+    assert_eq!(
+        compute_crc(&rom.data, cic),
+        Ok((rom.header.crc1, rom.header.crc2))
+    );
+}
+
+#[test]
+fn test_build_patch() {
+    let mut z64 = vec![0; CHECKSUM_START + CHECKSUM_LEN];
+    z64[..4].copy_from_slice(&[0x80, 0x37, 0x12, 0x40]);
+    let set = MessageSet {
+        sections: vec![vec![
+            crate::encode("あ").unwrap(),
+            crate::encode("い").unwrap(),
+        ]],
+    };
+    let section = set.build();
+    z64[0x2000..0x2000 + section.len()].copy_from_slice(&section);
+    let original = Rom::new(&z64).unwrap();
+    let table = MessageTable::read(&original.data, 0x2000).unwrap();
+    let mut opts = BuildOptions {
+        dialect: crate::MarioStory::builtin(),
+        placement: Placement::InPlace,
+        format: PatchFormat::Bps,
+        cic: Some(Cic::Cic6103),
+    };
+    let id = MessageId::new(0, 1);
+    let out = build_patch(&original, &table, &[(id, "か")], &opts).unwrap();
+    assert_eq!(out.table.to_string(&out.rom.data, id).unwrap(), "か");
+    assert_eq!(
+        compute_crc(&out.rom.data, Cic::Cic6103),
+        Ok((out.rom.header.crc1, out.rom.header.crc2))
+    );
+    let target = out.rom.to_order(ByteOrder::Z64);
+    assert_eq!(apply_bps(&z64, &out.patch), Ok(target.clone()));
+    opts.format = PatchFormat::Ips;
+    let out = build_patch(&original, &table, &[(id, "か")], &opts).unwrap();
+    assert_eq!(apply_ips(&z64, &out.patch), Ok(target));
+    assert_eq!(
+        build_patch(&original, &table, &[(id, "a")], &opts).unwrap_err(),
+        BuildError::Encode {
+            id,
+            error: EncodeError::Unencodable { ch: 'a' }
+        }
+    );
+    // No boot code to detect the CIC from
+    opts.cic = None;
+    assert_matches!(
+        build_patch(&original, &table, &[], &opts),
+        Err(BuildError::Patch(PatchError::UnknownCic { .. }))
+    );
+}
+
+#[test]
+fn test_patches() {
+    let source: Vec<u8> = (0..0x200).map(|i| i as u8).collect();
+    let mut target = source.clone();
+    target[0x10..0x14].copy_from_slice(b"abcd");
+    target[0x16] = 0;
+    target[0x100] = 0xFF;
+    target.extend(b"tail");
+    let bps = create_bps(&source, &target);
+    assert_eq!(apply_bps(&source, &bps), Ok(target.clone()));
+    assert_eq!(apply_bps(&target, &bps), Err(PatchError::SourceMismatch));
+    let mut corrupt = bps.clone();
+    corrupt[6] ^= 1;
+    assert_eq!(apply_bps(&source, &corrupt), Err(PatchError::PatchMismatch));
+    let ips = create_ips(&source, &target).unwrap();
+    assert_eq!(apply_ips(&source, &ips), Ok(target.clone()));
+    // Truncation
+    let ips = create_ips(&target, &source).unwrap();
+    assert_eq!(apply_ips(&target, &ips), Ok(source.clone()));
+    // A change at the offset that would read as `EOF`
+    let source = vec![0; IPS_EOF_OFFSET + 2];
+    let mut target = source.clone();
+    target[IPS_EOF_OFFSET] = 1;
+    let ips = create_ips(&source, &target).unwrap();
+    assert!(!ips[5..].starts_with(b"EOF"));
+    assert_eq!(apply_ips(&source, &ips), Ok(target));
+    let source = vec![0; IPS_MAX_LEN + 1];
+    let mut target = source.clone();
+    target[IPS_MAX_LEN] = 1;
+    assert_eq!(
+        create_ips(&source, &target),
+        Err(PatchError::IpsOutOfRange {
+            offset: IPS_MAX_LEN
+        })
+    );
+}
@@ -0,0 +1,365 @@
+//! Gettext PO files, for translating messages in a PO editor.
+//!
+//! [`extract`] writes every message of a message section to a PO template. The message id
+//! is the `msgctxt`, and the original text in [`markup`](crate::markup) syntax is the
+//! `msgid`. The bubble style and speaker voice are added as extracted comments. Codes that
+//! are only approximated are tagged. [`extract_with`] can also mark characters whose glyph
+//! isn't verified with `[check:..]`, so translators know to double-check them.
+//!
+//! [`reinsert`] reads a translated PO file back, encodes every `msgstr` and rebuilds the
+//! message section. Messages without a usable translation keep their original bytes.
+
+use crate::{
+    encode,
+    msg::{self, MessageId, MessageSet, MessageTable, Placement, RebuildError, TableError},
+    translate_with, DecodeOptions, EncodeError, Event, ExtCmd, GlyphMode, Strictness,
+};
+
+/// A single entry of a PO file
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PoEntry {
+    pub msgctxt: Option<String>,
+    pub msgid: String,
+    pub msgstr: String,
+    /// Marked as `#, fuzzy`, meaning the translation needs review
+    pub fuzzy: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PoError {
+    /// Malformed PO syntax on this (1 based) line
+    Syntax {
+        line: usize,
+    },
+    Table(TableError),
+    Rebuild(RebuildError),
+}
+
+impl std::fmt::Display for PoError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Syntax { line } => write!(f, "PO syntax error on line {line}"),
+            Self::Table(e) => write!(f, "{e}"),
+            Self::Rebuild(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for PoError {}
+
+/// What happened to the entries of a PO file on reinsertion
+#[derive(Debug, Default)]
+pub struct InsertReport {
+    /// Messages that were replaced by their translation
+    pub translated: Vec<MessageId>,
+    /// Messages with an empty `msgstr`
+    pub untranslated: Vec<MessageId>,
+    /// Messages with a fuzzy translation, which is not used
+    pub fuzzy: Vec<MessageId>,
+    /// Messages whose translation couldn't be encoded
+    pub unencodable: Vec<(MessageId, EncodeError)>,
+    /// `msgctxt`s that aren't a message id in the section
+    pub unknown: Vec<String>,
+}
+
+/// How to write a PO template
+#[derive(Debug, Clone, Default)]
+pub struct ExtractOptions {
+    /// Mark characters whose glyph isn't verified, see
+    /// [`DecodeOptions::flag_unverified`]. No built-in glyph is verified yet, so this marks
+    /// nearly every character.
+    pub flag_unverified: bool,
+}
+
+/// Write a PO template with every message of the section
+pub fn extract(data: &[u8], table: &MessageTable) -> Result<String, TableError> {
+    extract_with(data, table, &ExtractOptions::default())
+}
+
+/// Like [`extract`], with options
+pub fn extract_with(
+    data: &[u8],
+    table: &MessageTable,
+    opts: &ExtractOptions,
+) -> Result<String, TableError> {
+    let opts = DecodeOptions {
+        strictness: Strictness::Lenient,
+        stop_at_end: true,
+        // Keep approximated codes apart, so translations of them encode to the same code
+        glyphs: GlyphMode::Tagged,
+        flag_unverified: opts.flag_unverified,
+        ..Default::default()
+    };
+    let mut out =
+        String::from("msgid \"\"\nmsgstr \"\"\n\"Content-Type: text/plain; charset=UTF-8\\n\"\n");
+    for (id, _) in table.messages() {
+        let bytes = table.message_bytes(data, id)?;
+        let events: Vec<Event> = translate_with(&bytes, &opts)
+            .map_err(|error| TableError::Decode { id, error })?
+            .items
+            .into_iter()
+            .map(|ev| ev.inner)
+            .collect();
+        out.push('\n');
+        let style = events.iter().find_map(|ev| match ev {
+            Event::StyleChange(style) => Some(style),
+            _ => None,
+        });
+        if let Some(style) = style {
+            out.push_str(&format!("#. style: {style:?}\n"));
+        }
+        let voice = events.iter().find_map(|ev| match ev {
+            Event::ExtCmd(ExtCmd::Voice { p1 }) => Some(p1),
+            _ => None,
+        });
+        if let Some(voice) = voice {
+            out.push_str(&format!("#. voice: {voice}\n"));
+        }
+        out.push_str(&format!("msgctxt \"{id}\"\n"));
+        write_string(&mut out, "msgid", &crate::markup::print(&events));
+        out.push_str("msgstr \"\"\n");
+    }
+    Ok(out)
+}
+
+/// Write a keyword and its string, one line per linebreak like PO editors do
+fn write_string(out: &mut String, keyword: &str, s: &str) {
+    if !s.contains('\n') {
+        out.push_str(&format!("{keyword} \"{}\"\n", escape(s)));
+        return;
+    }
+    out.push_str(&format!("{keyword} \"\"\n"));
+    for line in s.split_inclusive('\n') {
+        out.push_str(&format!("\"{}\"\n", escape(line)));
+    }
+}
+
+fn escape(s: &str) -> String {
+    let mut out = String::new();
+    for ch in s.chars() {
+        match ch {
+            '\\' => out.push_str("\\\\"),
+            '"' => out.push_str("\\\""),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            _ => out.push(ch),
+        }
+    }
+    out
+}
+
+fn unescape(s: &str) -> Option<String> {
+    let mut out = String::new();
+    let mut chars = s.chars();
+    while let Some(ch) = chars.next() {
+        if ch != '\\' {
+            out.push(ch);
+            continue;
+        }
+        out.push(match chars.next()? {
+            'n' => '\n',
+            't' => '\t',
+            '"' => '"',
+            '\\' => '\\',
+            _ => return None,
+        });
+    }
+    Some(out)
+}
+
+/// Parse the entries of a PO file
+pub fn parse(text: &str) -> Result<Vec<PoEntry>, PoError> {
+    let mut entries = Vec::new();
+    let mut entry = PoEntry::default();
+    // The string continuation lines are appended to
+    let mut current: Option<Field> = None;
+    // Once an entry has its msgstr, anything but a continuation line starts the next one
+    let mut complete = false;
+    for (i, line) in text.lines().enumerate() {
+        let syntax = || PoError::Syntax { line: i + 1 };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if line.starts_with('"') {
+            let field = current.ok_or_else(syntax)?;
+            let s = parse_quoted(line).ok_or_else(syntax)?;
+            field.of(&mut entry).push_str(&s);
+            continue;
+        }
+        if complete {
+            entries.push(std::mem::take(&mut entry));
+            complete = false;
+            current = None;
+        }
+        if let Some(comment) = line.strip_prefix('#') {
+            if let Some(flags) = comment.strip_prefix(',') {
+                entry.fuzzy |= flags.split(',').any(|flag| flag.trim() == "fuzzy");
+            }
+            continue;
+        }
+        let (keyword, rest) = line.split_once(' ').ok_or_else(syntax)?;
+        let field = match keyword {
+            "msgctxt" => Field::Ctxt,
+            "msgid" => Field::Id,
+            "msgstr" => Field::Str,
+            _ => return Err(syntax()),
+        };
+        *field.of(&mut entry) = parse_quoted(rest.trim()).ok_or_else(syntax)?;
+        complete = field == Field::Str;
+        current = Some(field);
+    }
+    if complete {
+        entries.push(entry);
+    } else if current.is_some() {
+        return Err(PoError::Syntax {
+            line: text.lines().count(),
+        });
+    }
+    Ok(entries)
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Field {
+    Ctxt,
+    Id,
+    Str,
+}
+
+impl Field {
+    fn of(self, entry: &mut PoEntry) -> &mut String {
+        match self {
+            Self::Ctxt => entry.msgctxt.get_or_insert_with(String::new),
+            Self::Id => &mut entry.msgid,
+            Self::Str => &mut entry.msgstr,
+        }
+    }
+}
+
+fn parse_quoted(s: &str) -> Option<String> {
+    unescape(s.strip_prefix('"')?.strip_suffix('"')?)
+}
+
+/// Replace messages in `set` with their translations from `entries`
+pub fn apply(set: &mut MessageSet, entries: &[PoEntry]) -> InsertReport {
+    let mut report = InsertReport::default();
+    for entry in entries {
+        // The header entry
+        let Some(ctxt) = &entry.msgctxt else {
+            continue;
+        };
+        let Ok(id) = ctxt.parse::<MessageId>() else {
+            report.unknown.push(ctxt.clone());
+            continue;
+        };
+        if entry.msgstr.is_empty() {
+            report.untranslated.push(id);
+            continue;
+        }
+        if entry.fuzzy {
+            report.fuzzy.push(id);
+            continue;
+        }
+        let bytes = match encode(&entry.msgstr) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                report.unencodable.push((id, e));
+                continue;
+            }
+        };
+        match set.set(id, bytes) {
+            Ok(()) => report.translated.push(id),
+            Err(_) => report.unknown.push(ctxt.clone()),
+        }
+    }
+    report
+}
+
+/// Insert the translations of a PO file into the message section of `data`, and rebuild it.
+///
+/// Returns the layout of the rebuilt section, and what happened to each entry.
+pub fn reinsert(
+    data: &mut [u8],
+    table: &MessageTable,
+    po: &str,
+    placement: Placement,
+) -> Result<(MessageTable, InsertReport), PoError> {
+    let entries = parse(po)?;
+    let mut set = MessageSet::from_table(table, data).map_err(PoError::Table)?;
+    let report = apply(&mut set, &entries);
+    let table = msg::rebuild(data, table, &set, placement).map_err(PoError::Rebuild)?;
+    Ok((table, report))
+}
+
+#[test]
+fn test_po_parse() {
+    let po = "msgid \"\"\nmsgstr \"\"\n\"Content-Type: text/plain; charset=UTF-8\\n\"\n\n\
+              #. voice: 3\n#, fuzzy, c-format\nmsgctxt \"0x0001_0002\"\nmsgid \"\"\n\"あ\\n\"\n\"い\"\n\
+              msgstr \"\\\"う\\\"\"\n";
+    let entries = parse(po).unwrap();
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0].msgctxt, None);
+    assert_eq!(
+        entries[1],
+        PoEntry {
+            msgctxt: Some("0x0001_0002".into()),
+            msgid: "あ\nい".into(),
+            msgstr: "\"う\"".into(),
+            fuzzy: true,
+        }
+    );
+    assert_eq!(
+        parse("msgid \"あ\"\nbogus"),
+        Err(PoError::Syntax { line: 2 })
+    );
+}
+
+#[test]
+fn test_po_roundtrip() {
+    let set = MessageSet {
+        sections: vec![vec![
+            crate::swap_words(&[0xFC, 0x01, 0xFF, 0x2F, 0x03, 0x00, 0xF0, 0x01, 0xFD]),
+            crate::encode("う").unwrap(),
+            crate::encode("え").unwrap(),
+            crate::encode("お").unwrap(),
+        ]],
+    };
+    let section = set.build();
+    let mut data = crate::swap_words(&section);
+    let table = MessageTable::read(&data, 0).unwrap();
+    let pot = extract(&data, &table).unwrap();
+    assert!(pot.contains(
+        "#. style: BubbleRight\n#. voice: 3\nmsgctxt \"0x0000_0000\"\nmsgid \"\"\n\
+         \"[style:BubbleRight][voice:3]あ\\n\"\n\"い[end]\"\nmsgstr \"\"\n"
+    ));
+    let opts = ExtractOptions {
+        flag_unverified: true,
+    };
+    let flagged = extract_with(&data, &table, &opts).unwrap();
+    assert!(flagged.contains("\"[check:kana:02:う][end]\"\n"));
+    let mut entries = parse(&pot).unwrap();
+    assert_eq!(entries.len(), 5);
+    assert_eq!(entries[1].msgid, "[style:BubbleRight][voice:3]あ\nい[end]");
+    entries[1].msgstr = "[style:BubbleRight]お\nか".into();
+    entries[2].msgstr = "a".into();
+    entries[3].msgstr = "き".into();
+    entries[3].fuzzy = true;
+    let mut set = MessageSet::from_table(&table, &data).unwrap();
+    let report = apply(&mut set, &entries);
+    assert_eq!(report.translated, [MessageId::new(0, 0)]);
+    assert_eq!(report.untranslated, [MessageId::new(0, 3)]);
+    assert_eq!(report.fuzzy, [MessageId::new(0, 2)]);
+    assert_eq!(
+        report.unencodable,
+        [(MessageId::new(0, 1), EncodeError::Unencodable { ch: 'a' })]
+    );
+    let po = pot.replacen(
+        "msgid \"え[end]\"\nmsgstr \"\"",
+        "msgid \"え[end]\"\nmsgstr \"く\"",
+        1,
+    );
+    let (table, report) = reinsert(&mut data, &table, &po, Placement::InPlace).unwrap();
+    assert_eq!(report.translated, [MessageId::new(0, 2)]);
+    assert_eq!(table.to_string(&data, MessageId::new(0, 2)).unwrap(), "く");
+    assert_eq!(table.to_string(&data, MessageId::new(0, 3)).unwrap(), "お");
+}
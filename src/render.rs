@@ -0,0 +1,836 @@
+//! Drawing messages with the game font, to see how they look in a bubble.
+//!
+//! [`render`] draws script [`Token`]s, and [`render_imm`] draws the events of an immediate
+//! buffer. Both show only the lines that fit the window, 3 by default like in the game: of
+//! one bubble for scripts, and scrolled like [`decode_imm_buf`](crate::decode_imm_buf)
+//! does for immediate buffers. Glyphs advance by their [`GlyphWidths`], and [`measure`]
+//! gives the width of every line, to check that a translation fits. [`measure_events`] does
+//! the same for events, like those parsed from markup.
+//!
+//! Every character code is drawn with its own glyph, so codes that decode to the same
+//! character still look like they do in the game. For that the input has to keep the
+//! codes: tokens rather than events, and lossless immediate buffer events, see
+//! [`imm::decode_events_lossless`]. Characters without a code are drawn with the code
+//! [`Dialect::reverse`] gives them, or as a box if there isn't exactly one.
+//!
+//! Widths are only as good as the [`GlyphWidths`] given. No width table of any game ships
+//! with this crate, so measured widths are estimates, not pixel accurate, unless the game's
+//! widths are loaded.
+//!
+//! Only the layout is modelled: linebreaks, the horizontal offset of
+//! [`imm::Event::ExtTextHoffset`], font size changes and text colour. Text effects, the
+//! bubble frame and the speaker are not drawn.
+
+#[cfg(test)]
+use crate::{
+    decode_imm_buf, encode,
+    font::{FontLayout, FontTable, GlyphLayout},
+    tokenize, CodeTable, MarioStory, PaperMario,
+};
+use crate::{
+    font::{Font, GlyphWidths},
+    imm, CharCode, Dialect, Event, ExtCmd, Image, LookupTable, Token,
+};
+
+/// The font size parameter that draws glyphs at their tile size
+pub const DEFAULT_FONT_SIZE: u8 = 16;
+
+/// How to lay out and colour the text
+#[derive(Debug, Clone)]
+pub struct RenderOptions {
+    /// Width of the image, or `None` to fit the longest line
+    pub width: Option<usize>,
+    /// Vertical distance between lines, in pixels
+    pub line_height: usize,
+    /// Number of lines the window shows
+    pub lines: usize,
+    /// Border around the text, in pixels
+    pub margin: usize,
+    /// Which bubble of a script to draw, counting from 0. Immediate buffers scroll across
+    /// bubbles instead, see [`render_imm`].
+    pub bubble: usize,
+    /// Scroll position in pixels, the first line shown is `scroll / line_height`
+    pub scroll: u32,
+    pub background: [u8; 4],
+    /// Colour for every text colour index. Indices past the end use the first colour.
+    ///
+    /// The default is not the game's palette: index 0 is dark text, and the others are
+    /// distinct colours so changes are easy to spot.
+    pub palette: Vec<[u8; 4]>,
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        Self {
+            width: None,
+            line_height: 16,
+            lines: 3,
+            margin: 8,
+            bubble: 0,
+            scroll: 0,
+            background: [0xF0, 0xF0, 0xE8, 0xFF],
+            palette: vec![
+                [0x20, 0x20, 0x20, 0xFF],
+                [0xD0, 0x20, 0x20, 0xFF],
+                [0x20, 0xA0, 0x20, 0xFF],
+                [0x20, 0x40, 0xD0, 0xFF],
+                [0xC0, 0x90, 0x00, 0xFF],
+                [0xA0, 0x30, 0xC0, 0xFF],
+                [0x00, 0xA0, 0xA0, 0xFF],
+                [0x80, 0x80, 0x80, 0xFF],
+            ],
+        }
+    }
+}
+
+/// What the renderer does, the events of both formats reduce to these
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Draw {
+    Glyph(LookupTable, u8),
+    /// A character without a glyph, drawn as a box
+    Missing,
+    Space,
+    /// A space a whole cell wide
+    FullSpace,
+    /// A space half a cell wide
+    HalfSpace,
+    /// Move to the next tab stop
+    Tab,
+    Linebreak,
+    NextBubble,
+    Hoffset(u8),
+    FontSize(u8, u8),
+    FontSizeReset,
+    Color(u8),
+}
+
+/// A character without its code. Only a code the dialect would encode it with is drawn.
+fn char_draw(dialect: &dyn Dialect, ch: char) -> Draw {
+    if ch == dialect.space() {
+        return Draw::Space;
+    }
+    match dialect.reverse(ch) {
+        Ok(CharCode::Single(table, code)) => Draw::Glyph(table, code),
+        Ok(CharCode::Wide(_)) | Err(_) => Draw::Missing,
+    }
+}
+
+fn button_draw(dialect: &dyn Dialect, button: crate::Button) -> Draw {
+    (0..=0xFF)
+        .find(|&code| dialect.button(code) == Some(button))
+        .map_or(Draw::Missing, |code| Draw::Glyph(LookupTable::Button, code))
+}
+
+fn script_draws(tokens: &[Token]) -> Vec<Draw> {
+    let mut out = Vec::new();
+    for token in tokens {
+        match token {
+            Token::Char { table, code, .. } => out.push(Draw::Glyph(*table, *code)),
+            Token::WideChar { .. } => out.push(Draw::Missing),
+            Token::TableSwitch(_) => {}
+            // Not a token, and the codes are unknown
+            Token::Event(Event::Dialog(s)) => out.extend(s.chars().map(|_| Draw::Missing)),
+            Token::Event(event) => out.extend(event_draw(event)),
+        }
+    }
+    out
+}
+
+fn event_draws(events: &[Event], dialect: &dyn Dialect) -> Vec<Draw> {
+    let mut out = Vec::new();
+    for event in events {
+        match event {
+            Event::Dialog(s) => out.extend(s.chars().map(|ch| char_draw(dialect, ch))),
+            event => out.extend(event_draw(event)),
+        }
+    }
+    out
+}
+
+/// What a script event draws. The characters of [`Event::Dialog`] are left to the caller.
+fn event_draw(event: &Event) -> Option<Draw> {
+    Some(match *event {
+        Event::Space => Draw::Space,
+        Event::FullSpace => Draw::FullSpace,
+        Event::HalfSpace => Draw::HalfSpace,
+        Event::Linebreak => Draw::Linebreak,
+        Event::NextBubble => Draw::NextBubble,
+        Event::ButtonRef { rawcode, .. } => Draw::Glyph(LookupTable::Button, rawcode),
+        Event::RawChar { table, code } | Event::Unverified { table, code, .. } => {
+            Draw::Glyph(table, code)
+        }
+        Event::RawWideChar { .. } => Draw::Missing,
+        Event::ExtCmd(ExtCmd::TextColor { c }) => Draw::Color(c),
+        Event::ExtCmd(ExtCmd::FontSize { x, y }) => Draw::FontSize(x, y),
+        Event::ExtCmd(ExtCmd::FontSizeReset {}) => Draw::FontSizeReset,
+        Event::Dialog(_)
+        | Event::StyleChange(_)
+        | Event::StyleWithParams { .. }
+        | Event::SaveColor
+        | Event::End
+        | Event::Delay(_)
+        | Event::Bell
+        | Event::Sparkly
+        | Event::ExtCmd(_)
+        | Event::ExtCmdError { .. }
+        | Event::Error { .. } => return None,
+    })
+}
+
+/// What an immediate buffer event draws. The events that move the window are handled by
+/// [`imm_lines`].
+fn imm_draw(event: &imm::Event, dialect: &dyn Dialect) -> Option<Draw> {
+    Some(match *event {
+        imm::Event::Code { table, code, .. } => Draw::Glyph(table, code),
+        imm::Event::Char(ch) => char_draw(dialect, ch),
+        imm::Event::UnkKana(code) => Draw::Glyph(LookupTable::Kana, code),
+        imm::Event::UnkKanji(code) => Draw::Glyph(LookupTable::Kanji, code),
+        imm::Event::UnkLatin(code) => Draw::Glyph(LookupTable::Latin, code),
+        imm::Event::UnkBtn(code) => Draw::Glyph(LookupTable::Button, code),
+        imm::Event::Btn(button) => button_draw(dialect, button),
+        imm::Event::Space => Draw::Space,
+        imm::Event::Tab => Draw::Tab,
+        imm::Event::ExtSetColor(c) => Draw::Color(c),
+        _ => return None,
+    })
+}
+
+/// A line of an immediate buffer, split the way [`decode_imm_buf`](crate::decode_imm_buf)
+/// splits them
+enum ImmLine {
+    Text {
+        draws: Vec<Draw>,
+        /// The horizontal offset when the line ended
+        hoffset: u8,
+    },
+    BubbleBreak,
+}
+
+struct ImmLines {
+    lines: Vec<ImmLine>,
+    /// The last [`imm::Event::ExtExtVOffset`]
+    start_scroll: u32,
+    /// The last [`imm::Event::BubbleStyle`]
+    bubble_style: u8,
+}
+
+fn imm_lines(events: &[imm::Event], dialect: &dyn Dialect) -> ImmLines {
+    let mut out = ImmLines {
+        lines: Vec::new(),
+        start_scroll: 0,
+        bubble_style: 0,
+    };
+    let (mut draws, mut hoffset) = (Vec::new(), 0);
+    for event in events {
+        match *event {
+            imm::Event::Newline => out.lines.push(ImmLine::Text {
+                draws: std::mem::take(&mut draws),
+                hoffset,
+            }),
+            // The line goes on in the next bubble, like in decode_imm_buf
+            imm::Event::NextBubble => out.lines.push(ImmLine::BubbleBreak),
+            imm::Event::ExtTextHoffset(off) => hoffset = off,
+            imm::Event::ExtExtVOffset(off) => out.start_scroll = u32::from(off),
+            imm::Event::BubbleStyle(style) => out.bubble_style = style,
+            _ => draws.extend(imm_draw(event, dialect)),
+        }
+    }
+    if draws.iter().any(|d| !matches!(d, Draw::Color(_))) {
+        out.lines.push(ImmLine::Text { draws, hoffset });
+    }
+    out
+}
+
+/// The lines of an immediate buffer the window shows
+struct ImmWindow {
+    /// Colour changes in the lines scrolled past
+    state: Vec<Draw>,
+    lines: Vec<Vec<Draw>>,
+    /// Horizontal offset of the shown text: that of the last line scrolled past
+    hoffset: u8,
+}
+
+/// Pick the lines like [`decode_imm_buf`](crate::decode_imm_buf): the scroll position
+/// starts at the last vertical offset, bubble style 0x07 scrolls 12 pixels less, and lines
+/// are scrolled past regardless of bubbles. Then up to `max` lines are shown, stopping at
+/// the end of a bubble.
+fn imm_window(imm: ImmLines, scroll: u32, line_height: usize, max: usize) -> ImmWindow {
+    let mut scroll = scroll + imm.start_scroll;
+    if imm.bubble_style == 0x07 {
+        scroll = scroll.saturating_sub(12);
+    }
+    let mut skip = scroll as usize / line_height.max(1);
+    let mut out = ImmWindow {
+        state: Vec::new(),
+        lines: Vec::new(),
+        hoffset: 0,
+    };
+    for line in imm.lines {
+        match line {
+            ImmLine::Text { draws, hoffset } if skip > 0 => {
+                skip -= 1;
+                out.hoffset = hoffset;
+                out.state
+                    .extend(draws.into_iter().filter(|d| matches!(d, Draw::Color(_))));
+            }
+            ImmLine::Text { draws, .. } => {
+                out.lines.push(draws);
+                if out.lines.len() == max {
+                    break;
+                }
+            }
+            ImmLine::BubbleBreak if !out.lines.is_empty() => break,
+            ImmLine::BubbleBreak => {}
+        }
+    }
+    out
+}
+
+/// A glyph at its place in the bubble
+struct Placed {
+    draw: Draw,
+    bubble: usize,
+    x: usize,
+    /// Line, counting from the first line of the bubble
+    line: usize,
+    width: usize,
+    height: usize,
+    color: u8,
+}
+
+/// How wide a line of text is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineWidth {
+    /// Bubble, counting from 0
+    pub bubble: usize,
+    /// Line, counting from the first line of the bubble
+    pub line: usize,
+    /// Width in pixels, from the left edge of the text to the end of the last glyph
+    pub width: usize,
+}
+
+struct Layout {
+    placed: Vec<Placed>,
+    lines: Vec<LineWidth>,
+}
+
+/// Place every glyph. `cell` is the size a glyph is drawn at, at the default font size.
+fn layout(draws: &[Draw], widths: &GlyphWidths, cell: (usize, usize)) -> Layout {
+    let mut out = Layout {
+        placed: Vec::new(),
+        lines: Vec::new(),
+    };
+    let (mut bubble, mut x, mut line, mut hoffset, mut color) = (0, 0, 0, 0, 0);
+    let mut size = (DEFAULT_FONT_SIZE, DEFAULT_FONT_SIZE);
+    let scaled = |px: usize, size: u8| px * usize::from(size) / usize::from(DEFAULT_FONT_SIZE);
+    let end_line = |out: &mut Layout, bubble, line, hoffset, x: usize, last: bool| {
+        // An empty line is empty however far it is offset
+        let width = if x > 0 { hoffset + x } else { 0 };
+        // A bubble doesn't end with an empty line, unless that's all it has
+        let empty = out
+            .lines
+            .last()
+            .is_some_and(|l: &LineWidth| l.bubble == bubble);
+        if !last || width > 0 || !empty {
+            out.lines.push(LineWidth {
+                bubble,
+                line,
+                width,
+            });
+        }
+    };
+    for &draw in draws {
+        let advance = match draw {
+            Draw::Glyph(table, code) => widths.get(table, code),
+            Draw::Missing => widths.default,
+            Draw::Space => widths.space,
+            Draw::FullSpace => widths.full_space,
+            Draw::HalfSpace => widths.half_space,
+            Draw::Tab => {
+                let stop = widths.tab.max(1);
+                x = (x / stop + 1) * stop;
+                continue;
+            }
+            Draw::NextBubble => {
+                end_line(&mut out, bubble, line, hoffset, x, true);
+                bubble += 1;
+                (x, line, hoffset) = (0, 0, 0);
+                continue;
+            }
+            Draw::Linebreak => {
+                end_line(&mut out, bubble, line, hoffset, x, false);
+                x = 0;
+                line += 1;
+                continue;
+            }
+            Draw::Hoffset(off) => {
+                hoffset = usize::from(off);
+                continue;
+            }
+            Draw::FontSize(sx, sy) => {
+                size = (sx, sy);
+                continue;
+            }
+            Draw::FontSizeReset => {
+                size = (DEFAULT_FONT_SIZE, DEFAULT_FONT_SIZE);
+                continue;
+            }
+            Draw::Color(c) => {
+                color = c;
+                continue;
+            }
+        };
+        out.placed.push(Placed {
+            draw,
+            bubble,
+            x: hoffset + x,
+            line,
+            width: scaled(cell.0, size.0),
+            height: scaled(cell.1, size.1),
+            color,
+        });
+        x += scaled(usize::from(advance), size.0);
+    }
+    end_line(&mut out, bubble, line, hoffset, x, true);
+    out
+}
+
+fn draw_glyph(image: &mut Image, font: &Font, placed: &Placed, x: usize, y: usize, rgba: [u8; 4]) {
+    let glyph = font.glyph;
+    let tile = match placed.draw {
+        Draw::Glyph(table, code) => font.tile(table, code),
+        Draw::Missing => None,
+        _ => return,
+    };
+    if placed.width == 0 || placed.height == 0 {
+        return;
+    }
+    let Some(tile) = tile else {
+        // Outline the cell, so missing glyphs stand out
+        for dx in 0..placed.width {
+            image.set(x + dx, y, rgba);
+            image.set(x + dx, y + placed.height - 1, rgba);
+        }
+        for dy in 0..placed.height {
+            image.set(x, y + dy, rgba);
+            image.set(x + placed.width - 1, y + dy, rgba);
+        }
+        return;
+    };
+    for dy in 0..placed.height {
+        for dx in 0..placed.width {
+            let v = tile
+                [dy * glyph.height / placed.height * glyph.width + dx * glyph.width / placed.width];
+            if v == 0 {
+                continue;
+            }
+            let bg = image.get(x + dx, y + dy);
+            let blend = |i: usize| {
+                ((u16::from(rgba[i]) * u16::from(v) + u16::from(bg[i]) * u16::from(255 - v)) / 255)
+                    as u8
+            };
+            image.set(x + dx, y + dy, [blend(0), blend(1), blend(2), bg[3].max(v)]);
+        }
+    }
+}
+
+fn render_draws(draws: &[Draw], font: &Font, opts: &RenderOptions) -> Image {
+    let layout = layout(draws, &font.widths, (font.glyph.width, font.glyph.height));
+    let first = opts.scroll as usize / opts.line_height.max(1);
+    let shown: Vec<&Placed> = layout
+        .placed
+        .iter()
+        .filter(|p| p.bubble == opts.bubble && (first..first + opts.lines).contains(&p.line))
+        .collect();
+    let width = opts.width.unwrap_or_else(|| {
+        let text = shown.iter().map(|p| p.x + p.width).max().unwrap_or(0);
+        text + 2 * opts.margin
+    });
+    let mut image = Image::new(width, opts.lines * opts.line_height + 2 * opts.margin);
+    image.fill(0, 0, image.width, image.height, opts.background);
+    for p in shown {
+        let rgba = opts
+            .palette
+            .get(usize::from(p.color))
+            .or(opts.palette.first())
+            .copied()
+            .unwrap_or([0, 0, 0, 0xFF]);
+        let y = opts.margin + (p.line - first) * opts.line_height;
+        draw_glyph(&mut image, font, p, opts.margin + p.x, y, rgba);
+    }
+    image
+}
+
+/// Draw a bubble of script tokens, see [`tokenize`](crate::tokenize)
+pub fn render(tokens: &[Token], font: &Font, opts: &RenderOptions) -> Image {
+    render_draws(&script_draws(tokens), font, opts)
+}
+
+/// The width of every line of script tokens, in pixels.
+///
+/// Font size changes scale the widths of the glyphs after them, like in the game.
+pub fn measure(tokens: &[Token], widths: &GlyphWidths) -> Vec<LineWidth> {
+    layout(&script_draws(tokens), widths, (0, 0)).lines
+}
+
+/// The width of every line of events, like [`measure`].
+///
+/// Events don't keep the codes of their characters, so characters are measured by the code
+/// [`Dialect::reverse`] gives them, and as [`GlyphWidths::default`] wide if it doesn't give
+/// exactly one.
+pub fn measure_events(
+    events: &[Event],
+    widths: &GlyphWidths,
+    dialect: &dyn Dialect,
+) -> Vec<LineWidth> {
+    layout(&event_draws(events, dialect), widths, (0, 0)).lines
+}
+
+/// Draw the window of immediate buffer events, see [`imm::decode_events_lossless`].
+///
+/// The window is picked like [`decode_imm_buf`](crate::decode_imm_buf) picks it, so
+/// `opts.bubble` is ignored: the scroll position is added to the vertical offset of the
+/// buffer, and lines are scrolled past regardless of bubbles. The shown text is offset by
+/// the horizontal offset of the last line scrolled past.
+pub fn render_imm(
+    events: &[imm::Event],
+    font: &Font,
+    dialect: &dyn Dialect,
+    opts: &RenderOptions,
+) -> Image {
+    let lines = imm_lines(events, dialect);
+    let window = imm_window(lines, opts.scroll, opts.line_height, opts.lines);
+    let mut draws = window.state;
+    draws.push(Draw::Hoffset(window.hoffset));
+    for (i, line) in window.lines.into_iter().enumerate() {
+        if i > 0 {
+            draws.push(Draw::Linebreak);
+        }
+        draws.extend(line);
+    }
+    let opts = RenderOptions {
+        bubble: 0,
+        scroll: 0,
+        ..opts.clone()
+    };
+    render_draws(&draws, font, &opts)
+}
+
+/// The width of every line of immediate buffer events, in pixels, like [`measure`].
+///
+/// Lines are split like [`decode_imm_buf`](crate::decode_imm_buf) splits them, and are
+/// offset by the horizontal offset at their end.
+pub fn measure_imm(
+    events: &[imm::Event],
+    widths: &GlyphWidths,
+    dialect: &dyn Dialect,
+) -> Vec<LineWidth> {
+    let mut draws = Vec::new();
+    for line in imm_lines(events, dialect).lines {
+        match line {
+            ImmLine::Text {
+                draws: line,
+                hoffset,
+            } => {
+                draws.push(Draw::Hoffset(hoffset));
+                draws.extend(line);
+                draws.push(Draw::Linebreak);
+            }
+            ImmLine::BubbleBreak => draws.push(Draw::NextBubble),
+        }
+    }
+    layout(&draws, widths, (0, 0)).lines
+}
+
+#[cfg(test)]
+const INK: [u8; 4] = [0x20, 0x20, 0x20, 0xFF];
+
+/// A font of 2x2 tiles, 8 bits per pixel, with the tiles of every table one after another
+#[cfg(test)]
+fn font_2x2(tables: &[(LookupTable, &[[u8; 4]])]) -> Font {
+    let mut data = Vec::new();
+    let mut layout = FontLayout {
+        glyph: GlyphLayout {
+            width: 2,
+            height: 2,
+            bpp: 8,
+            palette: None,
+        },
+        tables: Vec::new(),
+    };
+    for &(table, tiles) in tables {
+        layout.tables.push(FontTable {
+            table,
+            offset: data.len(),
+            count: tiles.len(),
+            widths: None,
+        });
+        data.extend(tiles.concat());
+    }
+    Font::load(&data, &layout).unwrap()
+}
+
+#[test]
+fn test_render() {
+    // Kana 00 is solid, Kana 01 is blank, Latin 00 is solid
+    let font = font_2x2(&[
+        (LookupTable::Kana, &[[0xFF; 4], [0x00; 4]]),
+        (LookupTable::Latin, &[[0xFF; 4]]),
+    ]);
+    let opts = RenderOptions {
+        line_height: 2,
+        lines: 2,
+        margin: 1,
+        background: [0, 0, 0, 0],
+        ..Default::default()
+    };
+    let kana = |code| Token::Char {
+        table: LookupTable::Kana,
+        code,
+        ch: None,
+    };
+    let tokens = [
+        kana(0x00),
+        kana(0x01),
+        Token::Event(Event::Linebreak),
+        Token::Event(Event::ExtCmd(ExtCmd::TextColor { c: 1 })),
+        Token::Event(Event::ExtCmd(ExtCmd::FontSize { x: 32, y: 16 })),
+        kana(0x00),
+        Token::Event(Event::ExtCmd(ExtCmd::FontSizeReset {})),
+        kana(0x05),
+        Token::Event(Event::Linebreak),
+        kana(0x00),
+    ];
+    let image = render(&tokens, &font, &opts);
+    // The third line is outside the window
+    assert_eq!((image.width, image.height), (8, 6));
+    assert_eq!(image.get(1, 1), INK);
+    assert_eq!(image.get(3, 1), [0, 0, 0, 0]);
+    // The wide glyph fills 4 pixels, and the one without a tile is outlined
+    let red = opts.palette[1];
+    assert_eq!(image.get(4, 4), red);
+    assert_eq!(image.get(5, 3), red);
+    assert_eq!(image.get(6, 4), red);
+    let scrolled = RenderOptions {
+        scroll: 2,
+        ..opts.clone()
+    };
+    let image = render(&tokens, &font, &scrolled);
+    assert_eq!(image.get(1, 1), red);
+    assert_eq!(image.get(1, 3), red);
+}
+
+#[test]
+fn test_render_approximated() {
+    // Kana C4 stands in for a small ん and decodes to ん, but is drawn with its own tile
+    let mut tiles = vec![[0x00; 4]; 0xC5];
+    tiles[0xC4] = [0xFF; 4];
+    let font = font_2x2(&[(LookupTable::Kana, &tiles)]);
+    let opts = RenderOptions {
+        line_height: 2,
+        lines: 1,
+        margin: 0,
+        background: [0, 0, 0, 0],
+        ..Default::default()
+    };
+    let tokens = tokenize(&encode("\u{E0C4}").unwrap()).unwrap();
+    assert!(tokens.contains(&Token::Char {
+        table: LookupTable::Kana,
+        code: 0xC4,
+        ch: Some('ん'),
+    }));
+    let image = render(&tokens, &font, &opts);
+    assert_eq!(image.get(0, 0), INK);
+    // Without the codes the character can't be told apart from kana 2D
+    let tokens = [Token::Event(Event::Dialog("ん".into()))];
+    assert_eq!(script_draws(&tokens), [Draw::Missing]);
+}
+
+#[test]
+fn test_render_imm() {
+    // Latin 00 is solid
+    let font = font_2x2(&[(LookupTable::Latin, &[[0xFF; 4]])]);
+    let dialect = PaperMario::with_table(CodeTable::new());
+    let latin = imm::Event::Code {
+        table: LookupTable::Latin,
+        code: 0x00,
+        ch: Some('A'),
+    };
+    let events = [
+        latin.clone(),
+        imm::Event::NextBubble,
+        imm::Event::ExtTextHoffset(3),
+        latin.clone(),
+        imm::Event::Newline,
+        latin.clone(),
+        imm::Event::Tab,
+        latin.clone(),
+        imm::Event::Newline,
+        imm::Event::NextBubble,
+        latin,
+    ];
+    let opts = RenderOptions {
+        width: Some(16),
+        line_height: 2,
+        lines: 2,
+        margin: 1,
+        background: [0, 0, 0, 0],
+        ..Default::default()
+    };
+    // The first line goes on past the bubble break, and tab stops are 4 glyphs apart.
+    // Nothing is scrolled past, so nothing is offset.
+    let image = render_imm(&events, &font, &dialect, &opts);
+    assert_eq!((image.width, image.height), (16, 6));
+    assert_eq!(image.get(1, 1), INK);
+    assert_eq!(image.get(3, 1), INK);
+    assert_eq!(image.get(1, 3), INK);
+    assert_eq!(image.get(5, 3), [0, 0, 0, 0]);
+    assert_eq!(image.get(9, 3), INK);
+    // Scrolling past a line offsets the text by its offset, and stops at the next bubble
+    let scrolled = RenderOptions {
+        scroll: 2,
+        ..opts.clone()
+    };
+    let image = render_imm(&events, &font, &dialect, &scrolled);
+    assert_eq!(image.get(1, 1), [0, 0, 0, 0]);
+    assert_eq!(image.get(4, 1), INK);
+    assert_eq!(image.get(12, 1), INK);
+    assert_eq!(image.get(4, 3), [0, 0, 0, 0]);
+}
+
+#[test]
+fn test_imm_window() {
+    // The window shows the same lines as decode_imm_buf
+    let dialect = MarioStory::builtin();
+    let text = |draws: &[Draw]| -> String {
+        draws
+            .iter()
+            .map(|draw| match *draw {
+                Draw::Glyph(table, code) => dialect.char(table, code).unwrap(),
+                Draw::Space | Draw::Tab => '\u{3000}',
+                _ => unreachable!(),
+            })
+            .collect()
+    };
+    for style in [0x00, 0x07] {
+        let events = [
+            imm::Event::BubbleStyle(style),
+            imm::Event::ExtExtVOffset(0x10),
+            imm::Event::ExtTextHoffset(5),
+            imm::Event::Char('あ'),
+            imm::Event::Newline,
+            imm::Event::Char('い'),
+            imm::Event::Space,
+            imm::Event::Char('う'),
+            imm::Event::Newline,
+            imm::Event::NextBubble,
+            imm::Event::ExtTextHoffset(7),
+            imm::Event::Char('え'),
+            imm::Event::Newline,
+            imm::Event::Char('お'),
+            imm::Event::Newline,
+            imm::Event::Char('か'),
+            imm::Event::Newline,
+            imm::Event::Char('き'),
+            imm::Event::Newline,
+            imm::Event::NextBubble,
+            imm::Event::Char('く'),
+        ];
+        let buf = imm::encode_events(&events).unwrap();
+        for scroll in (0..96).step_by(4) {
+            let expected = decode_imm_buf(&buf, scroll);
+            let lines = imm_lines(&imm::decode_events_lossless(&buf), dialect);
+            let window = imm_window(lines, scroll, 16, 3);
+            let shown: Vec<String> = window.lines.iter().map(|l| text(l)).collect();
+            // decode_imm_buf ends the text with a newline when it stops at a bubble
+            let expected_text = expected.text.trim_end_matches('\n');
+            assert_eq!(
+                shown.join("\n"),
+                expected_text,
+                "style {style}, scroll {scroll}"
+            );
+            assert_eq!(
+                window.hoffset, expected.hoffs,
+                "style {style}, scroll {scroll}"
+            );
+        }
+    }
+}
+
+#[test]
+fn test_measure() {
+    // Kana 00 is solid, Kana 01 is blank
+    let font = font_2x2(&[(LookupTable::Kana, &[[0xFF; 4], [0x00; 4]])]);
+    // Blank glyphs and glyphs without a tile are a whole tile wide
+    let mut widths = font.widths.clone();
+    assert_eq!(widths.kana.get(0x00), Some(2));
+    assert_eq!(widths.kana.get(0x01), None);
+    widths.load_tbl(LookupTable::Kana, "00=12\n01=8").unwrap();
+    widths.load_tbl(LookupTable::Button, "04=10").unwrap();
+    widths.space = 16;
+    assert_eq!(widths.to_tbl(LookupTable::Kana), "00=12\n01=8\n");
+    assert_eq!(widths.get(LookupTable::Kanji, 0x00), 2);
+    let tokens = [
+        Token::Char {
+            table: LookupTable::Kana,
+            code: 0x01,
+            ch: None,
+        },
+        Token::Event(Event::Space),
+        Token::Event(Event::ButtonRef {
+            button: None,
+            rawcode: 0x04,
+        }),
+        Token::Event(Event::Linebreak),
+        Token::Event(Event::ExtCmd(ExtCmd::FontSize { x: 8, y: 8 })),
+        Token::TableSwitch(LookupTable::Kana),
+        Token::Char {
+            table: LookupTable::Kana,
+            code: 0x00,
+            ch: None,
+        },
+        Token::Event(Event::NextBubble),
+        Token::Event(Event::Linebreak),
+        Token::Event(Event::Linebreak),
+    ];
+    let lines: Vec<(usize, usize, usize)> = measure(&tokens, &widths)
+        .into_iter()
+        .map(|l| (l.bubble, l.line, l.width))
+        .collect();
+    assert_eq!(lines, [(0, 0, 34), (0, 1, 6), (1, 0, 0), (1, 1, 0)]);
+    let events = [
+        imm::Event::ExtTextHoffset(3),
+        imm::Event::Code {
+            table: LookupTable::Kana,
+            code: 0x00,
+            ch: None,
+        },
+        imm::Event::Tab,
+        imm::Event::UnkBtn(0x04),
+        imm::Event::Newline,
+        imm::Event::Space,
+    ];
+    let dialect = PaperMario::with_table(CodeTable::new());
+    let lines: Vec<usize> = measure_imm(&events, &widths, &dialect)
+        .into_iter()
+        .map(|l| l.width)
+        .collect();
+    assert_eq!(lines, [3 + 16 + 10, 3 + 16]);
+    // Characters are measured by the code they encode to, if there's only one
+    widths.kana.set(0x2D, Some(9));
+    widths.full_space = 14;
+    widths.half_space = 7;
+    let events = [
+        Event::Dialog("ん".into()),
+        Event::FullSpace,
+        Event::HalfSpace,
+        Event::Space,
+        Event::Linebreak,
+        Event::Dialog("べ".into()),
+    ];
+    let lines: Vec<usize> = measure_events(&events, &widths, MarioStory::builtin())
+        .into_iter()
+        .map(|l| l.width)
+        .collect();
+    assert_eq!(lines, [9 + 14 + 7 + 16, 2]);
+}
@@ -0,0 +1,247 @@
+//! N64 ROM images.
+//!
+//! Dumps come in three byte orders, told apart by the first word of the header:
+//!
+//! | Order | Extension | First word    |
+//! |-------|-----------|---------------|
+//! | z64   | `.z64`    | `80 37 12 40` |
+//! | v64   | `.v64`    | `37 80 40 12` |
+//! | n64   | `.n64`    | `40 12 37 80` |
+//!
+//! The decoders in this crate expect n64 order, so [`Rom`] normalises to that.
+
+use crate::{swap_words, Dialect, MarioStory, PaperMario};
+
+#[cfg(doc)]
+use crate::Ique;
+
+/// Size of the ROM header
+pub const HEADER_SIZE: usize = 0x40;
+
+/// First word of the header, in z64 order
+const MAGIC: [u8; 4] = [0x80, 0x37, 0x12, 0x40];
+
+/// Byte order of a ROM dump
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteOrder {
+    /// Big endian, the order the cartridge bus delivers
+    Z64,
+    /// Byte swapped halfwords
+    V64,
+    /// Little endian words. This is the order the decoders expect.
+    N64,
+}
+
+impl ByteOrder {
+    /// Detect the byte order from the first word of a dump
+    pub fn detect(data: &[u8]) -> Option<Self> {
+        let magic: [u8; 4] = data.get(..4)?.try_into().ok()?;
+        [Self::Z64, Self::V64, Self::N64]
+            .into_iter()
+            .find(|order| order.convert(&MAGIC, Self::Z64) == magic)
+    }
+    /// Convert `data` in this order to `to` order
+    pub fn convert(self, data: &[u8], to: Self) -> Vec<u8> {
+        let z64 = match self {
+            Self::Z64 => data.to_vec(),
+            Self::V64 => swap_halves(data),
+            Self::N64 => swap_words(data),
+        };
+        match to {
+            Self::Z64 => z64,
+            Self::V64 => swap_halves(&z64),
+            Self::N64 => swap_words(&z64),
+        }
+    }
+}
+
+fn swap_halves(data: &[u8]) -> Vec<u8> {
+    data.chunks(2)
+        .flat_map(|chk| chk.iter().rev().copied())
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RomError {
+    /// The data is too small to hold a header
+    TooSmall { len: usize },
+    /// The length is not a multiple of 4, so the byte order can't be normalised
+    UnalignedLength { len: usize },
+    /// The first word isn't the N64 magic in any byte order
+    UnknownByteOrder { magic: [u8; 4] },
+}
+
+impl std::fmt::Display for RomError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::TooSmall { len } => write!(f, "{len} bytes is too small for a ROM"),
+            Self::UnalignedLength { len } => {
+                write!(f, "ROM length {len} is not a multiple of 4")
+            }
+            Self::UnknownByteOrder { magic } => {
+                write!(f, "unknown byte order, first word is {magic:02X?}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for RomError {}
+
+/// The games this crate knows about
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Game {
+    /// The Japanese original, ID `NMQJ`
+    MarioStory,
+    /// ID `NMQE`
+    PaperMarioUs,
+    /// ID `NMQP`
+    PaperMarioPal,
+    /// The Chinese iQue release, identified by region `C`
+    PaperMarioIque,
+}
+
+impl Game {
+    /// The text format of this game's messages, if it is supported.
+    ///
+    /// `None` for the iQue release: there is no built-in table of its Chinese glyphs, and
+    /// its control codes are only assumed to be Paper Mario's. Use an [`Ique`] with a
+    /// loaded table for it.
+    pub fn dialect(self) -> Option<&'static dyn Dialect> {
+        match self {
+            Self::MarioStory => Some(MarioStory::builtin()),
+            Self::PaperMarioUs | Self::PaperMarioPal => Some(PaperMario::builtin()),
+            Self::PaperMarioIque => None,
+        }
+    }
+}
+
+/// The parts of the N64 header that identify a ROM
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    /// Boot checksums
+    pub crc1: u32,
+    pub crc2: u32,
+    /// Internal name, padded with spaces
+    pub title: String,
+    /// Media type, cartridge id and region code, like `NMQJ`
+    pub game_id: [u8; 4],
+    /// ROM revision
+    pub version: u8,
+}
+
+impl Header {
+    /// Parse the header from z64 order `data`
+    pub fn parse(data: &[u8]) -> Result<Self, RomError> {
+        if data.len() < HEADER_SIZE {
+            return Err(RomError::TooSmall { len: data.len() });
+        }
+        let u32_at = |pos: usize| u32::from_be_bytes(data[pos..pos + 4].try_into().unwrap());
+        Ok(Self {
+            crc1: u32_at(0x10),
+            crc2: u32_at(0x14),
+            // The title is JIS X 0201, which is ASCII for everything we need to tell games apart
+            title: data[0x20..0x34].iter().map(|&b| char::from(b)).collect(),
+            game_id: data[0x3B..0x3F].try_into().unwrap(),
+            version: data[0x3F],
+        })
+    }
+    /// Region code, the last character of the game id
+    pub fn region(&self) -> char {
+        char::from(self.game_id[3])
+    }
+    /// Identify the game. Returns `None` for anything that isn't Mario Story or Paper Mario.
+    pub fn game(&self) -> Option<Game> {
+        if &self.game_id[1..3] != b"MQ" {
+            return None;
+        }
+        match self.region() {
+            'J' => Some(Game::MarioStory),
+            'E' => Some(Game::PaperMarioUs),
+            'P' => Some(Game::PaperMarioPal),
+            'C' => Some(Game::PaperMarioIque),
+            _ => None,
+        }
+    }
+}
+
+/// A ROM image, normalised to n64 order
+#[derive(Debug, Clone)]
+pub struct Rom {
+    /// The ROM contents in n64 order, ready for the decoders
+    pub data: Vec<u8>,
+    /// The order the ROM was loaded in
+    pub byte_order: ByteOrder,
+    pub header: Header,
+}
+
+impl Rom {
+    /// Load a ROM dump in any byte order
+    pub fn new(data: &[u8]) -> Result<Self, RomError> {
+        if data.len() < HEADER_SIZE {
+            return Err(RomError::TooSmall { len: data.len() });
+        }
+        if !data.len().is_multiple_of(4) {
+            return Err(RomError::UnalignedLength { len: data.len() });
+        }
+        let byte_order = ByteOrder::detect(data).ok_or(RomError::UnknownByteOrder {
+            magic: data[..4].try_into().unwrap(),
+        })?;
+        let header = Header::parse(&byte_order.convert(&data[..HEADER_SIZE], ByteOrder::Z64))?;
+        Ok(Self {
+            data: byte_order.convert(data, ByteOrder::N64),
+            byte_order,
+            header,
+        })
+    }
+    /// Identify the game, see [`Header::game`]
+    pub fn game(&self) -> Option<Game> {
+        self.header.game()
+    }
+    /// The ROM contents in `order`, for example to save it in the order it was loaded in
+    pub fn to_order(&self, order: ByteOrder) -> Vec<u8> {
+        ByteOrder::N64.convert(&self.data, order)
+    }
+}
+
+#[cfg(test)]
+fn test_header(game_id: &[u8; 4]) -> Vec<u8> {
+    let mut data = vec![0; 0x80];
+    data[..4].copy_from_slice(&MAGIC);
+    data[0x10..0x14].copy_from_slice(&0x1234_5678_u32.to_be_bytes());
+    data[0x14..0x18].copy_from_slice(&0x9ABC_DEF0_u32.to_be_bytes());
+    data[0x20..0x34].copy_from_slice(b"MARIO STORY         ");
+    data[0x3B..0x3F].copy_from_slice(game_id);
+    data[0x3F] = 1;
+    data[0x40] = 0xAA;
+    data
+}
+
+#[test]
+fn test_rom() {
+    let z64 = test_header(b"NMQJ");
+    for order in [ByteOrder::Z64, ByteOrder::V64, ByteOrder::N64] {
+        let dump = ByteOrder::Z64.convert(&z64, order);
+        assert_eq!(ByteOrder::detect(&dump), Some(order));
+        let rom = Rom::new(&dump).unwrap();
+        assert_eq!(rom.byte_order, order);
+        assert_eq!(rom.data, swap_words(&z64));
+        assert_eq!(rom.to_order(order), dump);
+        assert_eq!(rom.header.title.trim_end(), "MARIO STORY");
+        assert_eq!(rom.header.crc1, 0x1234_5678);
+        assert_eq!(rom.header.crc2, 0x9ABC_DEF0);
+        assert_eq!(rom.header.version, 1);
+        assert_eq!(rom.game(), Some(Game::MarioStory));
+    }
+    let header = Header::parse(&test_header(b"NMQP")).unwrap();
+    assert_eq!(header.game(), Some(Game::PaperMarioPal));
+    assert!(Game::PaperMarioIque.dialect().is_none());
+    assert_eq!(Header::parse(&test_header(b"NSMJ")).unwrap().game(), None);
+    assert_eq!(
+        Rom::new(&[0; 0x40]).unwrap_err(),
+        RomError::UnknownByteOrder { magic: [0; 4] }
+    );
+    assert_eq!(
+        Rom::new(&z64[..0x42]).unwrap_err(),
+        RomError::UnalignedLength { len: 0x42 }
+    );
+}
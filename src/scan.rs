@@ -0,0 +1,217 @@
+//! Locating dialog messages in a larger buffer, like a whole ROM
+
+use {
+    crate::{
+        swap_words, to_string_with, tokenize_stream, yay0, DecodeOptions, Dialect, Event, ExtCmd,
+        Span, Strictness, Token,
+    },
+    std::{ops::Range, sync::Arc},
+};
+
+/// A byte range that looks like a dialog message
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    /// Stream order offset of the first byte.
+    /// This is the offset in a big endian (z64) ROM.
+    pub offset: usize,
+    /// Length in bytes, including the terminator
+    pub len: usize,
+    /// Location in the scanned buffer, in file order
+    pub span: Span,
+    /// Plausibility score, higher is better
+    pub score: i32,
+    /// Beginning of the decoded text
+    pub preview: String,
+    /// Stream order offset of the Yay0 block the message was found in.
+    ///
+    /// If this is set, `offset`, `len` and `span` refer to the decompressed block.
+    pub yay0: Option<usize>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ScanOptions {
+    /// Candidates scoring lower than this are not reported
+    pub min_score: i32,
+    /// Give up on a candidate if no terminator is found within this many bytes
+    pub max_len: usize,
+    /// Give up on a candidate if it has more character codes the charsets can't map and ext
+    /// commands with an unknown id
+    pub max_unknown_chars: usize,
+    /// Only try candidates that start with a style change (`0xFC`).
+    ///
+    /// Most messages do, and scanning is a lot faster and less noisy this way.
+    pub style_start_only: bool,
+    /// Maximum number of characters in [`Candidate::preview`]
+    pub preview_len: usize,
+    /// Also scan inside Yay0 compressed blocks
+    pub yay0: bool,
+    /// Text format to scan for. `None` uses [`MarioStory::builtin`](crate::MarioStory::builtin).
+    pub dialect: Option<Arc<dyn Dialect>>,
+}
+
+impl Default for ScanOptions {
+    fn default() -> Self {
+        Self {
+            min_score: 10,
+            max_len: 0x1000,
+            max_unknown_chars: 2,
+            style_start_only: true,
+            preview_len: 32,
+            yay0: true,
+            dialect: None,
+        }
+    }
+}
+
+/// Scan `data` (in the same word swapped order [`crate::translate`] expects) for messages
+pub fn scan(data: &[u8], opts: &ScanOptions) -> Vec<Candidate> {
+    let stream = swap_words(data);
+    let mut candidates = Vec::new();
+    let mut pos = 0;
+    while pos < stream.len() {
+        if opts.yay0 && pos.is_multiple_of(4) && stream[pos..].starts_with(yay0::MAGIC) {
+            if let Ok((block, len)) = yay0::decompress_with_len(&stream[pos..]) {
+                let inner_opts = ScanOptions {
+                    yay0: false,
+                    ..opts.clone()
+                };
+                candidates.extend(
+                    scan(&swap_words(&block), &inner_opts)
+                        .into_iter()
+                        .map(|cand| Candidate {
+                            yay0: Some(pos),
+                            ..cand
+                        }),
+                );
+                pos += len;
+                continue;
+            }
+        }
+        if opts.style_start_only && stream[pos] != 0xFC {
+            pos += 1;
+            continue;
+        }
+        match parse_candidate(&stream, pos, opts) {
+            Some((len, Some(score))) if score >= opts.min_score => {
+                candidates.push(make_candidate(data, &stream, pos, len, score, opts));
+                // Any start inside this candidate would end at the same terminator
+                pos += len;
+            }
+            // A rejected candidate can run into a real message, which has to be tried
+            _ => pos += 1,
+        }
+    }
+    candidates
+}
+
+/// Parse a message starting at `pos`.
+///
+/// Returns the length of the message if it's grammatically valid, along with its
+/// plausibility score, or `None` for the score if it's too broken to consider.
+fn parse_candidate(stream: &[u8], pos: usize, opts: &ScanOptions) -> Option<(usize, Option<i32>)> {
+    let window = &stream[pos..stream.len().min(pos + opts.max_len)];
+    let decode_opts = DecodeOptions {
+        strictness: Strictness::Strict,
+        stop_at_end: true,
+        dialect: opts.dialect.clone(),
+        // Counted as unknown codes below, so a single one doesn't rule out the message
+        allow_unknown_commands: true,
+        ..Default::default()
+    };
+    let span_of = |r: Range<usize>| Span {
+        offset: r.start,
+        len: r.len(),
+    };
+    let tokens = tokenize_stream(window, span_of, &decode_opts).ok()?.items;
+    let len = tokens.last()?.span.range().end;
+    let mut score = 0;
+    let mut unknown = 0;
+    for tok in tokens {
+        score += match tok.inner {
+            Token::TableSwitch(_) => 0,
+            Token::Char { ch: Some(_), .. } | Token::WideChar { ch: Some(_), .. } => 1,
+            Token::Char { ch: None, .. }
+            | Token::WideChar { ch: None, .. }
+            | Token::Event(Event::ButtonRef { button: None, .. })
+            | Token::Event(Event::ExtCmd(ExtCmd::Unknown(_))) => {
+                unknown += 1;
+                -4
+            }
+            Token::Event(Event::StyleChange(_)) => 3,
+            Token::Event(Event::ExtCmd(_)) => 3,
+            Token::Event(Event::End) => 5,
+            Token::Event(_) => 1,
+        };
+    }
+    Some((len, (unknown <= opts.max_unknown_chars).then_some(score)))
+}
+
+fn make_candidate(
+    data: &[u8],
+    stream: &[u8],
+    pos: usize,
+    len: usize,
+    score: i32,
+    opts: &ScanOptions,
+) -> Candidate {
+    let decode_opts = DecodeOptions {
+        strictness: Strictness::Lenient,
+        stop_at_end: true,
+        dialect: opts.dialect.clone(),
+        allow_unknown_commands: true,
+        ..Default::default()
+    };
+    let text =
+        to_string_with(&swap_words(&stream[pos..pos + len]), &decode_opts).unwrap_or_default();
+    Candidate {
+        offset: pos,
+        len,
+        span: Span::from_stream(pos..pos + len, data.len()),
+        score,
+        preview: text.chars().take(opts.preview_len).collect(),
+        yay0: None,
+    }
+}
+
+#[test]
+fn test_scan() {
+    let mut stream = vec![0xC8, 0xE0, 0x00, 0xFC, 0x42];
+    let msg_offset = stream.len();
+    stream.extend([
+        0xFC, 0x01, 0x00, 0x01, 0x02, 0xF0, 0xFF, 0x05, 0x03, 0x04, 0xFD,
+    ]);
+    stream.extend([0xE5, 0xE6, 0xFC, 0x02, 0xFD]);
+    let candidates = scan(&swap_words(&stream), &ScanOptions::default());
+    assert_eq!(candidates.len(), 1);
+    assert_eq!(candidates[0].offset, msg_offset);
+    assert_eq!(candidates[0].len, 11);
+    assert_eq!(candidates[0].preview, "あいう\nお");
+    assert_eq!(candidates[0].yay0, None);
+    // The same data again, compressed after some padding
+    let mut outer = vec![0; 8];
+    outer.extend(yay0::compress(&stream));
+    let candidates = scan(&swap_words(&outer), &ScanOptions::default());
+    assert_eq!(candidates.len(), 1);
+    assert_eq!(candidates[0].yay0, Some(8));
+    assert_eq!(candidates[0].offset, msg_offset);
+    assert_eq!(candidates[0].preview, "あいう\nお");
+    // An unknown ext command counts against the score, but isn't fatal
+    let mut unknown = stream.clone();
+    unknown.splice(msg_offset + 6..msg_offset + 9, [0xFF, 0x77, 0x03]);
+    let candidates = scan(&swap_words(&unknown), &ScanOptions::default());
+    assert_eq!(candidates.len(), 1);
+    assert_eq!(candidates[0].preview, "あいう\n[ext:0x77]えお");
+    let known = scan(&swap_words(&stream), &ScanOptions::default());
+    assert!(candidates[0].score < known[0].score);
+    // A rejected candidate that ends at the message's terminator doesn't hide the message
+    let mut stray = vec![0xFC, 0x01, 0xF5, 0xE0, 0xE1, 0xE2, 0xE3];
+    stray.extend(&stream[msg_offset..msg_offset + 11]);
+    let candidates = scan(&swap_words(&stray), &ScanOptions::default());
+    assert_eq!(candidates.len(), 1);
+    assert_eq!(candidates[0].offset, 7);
+    assert_eq!(
+        candidates[0].preview,
+        "あいう
+お"
+    );
+}
@@ -0,0 +1,270 @@
+//! Yay0, the LZ compression Nintendo uses for a lot of N64 assets.
+//!
+//! A block starts with a 16 byte header: the magic `Yay0`, then the decompressed size, the
+//! offset of the link table, and the offset of the literal bytes, all big endian `u32`s.
+//! A bitstream of `u32` mask words follows the header. For each set bit a literal byte is
+//! copied, for each clear bit a `u16` link refers back to already decompressed data.
+//!
+//! All data here is in stream (z64) order. Use [`crate::swap_words`] to go between that and
+//! the order the decoders expect.
+
+use std::collections::HashMap;
+
+#[cfg(test)]
+use std::assert_matches::assert_matches;
+
+/// The magic every block starts with
+pub const MAGIC: &[u8; 4] = b"Yay0";
+
+const HEADER_SIZE: usize = 0x10;
+/// Farthest a link can reach back
+const WINDOW: usize = 0x1000;
+const MIN_MATCH: usize = 3;
+/// Longest match, with the length stored in an extra literal byte
+const MAX_MATCH: usize = 0xFF + 0x12;
+/// How many earlier positions the compressor tries per byte
+const MAX_CANDIDATES: usize = 256;
+/// Refuse to decompress blocks claiming to be larger than this. Nothing in a 64 MiB ROM is.
+const MAX_SIZE: usize = 0x400_0000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Yay0Error {
+    /// The data doesn't start with `Yay0`
+    BadMagic,
+    /// The header claims an implausibly large decompressed size
+    TooLarge { size: usize },
+    /// A read went past the end of the data
+    Truncated { offset: usize },
+    /// A link refers back to before the start of the output
+    InvalidLink { offset: usize },
+}
+
+impl std::fmt::Display for Yay0Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::BadMagic => f.write_str("not a Yay0 block"),
+            Self::TooLarge { size } => write!(f, "decompressed size {size} is too large"),
+            Self::Truncated { offset } => write!(f, "Yay0 block truncated at 0x{offset:X}"),
+            Self::InvalidLink { offset } => write!(f, "invalid link at 0x{offset:X}"),
+        }
+    }
+}
+
+impl std::error::Error for Yay0Error {}
+
+/// Decompress the Yay0 block at the start of `data`
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>, Yay0Error> {
+    decompress_with_len(data).map(|(out, _)| out)
+}
+
+/// Decompress the Yay0 block at the start of `data`, and also return how many bytes of
+/// `data` the block took up, rounded up to a multiple of 4
+pub fn decompress_with_len(data: &[u8]) -> Result<(Vec<u8>, usize), Yay0Error> {
+    if data.get(..4) != Some(MAGIC) {
+        return Err(Yay0Error::BadMagic);
+    }
+    let read = |pos: usize, len: usize| {
+        data.get(pos..pos + len)
+            .ok_or(Yay0Error::Truncated { offset: pos })
+    };
+    let u32_at = |pos| read(pos, 4).map(|b| u32::from_be_bytes(b.try_into().unwrap()) as usize);
+    let size = u32_at(4)?;
+    if size > MAX_SIZE {
+        return Err(Yay0Error::TooLarge { size });
+    }
+    let (mut link_pos, mut chunk_pos) = (u32_at(8)?, u32_at(12)?);
+    let mut mask_pos = HEADER_SIZE;
+    let (mut mask, mut mask_bits) = (0, 0);
+    let mut out = Vec::with_capacity(size);
+    while out.len() < size {
+        if mask_bits == 0 {
+            mask = u32_at(mask_pos)?;
+            mask_pos += 4;
+            mask_bits = 32;
+        }
+        let literal = mask & 0x8000_0000 != 0;
+        mask <<= 1;
+        mask_bits -= 1;
+        if literal {
+            out.push(read(chunk_pos, 1)?[0]);
+            chunk_pos += 1;
+            continue;
+        }
+        let link = read(link_pos, 2)?;
+        let link_offset = link_pos;
+        let link = usize::from(u16::from_be_bytes([link[0], link[1]]));
+        link_pos += 2;
+        let dist = (link & 0xFFF) + 1;
+        let count = match link >> 12 {
+            0 => {
+                let extra = usize::from(read(chunk_pos, 1)?[0]);
+                chunk_pos += 1;
+                extra + 0x12
+            }
+            n => n + 2,
+        };
+        let Some(start) = out.len().checked_sub(dist) else {
+            return Err(Yay0Error::InvalidLink {
+                offset: link_offset,
+            });
+        };
+        // Links may overlap the bytes they produce, so go byte by byte
+        for i in 0..count.min(size - out.len()) {
+            out.push(out[start + i]);
+        }
+    }
+    let len = mask_pos.max(link_pos).max(chunk_pos).next_multiple_of(4);
+    Ok((out, len))
+}
+
+/// Compress `data` into a Yay0 block.
+///
+/// The block is laid out like the game's own: mask words right after the header, then the
+/// links, then the literal bytes, padded to a multiple of 4 bytes.
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let mut masks: Vec<u32> = Vec::new();
+    let mut links: Vec<u8> = Vec::new();
+    let mut chunks: Vec<u8> = Vec::new();
+    let mut bit = 0;
+    let mut push_bit = |masks: &mut Vec<u32>, literal: bool| {
+        if bit == 0 {
+            masks.push(0);
+        }
+        if literal {
+            *masks.last_mut().unwrap() |= 0x8000_0000 >> bit;
+        }
+        bit = (bit + 1) % 32;
+    };
+    let mut finder = MatchFinder::default();
+    let mut pos = 0;
+    while pos < data.len() {
+        match finder.find(data, pos) {
+            Some((dist, count)) => {
+                push_bit(&mut masks, false);
+                let dist_bits = (dist - 1) as u16;
+                if count >= 0x12 {
+                    links.extend(dist_bits.to_be_bytes());
+                    chunks.push((count - 0x12) as u8);
+                } else {
+                    links.extend((((count - 2) as u16) << 12 | dist_bits).to_be_bytes());
+                }
+                for p in pos..pos + count {
+                    finder.insert(data, p);
+                }
+                pos += count;
+            }
+            None => {
+                push_bit(&mut masks, true);
+                chunks.push(data[pos]);
+                finder.insert(data, pos);
+                pos += 1;
+            }
+        }
+    }
+    let link_offset = HEADER_SIZE + masks.len() * 4;
+    let chunk_offset = link_offset + links.len();
+    let mut out = MAGIC.to_vec();
+    for word in [data.len(), link_offset, chunk_offset] {
+        out.extend((word as u32).to_be_bytes());
+    }
+    for mask in masks {
+        out.extend(mask.to_be_bytes());
+    }
+    out.extend(links);
+    out.extend(chunks);
+    out.resize(out.len().next_multiple_of(4), 0);
+    out
+}
+
+/// Hash chains over 3 byte prefixes
+#[derive(Default)]
+struct MatchFinder {
+    /// Most recent position of each prefix
+    heads: HashMap<[u8; MIN_MATCH], usize>,
+    /// Previous position with the same prefix, for each position
+    prev: Vec<Option<usize>>,
+}
+
+impl MatchFinder {
+    fn insert(&mut self, data: &[u8], pos: usize) {
+        let prev = data
+            .get(pos..pos + MIN_MATCH)
+            .and_then(|key| self.heads.insert(key.try_into().unwrap(), pos));
+        self.prev.push(prev);
+    }
+    /// Longest earlier match for the data at `pos`, as distance and length
+    fn find(&self, data: &[u8], pos: usize) -> Option<(usize, usize)> {
+        let key: [u8; MIN_MATCH] = data.get(pos..pos + MIN_MATCH)?.try_into().unwrap();
+        let max_len = MAX_MATCH.min(data.len() - pos);
+        let mut best: Option<(usize, usize)> = None;
+        let mut cand = self.heads.get(&key).copied();
+        for _ in 0..MAX_CANDIDATES {
+            let Some(c) = cand.filter(|&c| pos - c <= WINDOW) else {
+                break;
+            };
+            let len = (0..max_len)
+                .take_while(|&i| data[c + i] == data[pos + i])
+                .count();
+            if best.is_none_or(|(_, best_len)| len > best_len) {
+                best = Some((pos - c, len));
+                if len == max_len {
+                    break;
+                }
+            }
+            cand = self.prev[c];
+        }
+        best.filter(|&(_, len)| len >= MIN_MATCH)
+    }
+}
+
+#[test]
+fn test_yay0_roundtrip() {
+    let mut data = b"mario mario mario luigi ".repeat(40);
+    data.extend((0..=255u8).cycle().step_by(7).take(0x2000));
+    data.extend([0; 0x300]);
+    let compressed = compress(&data);
+    assert!(compressed.len() < data.len() / 2);
+    assert_eq!(
+        decompress_with_len(&compressed),
+        Ok((data, compressed.len()))
+    );
+    assert_eq!(decompress(&[]), Err(Yay0Error::BadMagic));
+    assert_matches!(
+        decompress(&compressed[..0x20]),
+        Err(Yay0Error::Truncated { .. })
+    );
+}
+
+#[test]
+fn test_yay0_decompress() {
+    // "abcabcabc!": 3 literals, a link back 3 with count 6, a literal
+    let block = [
+        b'Y',
+        b'a',
+        b'y',
+        b'0',
+        0,
+        0,
+        0,
+        10,
+        0,
+        0,
+        0,
+        0x14,
+        0,
+        0,
+        0,
+        0x16,
+        0b1110_1000,
+        0,
+        0,
+        0,
+        0x40,
+        0x02,
+        b'a',
+        b'b',
+        b'c',
+        b'!',
+    ];
+    assert_eq!(decompress(&block).unwrap(), b"abcabcabc!");
+}